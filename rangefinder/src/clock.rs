@@ -1,14 +1,43 @@
-use core::{
-    cell::Cell,
-    sync::atomic::{AtomicU8, Ordering},
-};
+//! Monotonic time, counted by Timer0.
+//!
+//! [`Clock::now_instant`] counts 64 bits of ticks, which at 40 kHz lasts
+//! longer than any turret will, so instants can be compared and subtracted
+//! without thinking about wrapping. [`Clock::now`] is the same count cut down
+//! to 32 bits, which wraps every 30 hours, for interrupt handlers that only
+//! ever take differences of nearby times with wrapping arithmetic.
+//!
+//! Between ticks Timer0 itself keeps counting, [`COUNT_HZ`] times a second.
+//! [`Clock::now_fine`] reads it too, for times finer than a tick.
 
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(target_arch = "avr")]
 use arduino_hal::pac::{tc0::tccr0b::CS0_A, TC0};
-use avr_device::interrupt::Mutex;
 use const_assert::{Assert, IsTrue};
 
+#[cfg(target_arch = "avr")]
+use crate::hal::Timebase;
+use crate::sync::Lock;
+
 pub static CLOCK: Clock<40, 8> = Clock::new();
 
+/// How fast [`CLOCK`] ticks
+pub const TICK_HZ: u32 = 40_000;
+
+/// A time on [`CLOCK`], in ticks since it started
+pub type Instant = fugit::Instant<u64, 1, TICK_HZ>;
+/// A length of time in [`CLOCK`] ticks
+pub type Duration = fugit::Duration<u64, 1, TICK_HZ>;
+
+/// How fast Timer0 counts under [`CLOCK`]
+pub const COUNT_HZ: u32 = 2_000_000;
+
+/// A time on [`CLOCK`], in Timer0 counts since it started
+pub type FineInstant = fugit::Instant<u64, 1, COUNT_HZ>;
+/// A length of time in Timer0 counts
+pub type FineDuration = fugit::Duration<u64, 1, COUNT_HZ>;
+
+#[cfg(target_arch = "avr")]
 const fn prescale_from_value<const PRESCALE: u32>() -> CS0_A {
     match PRESCALE {
         0 => CS0_A::NO_CLOCK,
@@ -21,6 +50,7 @@ const fn prescale_from_value<const PRESCALE: u32>() -> CS0_A {
     }
 }
 
+#[cfg(target_arch = "avr")]
 #[allow(dead_code)]
 const fn prescale_value(prescale: CS0_A) -> u32 {
     match prescale {
@@ -33,13 +63,16 @@ const fn prescale_value(prescale: CS0_A) -> u32 {
     }
 }
 
-/// Clock that ticks at 200kHz
+/// Clock that ticks at `KHZ`, on Timer0 compare matches
 ///
 /// interrupt frequency (Hz) = (16,000,000Hz) / (prescaler * (compare match register + 1))
 /// TOP = [ 16MHz / (PRESCALER * FREQ)] - 1
+///
+/// Ticks are counted in `part` until it wraps, then carried into `counter`,
+/// so most ticks don't have to add up all 64 bits.
 pub struct Clock<const KHZ: u32, const PRESCALE: u32> {
     part: AtomicU8,
-    counter: Mutex<Cell<u32>>,
+    counter: Lock<u64>,
 }
 
 impl<const KHZ: u32, const PRESCALE: u32> Clock<KHZ, PRESCALE>
@@ -48,15 +81,19 @@ where
     Assert<{ (16_000_000 / (PRESCALE * KHZ * 1_000)) - 1 < 256 }>: IsTrue,
 {
     pub const FREQ: u32 = KHZ * 1_000;
+    /// How fast Timer0 counts, [`Self::TOP`] + 1 times a tick
+    pub const COUNT_FREQ: u32 = 16_000_000 / PRESCALE;
+    #[cfg_attr(not(target_arch = "avr"), allow(dead_code))]
     const TOP: u8 = ((16_000_000 / (PRESCALE * Self::FREQ)) - 1) as u8;
 
     pub const fn new() -> Self {
         Self {
             part: AtomicU8::new(0),
-            counter: Mutex::new(Cell::new(0)),
+            counter: Lock::new(0),
         }
     }
 
+    #[cfg(target_arch = "avr")]
     pub fn start(&self, tc0: TC0) {
         // Configure the timer for the above interval (in CTC mode)
         tc0.tccr0a.write(|w| w.wgm0().ctc());
@@ -68,31 +105,248 @@ where
         tc0.timsk0.write(|w| w.ocie0a().set_bit());
     }
 
+    fn ticks(&self) -> u64 {
+        self.counter.lock(|counter| self.ticks_in(counter))
+    }
+
+    /// Ticks so far, with `counter` locked so that `tick` can't get between
+    /// the two halves.
+    fn ticks_in(&self, counter: &u64) -> u64 {
+        *counter + self.part.load(Ordering::SeqCst) as u64
+    }
+
+    /// Ticks since the clock started, wrapping around every 2³² of them.
     pub fn now(&self) -> u32 {
-        avr_device::interrupt::free(|cs| {
-            self.counter.borrow(cs).get() + self.part.load(Ordering::SeqCst) as u32
-        })
+        self.ticks() as u32
+    }
+
+    pub fn now_instant(&self) -> fugit::Instant<u64, 1, { KHZ * 1_000 }> {
+        fugit::Instant::<u64, 1, { KHZ * 1_000 }>::from_ticks(self.ticks())
+    }
+
+    /// Time since `earlier`, or nothing if `earlier` hasn't come yet.
+    pub fn elapsed_since(
+        &self,
+        earlier: fugit::Instant<u64, 1, { KHZ * 1_000 }>,
+    ) -> fugit::Duration<u64, 1, { KHZ * 1_000 }> {
+        self.now_instant()
+            .checked_duration_since(earlier)
+            .unwrap_or(fugit::Duration::<u64, 1, { KHZ * 1_000 }>::from_ticks(0))
     }
 
-    pub fn now_instant(&self) -> fugit::Instant<u32, 1, { KHZ * 1_000 }> {
-        fugit::Instant::<u32, 1, { KHZ * 1_000 }>::from_ticks(self.now())
+    /// A deadline `timeout` from now.
+    pub fn deadline(
+        &self,
+        timeout: fugit::Duration<u64, 1, { KHZ * 1_000 }>,
+    ) -> Deadline<{ KHZ * 1_000 }> {
+        Deadline::after(self.now_instant(), timeout)
     }
 
+    pub fn has_passed(&self, deadline: &Deadline<{ KHZ * 1_000 }>) -> bool {
+        deadline.has_passed(self.now_instant())
+    }
+
+    /// Timer0 counts since the clock started, reading the timer for the
+    /// counts since the last tick.
+    #[cfg(target_arch = "avr")]
+    pub fn now_fine(&self) -> fugit::Instant<u64, 1, { 16_000_000 / PRESCALE }> {
+        let counts = self.counter.lock(|counter| {
+            // SAFETY: Only reads the timer, which `start` has already set up
+            let tc0 = unsafe { &*TC0::ptr() };
+            let count = tc0.tcnt0.read().bits();
+            // A compare match since the lock was taken hasn't been ticked
+            // yet. The count read before it may be from either side of it, so
+            // read it again.
+            let pending = tc0
+                .tifr0
+                .read()
+                .ocf0a()
+                .bit_is_set()
+                .then(|| tc0.tcnt0.read().bits());
+            Self::counts(self.ticks_in(counter), count, pending)
+        });
+        fugit::Instant::<u64, 1, { 16_000_000 / PRESCALE }>::from_ticks(counts)
+    }
+
+    /// Timer0 counts since the clock started, given the ticks so far and what
+    /// the timer showed: its count, and its count again if a compare match
+    /// was waiting to be ticked.
+    #[cfg_attr(not(target_arch = "avr"), allow(dead_code))]
+    fn counts(ticks: u64, count: u8, pending: Option<u8>) -> u64 {
+        let per_tick = Self::TOP as u64 + 1;
+        match pending {
+            Some(count) => (ticks + 1) * per_tick + count.min(Self::TOP) as u64,
+            None => ticks * per_tick + count.min(Self::TOP) as u64,
+        }
+    }
+
+    /// Count one compare match. Only the interrupt handler calls this.
     pub fn tick(&self) {
-        avr_device::interrupt::free(|cs| {
-            let part = self.part.load(Ordering::SeqCst);
-            if part > 250 {
-                self.part.store(0, Ordering::SeqCst);
-                let counter = self.counter.borrow(cs);
-                counter.set(counter.get().wrapping_add(part as u32));
-            } else {
-                self.part.store(part + 1, Ordering::SeqCst);
+        self.counter.lock(|counter| {
+            let part = self.part.load(Ordering::SeqCst).wrapping_add(1);
+            if part == 0 {
+                *counter += 1 << 8;
             }
+            self.part.store(part, Ordering::SeqCst);
         });
     }
+
+    /// A clock that has already been running for `ticks`
+    #[cfg(test)]
+    fn started_at(ticks: u64) -> Self {
+        Self {
+            part: AtomicU8::new(0),
+            counter: Lock::new(ticks),
+        }
+    }
+}
+
+impl<const KHZ: u32, const PRESCALE: u32> Default for Clock<KHZ, PRESCALE>
+where
+    Assert<{ (16_000_000 / (PRESCALE * KHZ * 1_000)) - 1 < 256 }>: IsTrue,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A point in time to wait until.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline<const HZ: u32> {
+    at: fugit::Instant<u64, 1, HZ>,
+}
+
+impl<const HZ: u32> Deadline<HZ> {
+    pub const fn at(at: fugit::Instant<u64, 1, HZ>) -> Self {
+        Self { at }
+    }
+
+    pub fn after(now: fugit::Instant<u64, 1, HZ>, timeout: fugit::Duration<u64, 1, HZ>) -> Self {
+        Self { at: now + timeout }
+    }
+
+    pub fn instant(&self) -> fugit::Instant<u64, 1, HZ> {
+        self.at
+    }
+
+    pub fn has_passed(&self, now: fugit::Instant<u64, 1, HZ>) -> bool {
+        now >= self.at
+    }
+
+    /// Time left as of `now`, nothing once the deadline has passed.
+    pub fn remaining(&self, now: fugit::Instant<u64, 1, HZ>) -> fugit::Duration<u64, 1, HZ> {
+        self.at
+            .checked_duration_since(now)
+            .unwrap_or(fugit::Duration::<u64, 1, HZ>::from_ticks(0))
+    }
 }
 
+/// [`Timebase`] backed by [`CLOCK`] and the busy-wait `arduino_hal::delay_ms`.
+#[cfg(target_arch = "avr")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemTimebase;
+
+#[cfg(target_arch = "avr")]
+impl Timebase for SystemTimebase {
+    fn now_ms(&self) -> u32 {
+        CLOCK.now_instant().duration_since_epoch().to_millis() as u32
+    }
+
+    fn delay_ms(&mut self, ms: u16) {
+        arduino_hal::delay_ms(ms);
+    }
+}
+
+#[cfg(target_arch = "avr")]
 #[avr_device::interrupt(atmega328p)]
 fn TIMER0_COMPA() {
     CLOCK.tick();
+    crate::timer::TIMERS.tick(|| CLOCK.now_instant());
+    crate::ir::transmitter::tick();
+}
+
+#[cfg(test)]
+mod tests {
+    use fugit::ExtU64;
+
+    use super::*;
+
+    #[test]
+    fn instants_carry_on_past_32_bits() {
+        let clock = Clock::<40, 8>::started_at(u32::MAX as u64 - 100);
+        let before = clock.now_instant();
+        let deadline = clock.deadline(10.millis());
+        for _ in 0..1_000 {
+            clock.tick();
+        }
+
+        // The 32-bit count has wrapped, the instant hasn't
+        assert!(clock.now() < 1_000);
+        assert!(clock.now_instant() > before);
+        assert!(clock.elapsed_since(before) >= Duration::from_ticks(900));
+        assert!(clock.has_passed(&deadline));
+        // Waiting on something that hasn't happened yet
+        let later = clock.now_instant() + 1.secs();
+        assert_eq!(clock.elapsed_since(later).ticks(), 0);
+    }
+
+    #[test]
+    fn counts_every_tick() {
+        for start in [0, 200, u32::MAX as u64 - 300] {
+            let clock = Clock::<40, 8>::started_at(start);
+            for n in 1..=1_000 {
+                clock.tick();
+                assert_eq!(clock.ticks(), start + n);
+            }
+            assert_eq!(clock.now(), (start + 1_000) as u32);
+        }
+
+        // A minute, as the interrupt would see it
+        let clock = Clock::<40, 8>::new();
+        for _ in 0..60 * TICK_HZ {
+            clock.tick();
+        }
+        assert_eq!(
+            clock.elapsed_since(Instant::from_ticks(0)),
+            Duration::secs(60)
+        );
+    }
+
+    #[test]
+    fn fine_time_comes_from_the_timer() {
+        type C = Clock<40, 8>;
+        assert_eq!(C::COUNT_FREQ, COUNT_HZ);
+        assert_eq!(C::TOP, 49);
+
+        // Counting up through a tick
+        assert_eq!(C::counts(10, 0, None), 500);
+        assert_eq!(C::counts(10, 49, None), 549);
+        // The compare match hasn't been ticked yet, whichever side of it the
+        // first read was
+        assert_eq!(C::counts(10, 49, Some(0)), 550);
+        assert_eq!(C::counts(10, 0, Some(1)), 551);
+
+        // Never goes backwards, tick to tick
+        let mut last = 0;
+        for ticks in 0..3 {
+            for count in 0..=C::TOP {
+                let counts = C::counts(ticks, count, None);
+                assert!(counts > last || counts == 0);
+                last = counts;
+            }
+        }
+        let fine = FineInstant::from_ticks(C::counts(40_000, 25, None));
+        assert_eq!(fine.duration_since_epoch().to_micros(), 1_000_012);
+    }
+
+    #[test]
+    fn deadlines() {
+        let start = Instant::from_ticks(1_000);
+        let deadline = Deadline::after(start, 5.millis());
+        assert_eq!(deadline.instant(), Instant::from_ticks(1_200));
+        assert!(!deadline.has_passed(start));
+        assert_eq!(deadline.remaining(start), Duration::from_ticks(200));
+        assert!(deadline.has_passed(Instant::from_ticks(1_200)));
+        assert_eq!(deadline.remaining(Instant::from_ticks(5_000)).ticks(), 0);
+    }
 }
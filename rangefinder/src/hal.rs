@@ -0,0 +1,97 @@
+//! Hardware seams for the turret.
+//!
+//! [`Turret`](crate::turret::Turret) only talks to the outside world through
+//! these traits. The Nano implementations live next to the drivers they wrap
+//! (`servo`, `hc_sr04`, `clock`, `ir`), and [`host`] has stand-ins so the
+//! turret logic can run under `cargo test` on a dev box.
+
+use uom::si::f32::{Length, TemperatureInterval};
+
+use crate::{
+    hc_sr04::HcSr04Error,
+    ir::{IrCommand, Received, TransmitError},
+};
+
+#[cfg(not(target_arch = "avr"))]
+pub mod host;
+
+/// A servo, driven with the same 0..=180 value as the Arduino `Servo::write`.
+///
+/// For positional servos this is an angle, for continuous-rotation servos it
+/// is a speed where `90` means stop.
+pub trait Actuator {
+    fn write(&mut self, value: i16);
+}
+
+/// Something that can tell how far away the thing in front of the turret is.
+pub trait RangeSensor {
+    /// Measure and wait for the result.
+    fn measure_distance(&mut self) -> Result<Length, HcSr04Error>;
+
+    /// Send a ping without waiting for it to come back.
+    ///
+    /// Sensors that can't do that measure in
+    /// [`RangeSensor::poll_measurement`] instead.
+    fn start_measurement(&mut self) {}
+
+    /// Result of the ping sent by [`RangeSensor::start_measurement`], or
+    /// `WouldBlock` while it is still in flight.
+    fn poll_measurement(&mut self) -> nb::Result<Length, HcSr04Error> {
+        self.measure_distance().map_err(nb::Error::Other)
+    }
+
+    /// Let the sensor know how warm the air is, for sensors that care.
+    fn set_temperature(&mut self, _temperature: TemperatureInterval) {}
+}
+
+/// Something that can tell how warm it is, in degrees Celsius.
+pub trait Thermometer {
+    /// `None` if the reading makes no sense, e.g. the sensor is unplugged.
+    fn temperature(&mut self) -> Option<TemperatureInterval>;
+}
+
+/// Passage of time, both for measuring it and for waiting on it.
+pub trait Timebase {
+    /// Milliseconds since the timebase was started. Wraps around.
+    fn now_ms(&self) -> u32;
+
+    /// Block for the given number of milliseconds.
+    fn delay_ms(&mut self, ms: u16);
+}
+
+/// Where the turret gets its orders from.
+pub trait CommandSource {
+    /// Oldest command not handled yet.
+    fn next_command(&mut self) -> Option<Received>;
+
+    /// How many commands were dropped, because they came in faster than they
+    /// were handled, since this was last asked.
+    fn take_overflows(&mut self) -> u16 {
+        0
+    }
+}
+
+/// Something that can pass commands on to other turrets.
+pub trait Transmitter {
+    /// Start sending `cmd`, or `WouldBlock` while the last one is still going
+    /// out.
+    fn send(&mut self, cmd: &IrCommand) -> nb::Result<(), TransmitError>;
+}
+
+/// [`Transmitter`] for turrets without an IR LED.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoTransmitter;
+
+impl Transmitter for NoTransmitter {
+    fn send(&mut self, _cmd: &IrCommand) -> nb::Result<(), TransmitError> {
+        Err(nb::Error::Other(TransmitError::NoTransmitter))
+    }
+}
+
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
+impl Actuator for arduino_sys::Servo {
+    fn write(&mut self, value: i16) {
+        // Safety: the servo has been attached to its pin by `Turret::attach`
+        unsafe { arduino_sys::Servo::write(self, value) };
+    }
+}
@@ -0,0 +1,224 @@
+//! Host stand-ins for the [`hal`](super) traits.
+//!
+//! None of these try to model physics; they record what they were asked to do
+//! and hand back whatever was queued up, which is all the unit tests need.
+
+use heapless::{Deque, Vec};
+use infrared::protocol::nec::NecCommand;
+use uom::si::f32::Length;
+
+use super::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter};
+use crate::{
+    hc_sr04::HcSr04Error,
+    ir::{transmitter::nec_command, IrCommand, Received, TransmitError},
+};
+
+const HISTORY: usize = 64;
+
+/// Servo that remembers every value written to it.
+#[derive(Debug, Default)]
+pub struct RecordingActuator {
+    writes: Vec<i16, HISTORY>,
+}
+
+impl RecordingActuator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every value written so far, oldest first.
+    pub fn writes(&self) -> &[i16] {
+        &self.writes
+    }
+
+    pub fn last(&self) -> Option<i16> {
+        self.writes.last().copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+}
+
+impl Actuator for RecordingActuator {
+    fn write(&mut self, value: i16) {
+        // Drop the oldest entry rather than losing the newest one
+        if self.writes.is_full() {
+            self.writes.remove(0);
+        }
+        let _ = self.writes.push(value);
+    }
+}
+
+/// Range sensor that plays back a queue of canned readings.
+///
+/// Once the queue runs dry every measurement reports [`HcSr04Error::NoEcho`],
+/// like a sensor pointed at open sky.
+#[derive(Debug, Default)]
+pub struct ScriptedRangeSensor {
+    readings: Deque<Result<Length, HcSr04Error>, HISTORY>,
+    /// Polls a ping stays in flight for
+    latency: u8,
+    /// Polls left before the ping in flight comes back
+    in_flight: Option<u8>,
+}
+
+impl ScriptedRangeSensor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, reading: Result<Length, HcSr04Error>) {
+        let _ = self.readings.push_back(reading);
+    }
+
+    /// Make `poll_measurement` report `WouldBlock` this many times after each
+    /// `start_measurement`.
+    pub fn set_latency(&mut self, polls: u8) {
+        self.latency = polls;
+    }
+
+    pub fn in_flight(&self) -> bool {
+        self.in_flight.is_some()
+    }
+}
+
+impl RangeSensor for ScriptedRangeSensor {
+    fn measure_distance(&mut self) -> Result<Length, HcSr04Error> {
+        self.readings
+            .pop_front()
+            .unwrap_or(Err(HcSr04Error::NoEcho))
+    }
+
+    fn start_measurement(&mut self) {
+        self.in_flight.get_or_insert(self.latency);
+    }
+
+    fn poll_measurement(&mut self) -> nb::Result<Length, HcSr04Error> {
+        match self.in_flight {
+            Some(polls) if polls > 0 => {
+                self.in_flight = Some(polls - 1);
+                Err(nb::Error::WouldBlock)
+            }
+            _ => {
+                self.in_flight = None;
+                self.measure_distance().map_err(nb::Error::Other)
+            }
+        }
+    }
+}
+
+/// Clock that only moves when somebody waits on it.
+#[derive(Debug, Default)]
+pub struct ManualTimebase {
+    now: u32,
+}
+
+impl ManualTimebase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&mut self, ms: u32) {
+        self.now = self.now.wrapping_add(ms);
+    }
+}
+
+impl Timebase for ManualTimebase {
+    fn now_ms(&self) -> u32 {
+        self.now
+    }
+
+    fn delay_ms(&mut self, ms: u16) {
+        self.advance(ms as u32);
+    }
+}
+
+/// Remote control with the buttons already pressed.
+#[derive(Debug, Default)]
+pub struct QueuedCommands {
+    queue: Deque<Received, HISTORY>,
+    overflows: u16,
+}
+
+impl QueuedCommands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `cmd` as having come in at time `0`.
+    pub fn push(&mut self, cmd: impl Into<IrCommand>) {
+        self.push_at(cmd, 0);
+    }
+
+    /// Queue `cmd` as having come in at `at_ms`.
+    pub fn push_at(&mut self, cmd: impl Into<IrCommand>, at_ms: u32) {
+        let received = Received {
+            cmd: cmd.into(),
+            at_ms,
+        };
+        if self.queue.push_back(received).is_err() {
+            self.overflows = self.overflows.saturating_add(1);
+        }
+    }
+
+    /// Queue a press of `cmd` on the HackPack remote (address `0`).
+    pub fn press(&mut self, cmd: u8) {
+        self.push(NecCommand {
+            addr: 0,
+            cmd,
+            repeat: false,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl CommandSource for QueuedCommands {
+    fn next_command(&mut self) -> Option<Received> {
+        self.queue.pop_front()
+    }
+
+    fn take_overflows(&mut self) -> u16 {
+        core::mem::take(&mut self.overflows)
+    }
+}
+
+/// IR LED that remembers every frame it was asked to send.
+#[derive(Debug, Default)]
+pub struct RecordingTransmitter {
+    sent: Vec<IrCommand, HISTORY>,
+    busy: bool,
+}
+
+impl RecordingTransmitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every frame sent so far, oldest first.
+    pub fn sent(&self) -> &[IrCommand] {
+        &self.sent
+    }
+
+    /// Make `send` report `WouldBlock`, as if a frame were still going out.
+    pub fn set_busy(&mut self, busy: bool) {
+        self.busy = busy;
+    }
+}
+
+impl Transmitter for RecordingTransmitter {
+    fn send(&mut self, cmd: &IrCommand) -> nb::Result<(), TransmitError> {
+        nec_command(cmd)?;
+        if self.busy {
+            return Err(nb::Error::WouldBlock);
+        }
+        if self.sent.is_full() {
+            self.sent.remove(0);
+        }
+        let _ = self.sent.push(*cmd);
+        Ok(())
+    }
+}
@@ -1,73 +1,226 @@
-use core::{
-    cell::Cell,
-    sync::atomic::{AtomicU8, Ordering},
-};
+//! HC-SR04 ultrasonic range finder.
+//!
+//! Up to [`MAX_SENSORS`] sensors can be in use at once. Each one
+//! gets a slot that the interrupt handlers fill in with the times its echo pin
+//! went high and low again. The echo pin can be on `INT0`/`INT1` (D2/D3) or any
+//! pin with a pin-change interrupt, or on D8 for Timer1's input capture.
+
+#[cfg(target_arch = "avr")]
+use core::cell::RefCell;
 
+#[cfg(target_arch = "avr")]
 use arduino_hal::{
-    delay_us,
-    hal::port::Dynamic,
-    pac::EXINT,
+    hal::port::{Dynamic, PB0},
+    pac::TC1,
     port::{
         mode::{Floating, Input, Output},
         Pin, PinOps,
     },
 };
+#[cfg(target_arch = "avr")]
 use avr_device::interrupt::Mutex;
-use fugit::Duration;
+#[cfg(target_arch = "avr")]
+use uom::si::{f32::*, quantities::Time, time::microsecond};
 use uom::si::{
-    f32::*, quantities::Time, temperature_interval::degree_celsius, time::microsecond,
+    f32::{TemperatureInterval, Velocity},
+    temperature_interval::degree_celsius,
     velocity::meter_per_second,
 };
 
+#[cfg(target_arch = "avr")]
 use crate::{
-    clock::CLOCK,
-    interrupt::{AttachHwInterrupt, ExtIntMode},
+    clock::{Duration, FineInstant, Instant, SystemTimebase, CLOCK, TICK_HZ},
+    hal::RangeSensor,
+    interrupt::{
+        detach_pc_int, port_levels, AttachHwInterrupt, AttachPCInterrupt, ExtIntMode, PORT_C,
+        PORT_D,
+    },
 };
 
+#[cfg(target_arch = "avr")]
+mod capture;
+pub mod filter;
+
+#[cfg(target_arch = "avr")]
+use filter::{FilterConfig, Filtered};
+
+/// Most sensors timed by the interrupt handlers at once
+pub const MAX_SENSORS: usize = 4;
+
+#[cfg(target_arch = "avr")]
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 enum HcSr04State {
     Idle = 0,
     Triggered = 1,
     Measuring = 2,
+    Done = 3,
 }
 
+#[cfg(target_arch = "avr")]
 impl From<u8> for HcSr04State {
     fn from(value: u8) -> Self {
         match value {
             1 => HcSr04State::Triggered,
             2 => HcSr04State::Measuring,
+            3 => HcSr04State::Done,
             _ => HcSr04State::Idle,
         }
     }
 }
 
+/// Speed of sound in air at `temperature`. It goes up by about 0.6 m/s for
+/// every degree warmer, which is a couple of centimeters over a few meters.
+pub fn speed_of_sound(temperature: TemperatureInterval) -> Velocity {
+    Velocity::new::<meter_per_second>(331.3 + (0.606 * temperature.get::<degree_celsius>()))
+}
+
 #[derive(Clone, Copy, Debug, ufmt::derive::uDebug, PartialEq)]
 pub enum HcSr04Error {
     InvalidResult,
     NoEcho,
     NoTrigger,
+    /// Several pings came back, but none of them agreed on a distance
+    Inconsistent,
 }
 
-static STATE: AtomicU8 = AtomicU8::new(HcSr04State::Idle as u8);
-static TRIGGER_TIME: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
-static ECHO_TIME: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+/// Why a sensor couldn't be set up.
+#[cfg(target_arch = "avr")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum SetupError {
+    /// All [`MAX_SENSORS`] slots are taken
+    NoFreeSlot,
+    /// Timer1 is already counting for something else
+    Timer1Running,
+}
+
+/// What the interrupt handlers need to know about a sensor, and what they
+/// found out.
+#[cfg(target_arch = "avr")]
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    claimed: bool,
+    state: HcSr04State,
+    /// Port the echo pin is on, see [`AttachPCInterrupt::PORT`]
+    port: u8,
+    /// The echo pin's bit set, for picking it out of its port's levels
+    mask: u8,
+    /// When the echo pin went high, and low again
+    rise: Option<FineInstant>,
+    fall: Option<FineInstant>,
+}
 
+#[cfg(target_arch = "avr")]
+impl Slot {
+    const FREE: Slot = Slot {
+        claimed: false,
+        state: HcSr04State::Idle,
+        port: 0,
+        mask: 0,
+        rise: None,
+        fall: None,
+    };
+}
+
+#[cfg(target_arch = "avr")]
+static SLOTS: Mutex<RefCell<[Slot; MAX_SENSORS]>> =
+    Mutex::new(RefCell::new([Slot::FREE; MAX_SENSORS]));
+
+#[cfg(target_arch = "avr")]
+fn claim_slot(port: u8, mask: u8) -> Result<usize, SetupError> {
+    avr_device::interrupt::free(|cs| {
+        let mut slots = SLOTS.borrow(cs).borrow_mut();
+        let index = slots
+            .iter()
+            .position(|slot| !slot.claimed)
+            .ok_or(SetupError::NoFreeSlot)?;
+        slots[index] = Slot {
+            claimed: true,
+            port,
+            mask,
+            ..Slot::FREE
+        };
+        Ok(index)
+    })
+}
+
+#[cfg(target_arch = "avr")]
+fn with_slot<R>(index: usize, f: impl FnOnce(&mut Slot) -> R) -> R {
+    avr_device::interrupt::free(|cs| f(&mut SLOTS.borrow(cs).borrow_mut()[index]))
+}
+
+/// Timestamp the echo edges of every sensor on `port` that is waiting for one.
+///
+/// Goes by the pin levels rather than which interrupt fired, so it doesn't
+/// matter which handler on the port gets there first.
+#[cfg(target_arch = "avr")]
+pub(crate) fn echo_edge(port: u8) {
+    let now = CLOCK.now_fine();
+    let levels = port_levels(port);
+    avr_device::interrupt::free(|cs| {
+        for slot in SLOTS.borrow(cs).borrow_mut().iter_mut() {
+            if !slot.claimed || slot.port != port {
+                continue;
+            }
+            let high = levels & slot.mask != 0;
+            match (slot.state, high) {
+                // The ping is on its way
+                (HcSr04State::Triggered, true) => {
+                    slot.rise = Some(now);
+                    slot.state = HcSr04State::Measuring;
+                }
+                // The echo is back
+                (HcSr04State::Measuring, false) => {
+                    slot.fall = Some(now);
+                    slot.state = HcSr04State::Done;
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Which interrupt tells the handlers about the echo pin's edges.
+#[cfg(target_arch = "avr")]
+#[derive(Clone, Copy, Debug)]
+enum EdgeInterrupt {
+    /// `INT0` or `INT1`
+    External,
+    PinChange,
+}
+
+/// How the echo pulse gets timed.
+#[cfg(target_arch = "avr")]
+#[derive(Debug)]
+enum Echo {
+    /// Timestamped with [`CLOCK`] by an interrupt handler, into this slot.
+    /// Good to 25 µs, give or take other interrupts running first.
+    Clock(usize, EdgeInterrupt),
+    /// Timestamped by Timer1 itself. Good to 0.5 µs.
+    Capture(TC1),
+}
+
+#[cfg(target_arch = "avr")]
 pub struct HcSr04<ECHO> {
     trigger: Pin<Output, Dynamic>,
-    echo: Pin<Input<Floating>, ECHO>,
+    _echo_pin: Pin<Input<Floating>, ECHO>,
+    echo: Echo,
 
     trigger_time: u32,
     wait_time: u32,
+    /// When the ping in flight was sent
+    started: Instant,
 
     speed_of_sound: Velocity,
 
-    timeout: Duration<u32, 1, 40_000>,
+    timeout: Duration,
 }
 
+#[cfg(target_arch = "avr")]
 impl<ECHO> core::fmt::Debug for HcSr04<ECHO> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("HcSr04")
+            .field("echo", &self.echo)
             .field("trigger_time", &self.trigger_time)
             .field("wait_time", &self.wait_time)
             .field("speed_of_sound", &self.speed_of_sound)
@@ -76,50 +229,149 @@ impl<ECHO> core::fmt::Debug for HcSr04<ECHO> {
     }
 }
 
-#[allow(dead_code)]
+#[cfg(target_arch = "avr")]
 impl<ECHO> HcSr04<ECHO>
 where
-    Pin<Input<Floating>, ECHO>: AttachHwInterrupt,
+    Pin<Input<Floating>, ECHO>: AttachHwInterrupt + AttachPCInterrupt,
     ECHO: PinOps,
 {
-    #[allow(dead_code)]
+    /// Time the echo on `echo` with its external interrupt, `INT0` or `INT1`.
     pub fn new<TRIGGER>(
         temperature: TemperatureInterval,
         trigger: Pin<Output, TRIGGER>,
         echo: Pin<Input<Floating>, ECHO>,
-    ) -> Self
+    ) -> Result<Self, SetupError>
     where
-        TRIGGER: arduino_hal::port::PinOps<Dynamic = Dynamic>,
+        TRIGGER: PinOps<Dynamic = Dynamic>,
     {
-        let trigger = trigger.downgrade();
-        let speed_of_sound = Velocity::new::<meter_per_second>(
-            331.0 + (0.606 * temperature.get::<degree_celsius>()),
-        );
-        let timeout_seconds = 4.0 / speed_of_sound.get::<meter_per_second>() * 2.0;
-        let timeout_ticks = timeout_seconds * 80_000.0;
-        let timeout = Duration::<u32, 1, 40_000>::from_ticks(timeout_ticks as u32);
-
-        Self {
+        let slot = claim_slot(
+            <Pin<Input<Floating>, ECHO> as AttachPCInterrupt>::PORT,
+            <Pin<Input<Floating>, ECHO> as AttachPCInterrupt>::PIN,
+        )?;
+        // The echo pin goes high when the ping leaves and low when it comes
+        // back, so the handler wants to hear about both edges
+        echo.attach_hw_int(&exint(), ExtIntMode::Change);
+        Ok(Self::with_echo(
+            temperature,
+            trigger.downgrade(),
+            echo,
+            Echo::Clock(slot, EdgeInterrupt::External),
+        ))
+    }
+}
+
+#[cfg(target_arch = "avr")]
+impl<ECHO> HcSr04<ECHO>
+where
+    Pin<Input<Floating>, ECHO>: AttachPCInterrupt,
+    ECHO: PinOps,
+{
+    /// Time the echo on `echo` with its pin-change interrupt, so it can go on
+    /// any pin. Port B's interrupt is shared with the IR receiver, so ports C
+    /// (A0-A5) and D are the better choice.
+    pub fn with_pin_change<TRIGGER>(
+        temperature: TemperatureInterval,
+        trigger: Pin<Output, TRIGGER>,
+        echo: Pin<Input<Floating>, ECHO>,
+    ) -> Result<Self, SetupError>
+    where
+        TRIGGER: PinOps<Dynamic = Dynamic>,
+    {
+        let slot = claim_slot(
+            <Pin<Input<Floating>, ECHO> as AttachPCInterrupt>::PORT,
+            <Pin<Input<Floating>, ECHO> as AttachPCInterrupt>::PIN,
+        )?;
+        echo.attach_pc_int(&exint());
+        Ok(Self::with_echo(
+            temperature,
+            trigger.downgrade(),
+            echo,
+            Echo::Clock(slot, EdgeInterrupt::PinChange),
+        ))
+    }
+}
+
+#[cfg(target_arch = "avr")]
+impl HcSr04<PB0> {
+    /// Time the echo with Timer1's input capture instead, which needs the
+    /// echo wired to D8 and the timer to itself.
+    ///
+    /// This takes Timer1 itself, so it can't also go to `crate::servo`. Fails
+    /// if something else, like the Arduino `Servo` library, already has the
+    /// timer running. The Arduino library only starts the timer once a servo
+    /// is attached, so the check can't catch servos attached after this.
+    pub fn with_input_capture<TRIGGER>(
+        temperature: TemperatureInterval,
+        trigger: Pin<Output, TRIGGER>,
+        echo: Pin<Input<Floating>, PB0>,
+        tc1: TC1,
+    ) -> Result<Self, SetupError>
+    where
+        TRIGGER: PinOps<Dynamic = Dynamic>,
+    {
+        capture::claim(&tc1)?;
+        capture::init(&tc1);
+        Ok(Self::with_echo(
+            temperature,
+            trigger.downgrade(),
+            echo,
+            Echo::Capture(tc1),
+        ))
+    }
+}
+
+#[cfg(target_arch = "avr")]
+#[allow(dead_code)]
+impl<ECHO> HcSr04<ECHO>
+where
+    ECHO: PinOps,
+{
+    fn with_echo(
+        temperature: TemperatureInterval,
+        trigger: Pin<Output, Dynamic>,
+        echo_pin: Pin<Input<Floating>, ECHO>,
+        echo: Echo,
+    ) -> Self {
+        let mut hc_sr04 = Self {
             trigger,
+            _echo_pin: echo_pin,
             echo,
 
             trigger_time: 10,
             wait_time: 10,
+            started: Instant::from_ticks(0),
 
-            speed_of_sound,
-            timeout,
-        }
+            speed_of_sound: Velocity::new::<meter_per_second>(0.0),
+            timeout: Duration::from_ticks(0),
+        };
+        hc_sr04.set_temperature(temperature);
+        hc_sr04
     }
 
-    #[allow(dead_code)]
-    pub fn measure_us(&mut self, exint: &EXINT) -> Result<Duration<u32, 1, 40_000>, HcSr04Error> {
-        assert!(STATE.load(Ordering::SeqCst) == HcSr04State::Idle as u8);
-        let start = CLOCK.now_instant();
+    /// Work out distances for air at `temperature` from now on.
+    pub fn set_temperature(&mut self, temperature: TemperatureInterval) {
+        self.speed_of_sound = speed_of_sound(temperature);
+        let timeout_seconds = 4.0 / self.speed_of_sound.get::<meter_per_second>() * 2.0;
+        let timeout_ticks = timeout_seconds * TICK_HZ as f32;
+        self.timeout = Duration::from_ticks(timeout_ticks as u64);
+    }
+
+    fn state(&self) -> HcSr04State {
+        match self.echo {
+            Echo::Clock(slot, _) => with_slot(slot, |slot| slot.state),
+            Echo::Capture(_) => capture::state(),
+        }
+    }
 
-        avr_device::interrupt::free(|cs| {
-            TRIGGER_TIME.borrow(cs).set(0);
-            ECHO_TIME.borrow(cs).set(0);
-        });
+    /// Send a ping and return straight away. The echo is timestamped from an
+    /// interrupt as it comes back, and [`HcSr04::poll_measurement`] picks up
+    /// the result.
+    ///
+    /// Does nothing if a ping is already in flight.
+    pub fn start_measurement(&mut self) {
+        if self.state() != HcSr04State::Idle {
+            return;
+        }
 
         // Ensure trigger pin is low
         self.trigger.set_low();
@@ -135,84 +387,180 @@ where
         self.trigger.set_low();
         arduino_hal::delay_us(self.wait_time);
 
-        STATE.store(HcSr04State::Triggered as u8, Ordering::SeqCst);
-        // Attach interrupt to echo pin for the starting point
-        // TODO: add static status atomic to check state
-        self.echo.attach_hw_int(exint, ExtIntMode::Rising);
-
-        loop {
-            let checked_duration_since = CLOCK
-                .now_instant()
-                .checked_duration_since(start)
-                .expect("Should be in the future");
-            if checked_duration_since > self.timeout {
-                // Set waiting to false, because we timedout
-                // waiting = false;
-                break;
+        self.started = CLOCK.now_instant();
+        match &self.echo {
+            Echo::Clock(slot, _) => with_slot(*slot, |slot| {
+                slot.rise = None;
+                slot.fall = None;
+                slot.state = HcSr04State::Triggered;
+            }),
+            Echo::Capture(tc1) => capture::arm(tc1),
+        }
+    }
+
+    /// Round trip time of the ping sent by [`HcSr04::start_measurement`], or
+    /// `WouldBlock` while it is still in flight.
+    ///
+    /// Fails with [`HcSr04Error::NoTrigger`] if no ping was sent.
+    pub fn poll_echo(&mut self) -> nb::Result<Time, HcSr04Error> {
+        match self.state() {
+            HcSr04State::Idle => return Err(nb::Error::Other(HcSr04Error::NoTrigger)),
+            HcSr04State::Done => {}
+            HcSr04State::Triggered | HcSr04State::Measuring => {
+                if CLOCK.elapsed_since(self.started) <= self.timeout {
+                    return Err(nb::Error::WouldBlock);
+                }
             }
-            delay_us(1);
+        }
 
-            let trigger = avr_device::interrupt::free(|cs| TRIGGER_TIME.borrow(cs).get());
-            if trigger > 0 && STATE.load(Ordering::SeqCst) == HcSr04State::Triggered as u8 {
-                STATE.store(HcSr04State::Measuring as u8, Ordering::SeqCst);
+        let echo = match &self.echo {
+            Echo::Clock(slot, _) => finish_slot(*slot),
+            Echo::Capture(tc1) => capture::finish(tc1),
+        };
+        echo.map_err(nb::Error::Other)
+    }
 
-                // Attach interrupt to echo pin for the ending point
-                self.echo.attach_hw_int(exint, ExtIntMode::Falling);
-            }
+    /// Distance measured by the ping sent by [`HcSr04::start_measurement`], or
+    /// `WouldBlock` while it is still in flight.
+    pub fn poll_measurement(&mut self) -> nb::Result<Length, HcSr04Error> {
+        let duration = self.poll_echo()?;
+        Ok(self.speed_of_sound * duration / 2.0)
+    }
+
+    /// Send a ping and wait for it to come back.
+    pub fn measure_echo(&mut self) -> Result<Time, HcSr04Error> {
+        self.start_measurement();
+        nb::block!(self.poll_echo())
+    }
+
+    pub fn measure_distance(&mut self) -> Result<Length, HcSr04Error> {
+        self.start_measurement();
+        nb::block!(self.poll_measurement())
+    }
+
+    /// Take several pings and boil them down to one distance, see
+    /// [`filter::measure`].
+    pub fn measure_filtered(&mut self, config: &FilterConfig) -> Result<Filtered, HcSr04Error> {
+        filter::measure(self, &mut SystemTimebase, config)
+    }
+}
 
-            let echo = avr_device::interrupt::free(|cs| ECHO_TIME.borrow(cs).get());
-            if trigger > 0
-                && echo > 0
-                && STATE.load(Ordering::SeqCst) == HcSr04State::Measuring as u8
-            {
-                break;
+#[cfg(target_arch = "avr")]
+impl<ECHO> Drop for HcSr04<ECHO> {
+    fn drop(&mut self) {
+        match &self.echo {
+            Echo::Clock(index, interrupt) => {
+                let index = *index;
+                // Silence the pin before the slot can go to another sensor
+                let slot = with_slot(index, |slot| *slot);
+                let exint = exint();
+                match interrupt {
+                    EdgeInterrupt::External if slot.mask == 1 << 2 => {
+                        exint.eimsk.modify(|_, w| w.int0().clear_bit())
+                    }
+                    EdgeInterrupt::External => exint.eimsk.modify(|_, w| w.int1().clear_bit()),
+                    EdgeInterrupt::PinChange => detach_pc_int(&exint, slot.port, slot.mask),
+                }
+                with_slot(index, |slot| *slot = Slot::FREE);
+            }
+            // Leave Timer1 stopped and quiet, so whatever gets it next passes
+            // `capture::claim`
+            Echo::Capture(tc1) => {
+                tc1.timsk1.write(|w| w);
+                tc1.tccr1b.write(|w| w.cs1().no_clock());
             }
         }
+    }
+}
 
-        // Detach interrupt from echo pin
-        self.echo.detach_hw_int(exint);
-        STATE.store(HcSr04State::Idle as u8, Ordering::SeqCst);
+#[cfg(target_arch = "avr")]
+impl<ECHO> RangeSensor for HcSr04<ECHO>
+where
+    ECHO: PinOps,
+{
+    fn measure_distance(&mut self) -> Result<Length, HcSr04Error> {
+        HcSr04::measure_distance(self)
+    }
 
-        let (trigger, echo) = avr_device::interrupt::free(|cs| {
-            (TRIGGER_TIME.borrow(cs).get(), ECHO_TIME.borrow(cs).get())
-        });
+    fn start_measurement(&mut self) {
+        HcSr04::start_measurement(self);
+    }
 
-        if trigger == 0 {
-            return Err(HcSr04Error::NoTrigger);
-        }
-        if echo == 0 {
-            return Err(HcSr04Error::NoEcho);
-        }
-        if echo <= trigger {
-            return Err(HcSr04Error::InvalidResult);
-        }
-        Ok(Duration::<u32, 1, 40_000>::from_ticks(echo - trigger))
+    fn poll_measurement(&mut self) -> nb::Result<Length, HcSr04Error> {
+        HcSr04::poll_measurement(self)
     }
 
-    pub fn measure_distance(&mut self, exint: &EXINT) -> Result<Length, HcSr04Error> {
-        let duration = self.measure_us(exint)?;
-        let duration = Time::new::<microsecond>(duration.to_micros() as f32);
-        Ok(self.speed_of_sound * duration / 2.0)
+    fn set_temperature(&mut self, temperature: TemperatureInterval) {
+        HcSr04::set_temperature(self, temperature);
     }
 }
 
-/// External Interrupt 1
-/// This is for 3, or PD3
+/// Stop timing the ping on `slot` and work out how long the echo pulse was.
+#[cfg(target_arch = "avr")]
+fn finish_slot(slot: usize) -> Result<Time, HcSr04Error> {
+    let (rise, fall) = with_slot(slot, |slot| {
+        slot.state = HcSr04State::Idle;
+        (slot.rise, slot.fall)
+    });
+
+    let rise = rise.ok_or(HcSr04Error::NoTrigger)?;
+    let fall = fall.ok_or(HcSr04Error::NoEcho)?;
+    let duration = fall
+        .checked_duration_since(rise)
+        .filter(|duration| duration.ticks() > 0)
+        .ok_or(HcSr04Error::InvalidResult)?;
+    Ok(Time::new::<microsecond>(duration.to_micros() as f32))
+}
+
+#[cfg(target_arch = "avr")]
+fn exint() -> arduino_hal::pac::EXINT {
+    // Safety: the driver only ever touches the interrupt bits that belong to
+    // its own echo pin, which nothing else is allowed to use.
+    unsafe { arduino_hal::Peripherals::steal() }.EXINT
+}
+
+/// External Interrupt 0, on D2 or PD2
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn INT0() {
+    echo_edge(PORT_D);
+}
+
+/// External Interrupt 1, on D3 or PD3
+#[cfg(target_arch = "avr")]
 #[avr_device::interrupt(atmega328p)]
 fn INT1() {
-    match STATE.load(Ordering::SeqCst).into() {
-        HcSr04State::Triggered => {
-            // Start measuring
-            avr_device::interrupt::free(|cs| {
-                TRIGGER_TIME.borrow(cs).set(CLOCK.now());
-            });
-        }
-        HcSr04State::Measuring => {
-            // Stop measuring
-            avr_device::interrupt::free(|cs| {
-                ECHO_TIME.borrow(cs).set(CLOCK.now());
-            });
-        }
-        _ => {}
+    echo_edge(PORT_D);
+}
+
+/// Pin changes on A0-A5. Port B's are handled alongside the IR receiver in
+/// `ir`.
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn PCINT1() {
+    echo_edge(PORT_C);
+}
+
+/// Pin changes on D0-D7
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn PCINT2() {
+    echo_edge(PORT_D);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speed_at(celsius: f32) -> f32 {
+        speed_of_sound(TemperatureInterval::new::<degree_celsius>(celsius))
+            .get::<meter_per_second>()
+    }
+
+    #[test]
+    fn sound_is_faster_in_warm_air() {
+        assert!((speed_at(0.0) - 331.3).abs() < 0.01);
+        assert!((speed_at(20.0) - 343.4).abs() < 0.1);
+        assert!((speed_at(-10.0) - 325.2).abs() < 0.1);
     }
 }
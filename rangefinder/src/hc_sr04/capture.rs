@@ -0,0 +1,147 @@
+//! Echo timing with Timer1's input capture unit.
+//!
+//! Timer1 runs freely at 2 MHz and latches its count into `ICR1` the moment
+//! PB0/ICP1 changes, so the edges are timestamped in hardware instead of
+//! whenever an interrupt handler gets round to reading a clock. The counter
+//! wraps every 32.8 ms. That is longer than any echo from within the driver's
+//! 4 m range, but a sensor that hears nothing back holds the echo pin high for
+//! about 38 ms. So the overflows between the two edges are counted as well,
+//! and a pulse that wrapped the counter is [`HcSr04Error::NoEcho`] rather than
+//! a short distance.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use arduino_hal::pac::TC1;
+use avr_device::interrupt::Mutex;
+use uom::si::{f32::Time, time::microsecond};
+
+use super::{HcSr04Error, HcSr04State, SetupError};
+
+/// Microseconds per Timer1 tick with a prescaler of 8
+const US_PER_TICK: f32 = 0.5;
+
+/// Captures this far up the count were taken late in Timer1's cycle, so an
+/// overflow still waiting to be handled happened after them
+const HALF: u16 = 0x8000;
+
+static STATE: AtomicU8 = AtomicU8::new(HcSr04State::Idle as u8);
+static RISE: Mutex<Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+static FALL: Mutex<Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+/// Times Timer1 wrapped between the rising edge and the falling one
+static OVERFLOWS: AtomicU8 = AtomicU8::new(0);
+
+/// Check nobody else is using Timer1.
+pub(super) fn claim(tc1: &TC1) -> Result<(), SetupError> {
+    if tc1.tccr1b.read().cs1().bits() != 0 {
+        return Err(SetupError::Timer1Running);
+    }
+    Ok(())
+}
+
+/// Start Timer1 counting, with the capture interrupt off until a ping is sent.
+pub(super) fn init(tc1: &TC1) {
+    tc1.timsk1.write(|w| w);
+    // Normal counting mode
+    tc1.tccr1a.write(|w| w.wgm1().bits(0));
+    // Filter out glitches on the echo pin, and a prescaler of 8
+    tc1.tccr1b
+        .write(|w| w.icnc1().set_bit().ices1().set_bit().cs1().prescale_8());
+}
+
+pub(super) fn state() -> HcSr04State {
+    STATE.load(Ordering::SeqCst).into()
+}
+
+/// Wait for the rising edge of the echo pulse.
+pub(super) fn arm(tc1: &TC1) {
+    avr_device::interrupt::free(|cs| {
+        RISE.borrow(cs).set(None);
+        FALL.borrow(cs).set(None);
+    });
+    STATE.store(HcSr04State::Triggered as u8, Ordering::SeqCst);
+
+    tc1.tccr1b.modify(|_, w| w.ices1().set_bit());
+    // Throw away any capture from before the ping
+    tc1.tifr1.write(|w| w.icf1().set_bit());
+    tc1.timsk1.modify(|_, w| w.icie1().set_bit());
+}
+
+/// Stop listening and work out how long the echo pulse was.
+pub(super) fn finish(tc1: &TC1) -> Result<Time, HcSr04Error> {
+    tc1.timsk1
+        .modify(|_, w| w.icie1().clear_bit().toie1().clear_bit());
+    STATE.store(HcSr04State::Idle as u8, Ordering::SeqCst);
+
+    let (rise, fall) =
+        avr_device::interrupt::free(|cs| (RISE.borrow(cs).get(), FALL.borrow(cs).get()));
+    let rise = rise.ok_or(HcSr04Error::NoTrigger)?;
+    let fall = fall.ok_or(HcSr04Error::NoEcho)?;
+    // The wrapping subtraction covers one overflow if the count went past the
+    // top between the edges. Any more and the pulse was longer than an echo
+    // can be, i.e. the sensor giving up.
+    let wraps = OVERFLOWS
+        .load(Ordering::SeqCst)
+        .saturating_sub((fall < rise) as u8);
+    if wraps > 0 {
+        return Err(HcSr04Error::NoEcho);
+    }
+    let ticks = fall.wrapping_sub(rise);
+    if ticks == 0 {
+        return Err(HcSr04Error::InvalidResult);
+    }
+    Ok(Time::new::<microsecond>(ticks as f32 * US_PER_TICK))
+}
+
+/// Timer1 input capture, fires on PB0/ICP1 edges
+#[avr_device::interrupt(atmega328p)]
+fn TIMER1_CAPT() {
+    // Safety: the interrupt is only enabled while an `HcSr04` built with
+    // `with_input_capture` owns Timer1
+    let tc1 = unsafe { arduino_hal::Peripherals::steal() }.TC1;
+    let at = tc1.icr1.read().bits();
+
+    match state() {
+        HcSr04State::Triggered => {
+            avr_device::interrupt::free(|cs| RISE.borrow(cs).set(Some(at)));
+            // An overflow that hasn't been handled yet only counts if it came
+            // after the capture
+            let late = tc1.tifr1.read().tov1().bit_is_set() && at >= HALF;
+            OVERFLOWS.store(late as u8, Ordering::SeqCst);
+            tc1.tifr1.write(|w| w.tov1().set_bit());
+            tc1.timsk1.modify(|_, w| w.toie1().set_bit());
+            STATE.store(HcSr04State::Measuring as u8, Ordering::SeqCst);
+            // Catch the end of the pulse next. Changing the edge can set the
+            // flag by itself, so clear it.
+            tc1.tccr1b.modify(|_, w| w.ices1().clear_bit());
+            tc1.tifr1.write(|w| w.icf1().set_bit());
+        }
+        HcSr04State::Measuring => {
+            avr_device::interrupt::free(|cs| FALL.borrow(cs).set(Some(at)));
+            // This time it only counts if it came before the capture
+            if tc1.tifr1.read().tov1().bit_is_set() && at < HALF {
+                count_overflow();
+            }
+            STATE.store(HcSr04State::Done as u8, Ordering::SeqCst);
+            tc1.timsk1
+                .modify(|_, w| w.icie1().clear_bit().toie1().clear_bit());
+        }
+        _ => {}
+    }
+}
+
+/// Timer1 overflow, only enabled while the echo pulse is being timed
+#[avr_device::interrupt(atmega328p)]
+fn TIMER1_OVF() {
+    if state() == HcSr04State::Measuring {
+        count_overflow();
+    }
+}
+
+/// Only called from Timer1's interrupts, which can't interrupt each other
+fn count_overflow() {
+    let overflows = OVERFLOWS.load(Ordering::SeqCst);
+    OVERFLOWS.store(overflows.saturating_add(1), Ordering::SeqCst);
+}
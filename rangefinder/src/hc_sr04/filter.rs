@@ -0,0 +1,256 @@
+//! Steadier distances from several pings.
+//!
+//! A single HC-SR04 reading jumps around: echoes off a second surface come
+//! back late, and now and then one goes missing altogether. [`measure`] takes a
+//! handful of pings, throws away the failures and the ones far from the
+//! median, and reports what is left along with how much it can be trusted.
+
+use heapless::Vec;
+use uom::si::{
+    f32::Length,
+    length::{centimeter, meter},
+};
+
+use super::HcSr04Error;
+use crate::hal::{RangeSensor, Timebase};
+
+/// Most pings a single filtered measurement can take
+pub const MAX_SAMPLES: usize = 16;
+
+/// How long to wait between pings so the previous one's echoes have died
+/// down. The datasheet asks for at least 60 ms.
+pub const PING_GAP_MS: u16 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterConfig {
+    /// Pings to take, at most [`MAX_SAMPLES`]
+    pub samples: u8,
+    /// Milliseconds between pings
+    pub gap_ms: u16,
+    /// Readings further than this from the median are treated as outliers
+    pub tolerance: Length,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            samples: 5,
+            gap_ms: PING_GAP_MS,
+            tolerance: Length::new::<centimeter>(5.0),
+        }
+    }
+}
+
+/// Result of a filtered measurement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filtered {
+    /// Median of the good readings
+    pub median: Length,
+    /// Mean of the readings within tolerance of the median
+    pub mean: Length,
+    /// Difference between the furthest and closest readings that were kept
+    pub spread: Length,
+    /// Readings that were kept
+    pub kept: u8,
+    /// Pings that were sent
+    pub samples: u8,
+}
+
+impl Filtered {
+    /// Fraction of pings that ended up being kept, from `0.0` to `1.0`.
+    pub fn confidence(&self) -> f32 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        self.kept as f32 / self.samples as f32
+    }
+}
+
+/// Raw readings for one filtered measurement, kept apart from the sensor so
+/// the maths can be checked on the host.
+#[derive(Clone, Debug, Default)]
+pub struct Samples {
+    /// Successful readings, in meters
+    readings: Vec<f32, MAX_SAMPLES>,
+    samples: u8,
+    last_error: Option<HcSr04Error>,
+    no_echo: bool,
+}
+
+impl Samples {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a reading. Anything past [`MAX_SAMPLES`] is dropped.
+    pub fn push(&mut self, reading: Result<Length, HcSr04Error>) {
+        if self.samples as usize >= MAX_SAMPLES {
+            return;
+        }
+        self.samples += 1;
+        match reading {
+            Ok(distance) => {
+                let _ = self.readings.push(distance.value);
+            }
+            Err(e) => {
+                self.no_echo |= e == HcSr04Error::NoEcho;
+                self.last_error = Some(e);
+            }
+        }
+    }
+
+    /// Boil the readings down to one distance.
+    ///
+    /// Fails if not a single ping came back. [`HcSr04Error::NoEcho`] wins over
+    /// the other errors, since it means there is nothing there rather than the
+    /// sensor misbehaving. Fails with [`HcSr04Error::Inconsistent`] if none of
+    /// the readings are within `tolerance` of the median.
+    pub fn filter(&self, tolerance: Length) -> Result<Filtered, HcSr04Error> {
+        if self.readings.is_empty() {
+            if self.no_echo {
+                return Err(HcSr04Error::NoEcho);
+            }
+            return Err(self.last_error.unwrap_or(HcSr04Error::NoTrigger));
+        }
+
+        let mut sorted = self.readings.clone();
+        sorted.sort_unstable_by(f32::total_cmp);
+        // Same index twice for an odd count, the two middle ones for an even
+        let median = (sorted[(sorted.len() - 1) / 2] + sorted[sorted.len() / 2]) / 2.0;
+
+        let tolerance = tolerance.value;
+        let mut kept = sorted
+            .iter()
+            .copied()
+            .filter(|&d| d >= median - tolerance && d <= median + tolerance);
+        // An even count can put the median in a gap wider than the tolerance,
+        // with nothing near it to go by
+        let first = kept.next().ok_or(HcSr04Error::Inconsistent)?;
+        let (sum, count, last) = kept.fold((first, 1u8, first), |(sum, count, _), d| {
+            (sum + d, count + 1, d)
+        });
+
+        Ok(Filtered {
+            median: length(median),
+            mean: length(sum / count as f32),
+            spread: length(last - first),
+            kept: count,
+            samples: self.samples,
+        })
+    }
+}
+
+fn length(meters: f32) -> Length {
+    Length::new::<meter>(meters)
+}
+
+/// Ping `sensor` `config.samples` times, `config.gap_ms` apart, and filter the
+/// results.
+pub fn measure<S, T>(
+    sensor: &mut S,
+    timebase: &mut T,
+    config: &FilterConfig,
+) -> Result<Filtered, HcSr04Error>
+where
+    S: RangeSensor,
+    T: Timebase,
+{
+    let mut samples = Samples::new();
+    for i in 0..config.samples.min(MAX_SAMPLES as u8) {
+        if i > 0 {
+            timebase.delay_ms(config.gap_ms);
+        }
+        samples.push(sensor.measure_distance());
+    }
+    samples.filter(config.tolerance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::host::{ManualTimebase, ScriptedRangeSensor};
+
+    fn cm(centimeters: f32) -> Result<Length, HcSr04Error> {
+        Ok(Length::new::<centimeter>(centimeters))
+    }
+
+    fn close(a: Length, centimeters: f32) -> bool {
+        (a.get::<centimeter>() - centimeters).abs() < 0.01
+    }
+
+    #[test]
+    fn drops_failures_and_outliers() {
+        let mut samples = Samples::new();
+        for reading in [
+            cm(100.0),
+            Err(HcSr04Error::NoEcho),
+            cm(102.0),
+            cm(180.0),
+            cm(98.0),
+            Err(HcSr04Error::InvalidResult),
+        ] {
+            samples.push(reading);
+        }
+
+        let filtered = samples.filter(Length::new::<centimeter>(5.0)).unwrap();
+
+        assert!(close(filtered.median, 101.0));
+        assert!(close(filtered.mean, 100.0));
+        assert!(close(filtered.spread, 4.0));
+        assert_eq!((filtered.kept, filtered.samples), (3, 6));
+        assert_eq!(filtered.confidence(), 0.5);
+    }
+
+    #[test]
+    fn reports_why_nothing_came_back() {
+        let mut samples = Samples::new();
+        samples.push(Err(HcSr04Error::InvalidResult));
+        assert_eq!(
+            samples.filter(Length::new::<meter>(0.05)),
+            Err(HcSr04Error::InvalidResult)
+        );
+
+        samples.push(Err(HcSr04Error::NoEcho));
+        samples.push(Err(HcSr04Error::NoTrigger));
+        assert_eq!(
+            samples.filter(Length::new::<meter>(0.05)),
+            Err(HcSr04Error::NoEcho)
+        );
+    }
+
+    #[test]
+    fn scattered_readings_are_not_averaged() {
+        let mut samples = Samples::new();
+        for reading in [cm(50.0), cm(90.0), cm(140.0), cm(200.0)] {
+            samples.push(reading);
+        }
+
+        assert_eq!(
+            samples.filter(Length::new::<centimeter>(5.0)),
+            Err(HcSr04Error::Inconsistent)
+        );
+        // Loose enough to take the two middle ones
+        let filtered = samples.filter(Length::new::<centimeter>(30.0)).unwrap();
+        assert!(close(filtered.mean, 115.0));
+        assert_eq!((filtered.kept, filtered.samples), (2, 4));
+    }
+
+    #[test]
+    fn waits_between_pings() {
+        let mut sensor = ScriptedRangeSensor::new();
+        for reading in [cm(50.0), cm(51.0), cm(49.0)] {
+            sensor.push(reading);
+        }
+        let mut timebase = ManualTimebase::new();
+        let config = FilterConfig {
+            samples: 3,
+            ..FilterConfig::default()
+        };
+
+        let filtered = measure(&mut sensor, &mut timebase, &config).unwrap();
+
+        assert_eq!(timebase.now_ms(), 2 * PING_GAP_MS as u32);
+        assert!(close(filtered.median, 50.0));
+        assert_eq!(filtered.confidence(), 1.0);
+    }
+}
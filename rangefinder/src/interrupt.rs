@@ -1,7 +1,18 @@
-use arduino_hal::{hal::port::*, pac::EXINT, port::mode::Input};
+use arduino_hal::{
+    hal::port::*,
+    pac::{EXINT, PORTB, PORTC, PORTD},
+    port::mode::Input,
+};
+
+/// `PCICR` bits for the three pin-change interrupts, one per port
+pub const PORT_B: u8 = 0b001;
+pub const PORT_C: u8 = 0b010;
+pub const PORT_D: u8 = 0b100;
 
 pub trait AttachPCInterrupt {
+    /// One of [`PORT_B`], [`PORT_C`] or [`PORT_D`]
     const PORT: u8;
+    /// The pin's bit set, as a mask for its port
     const PIN: u8;
 
     /// Attach a pin change interrupt to the pin
@@ -12,14 +23,25 @@ pub trait AttachPCInterrupt {
         exint
             .pcicr
             .modify(|r, w| unsafe { w.bits(Self::PORT | r.bits()) });
-        // Enable PC inetrrupt for PIN
-        exint.pcmsk0.modify(|r, w| w.bits(Self::PIN | r.bits()));
+        // Enable PC inetrrupt for PIN, in the mask register for its port
+        #[allow(unused_unsafe)]
+        match Self::PORT {
+            PORT_B => exint
+                .pcmsk0
+                .modify(|r, w| unsafe { w.bits(Self::PIN | r.bits()) }),
+            PORT_C => exint
+                .pcmsk1
+                .modify(|r, w| unsafe { w.bits(Self::PIN | r.bits()) }),
+            _ => exint
+                .pcmsk2
+                .modify(|r, w| unsafe { w.bits(Self::PIN | r.bits()) }),
+        }
     }
 }
 
 macro_rules! attach_pc_interrupt {
     (
-        $name:ident = $port:literal; [$($pin:literal),+]
+        $name:ident = $port:expr; [$($pin:literal),+]
     ) => {
         $(
             paste::paste! {
@@ -32,9 +54,39 @@ macro_rules! attach_pc_interrupt {
     };
 }
 
-attach_pc_interrupt!(PB = 0b001; [0, 1, 2, 3, 4, 5, 6, 7]);
-attach_pc_interrupt!(PC = 0b010; [0, 1, 2, 3, 4, 5, 6]);
-attach_pc_interrupt!(PD = 0b100; [0, 1, 2, 3, 4, 5, 6, 7]);
+attach_pc_interrupt!(PB = PORT_B; [0, 1, 2, 3, 4, 5, 6, 7]);
+attach_pc_interrupt!(PC = PORT_C; [0, 1, 2, 3, 4, 5, 6]);
+attach_pc_interrupt!(PD = PORT_D; [0, 1, 2, 3, 4, 5, 6, 7]);
+
+/// Undo [`AttachPCInterrupt::attach_pc_int`] for the pins in `mask` on `port`.
+/// The port's interrupt stays on for any other pins on it.
+pub fn detach_pc_int(exint: &EXINT, port: u8, mask: u8) {
+    #[allow(unused_unsafe)]
+    match port {
+        PORT_B => exint
+            .pcmsk0
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+        PORT_C => exint
+            .pcmsk1
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+        _ => exint
+            .pcmsk2
+            .modify(|r, w| unsafe { w.bits(r.bits() & !mask) }),
+    }
+}
+
+/// Current level of every pin on `port`, one of [`PORT_B`], [`PORT_C`] or
+/// [`PORT_D`], as read from its `PINx` register.
+pub fn port_levels(port: u8) -> u8 {
+    // Safety: reading the input register has no side effects
+    unsafe {
+        match port {
+            PORT_B => (*PORTB::ptr()).pinb.read().bits(),
+            PORT_C => (*PORTC::ptr()).pinc.read().bits(),
+            _ => (*PORTD::ptr()).pind.read().bits(),
+        }
+    }
+}
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -1,5 +1,10 @@
-use core::cell::Cell;
+#[cfg(target_arch = "avr")]
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
+#[cfg(target_arch = "avr")]
 use arduino_hal::{
     hal::port::PB1,
     port::{
@@ -7,74 +12,385 @@ use arduino_hal::{
         Pin,
     },
 };
+#[cfg(target_arch = "avr")]
 use avr_device::interrupt::Mutex;
+#[cfg(target_arch = "avr")]
+use heapless::spsc::{Consumer, Producer, Queue};
+use infrared::protocol::nec::NecCommand;
+#[cfg(all(target_arch = "avr", not(feature = "multi-protocol-ir")))]
+use infrared::{protocol::Nec, Receiver};
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
 use infrared::{
-    protocol::{nec::NecCommand, *},
-    Receiver,
+    protocol::{Nec, NecSamsung, Rc5, Rc6},
+    receiver::multi::{CmdEnum, MultiReceiver},
+};
+
+#[cfg(target_arch = "avr")]
+use crate::{
+    clock::{Clock, CLOCK},
+    hal::CommandSource,
+    hc_sr04,
+    interrupt::{port_levels, PORT_B},
 };
 
-use crate::clock::{Clock, CLOCK};
+mod keymap;
+mod learn;
+pub mod sirc;
+pub mod transmitter;
+
+#[cfg(target_arch = "avr")]
+pub use keymap::KeyMapStore;
+pub use keymap::{
+    hackpack_cmd, Key, KeyMap, KeyMapError, HACKPACK_ADDR, KEYMAP_BYTES, KEYMAP_CAPACITY,
+};
+pub use learn::{Learned, Learner, LEARNABLE};
+use sirc::SircCommand;
+#[cfg(target_arch = "avr")]
+pub use transmitter::IrTransmitter;
+pub use transmitter::TransmitError;
 
 pub const LEFT: u8 = 0x8;
 pub const RIGHT: u8 = 0x5A;
 pub const UP: u8 = 0x52;
 pub const DOWN: u8 = 0x18;
 pub const OK: u8 = 0x1C;
-#[allow(dead_code)]
 pub const CMD1: u8 = 0x45;
-#[allow(dead_code)]
 pub const CMD2: u8 = 0x46;
-#[allow(dead_code)]
 pub const CMD3: u8 = 0x47;
-#[allow(dead_code)]
 pub const CMD4: u8 = 0x44;
-#[allow(dead_code)]
 pub const CMD5: u8 = 0x40;
-#[allow(dead_code)]
 pub const CMD6: u8 = 0x43;
-#[allow(dead_code)]
 pub const CMD7: u8 = 0x7;
-#[allow(dead_code)]
 pub const CMD8: u8 = 0x15;
-#[allow(dead_code)]
 pub const CMD9: u8 = 0x9;
-#[allow(dead_code)]
 pub const CMD0: u8 = 0x19;
 pub const STAR: u8 = 0x16;
-#[allow(dead_code)]
 pub const HASHTAG: u8 = 0xD;
 
+/// Remote control protocols the receiver understands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum Protocol {
+    Nec,
+    /// Samsung's take on NEC, with the address sent twice instead of inverted
+    Samsung,
+    /// Sony
+    Sirc,
+    /// Philips
+    Rc5,
+    Rc6,
+}
+
+impl Protocol {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        [
+            Protocol::Nec,
+            Protocol::Samsung,
+            Protocol::Sirc,
+            Protocol::Rc5,
+            Protocol::Rc6,
+        ]
+        .get(byte as usize)
+        .copied()
+    }
+}
+
+/// A button press, tagged with the protocol it came in on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrCommand {
+    pub protocol: Protocol,
+    pub addr: u16,
+    pub cmd: u8,
+    /// The button is being held down
+    pub repeat: bool,
+}
+
+impl From<NecCommand> for IrCommand {
+    fn from(cmd: NecCommand) -> Self {
+        Self {
+            protocol: Protocol::Nec,
+            addr: cmd.addr.into(),
+            cmd: cmd.cmd,
+            repeat: cmd.repeat,
+        }
+    }
+}
+
+impl From<SircCommand> for IrCommand {
+    fn from(cmd: SircCommand) -> Self {
+        Self {
+            protocol: Protocol::Sirc,
+            addr: cmd.addr,
+            cmd: cmd.cmd,
+            repeat: cmd.repeat,
+        }
+    }
+}
+
+/// A command and when it came in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Received {
+    pub cmd: IrCommand,
+    /// Milliseconds on the same clock as [`Timebase::now_ms`](crate::hal::Timebase::now_ms)
+    pub at_ms: u32,
+}
+
+/// Most commands that can wait to be handled, e.g. while
+/// [`Turret::fire_all`](crate::turret::Turret::fire_all) blocks
+pub const QUEUE_CAPACITY: usize = 8;
+
+/// Tells a held RC5/RC6 button from a new press. Those send a toggle bit that
+/// flips on every press and stays put while the button is held, rather than a
+/// repeat code.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Toggles {
+    last: Option<(Protocol, u16, u8, bool)>,
+}
+
+impl Toggles {
+    /// Whether this frame repeats the one before it.
+    pub fn repeat(&mut self, protocol: Protocol, addr: u16, cmd: u8, toggle: bool) -> bool {
+        let frame = Some((protocol, addr, cmd, toggle));
+        let repeat = self.last == frame;
+        self.last = frame;
+        repeat
+    }
+}
+
+#[cfg(target_arch = "avr")]
 type IRPin = Pin<Input<Floating>, PB1>;
 
+/// Every decoder listening on the pin at once
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
+struct Multi {
+    receiver: MultiReceiver<4, (Nec, NecSamsung, Rc5, Rc6), IRPin, u32>,
+    sirc: sirc::Sirc,
+    toggles: Toggles,
+    last_edge: u32,
+}
+
+#[cfg(all(target_arch = "avr", not(feature = "multi-protocol-ir")))]
 static mut RECEIVER: Option<Receiver<Nec, IRPin, u32, NecCommand>> = None;
-static CMD: Mutex<Cell<Option<NecCommand>>> = Mutex::new(Cell::new(None));
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
+static mut RECEIVER: Option<Multi> = None;
+#[cfg(target_arch = "avr")]
+static mut QUEUE: Queue<Received, { QUEUE_CAPACITY + 1 }> = Queue::new();
+/// Only ever used by `PCINT0`, or by [`poll`] with interrupts disabled
+#[cfg(target_arch = "avr")]
+static mut PRODUCER: Option<Producer<'static, Received, { QUEUE_CAPACITY + 1 }>> = None;
+/// Only ever used by [`fetch_message`], outside of interrupts
+#[cfg(target_arch = "avr")]
+static mut CONSUMER: Option<Consumer<'static, Received, { QUEUE_CAPACITY + 1 }>> = None;
+/// Commands thrown away because the queue was full
+#[cfg(target_arch = "avr")]
+static OVERFLOWS: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+/// Level of the receiver pin last time the handler ran
+#[cfg(target_arch = "avr")]
+static IR_LEVEL: AtomicBool = AtomicBool::new(true);
 
+/// Pin changes on port B, shared by the IR receiver and any range finder
+/// echo pins on D8-D13
+#[cfg(target_arch = "avr")]
 #[avr_device::interrupt(atmega328p)]
 fn PCINT0() {
-    let recv = unsafe { RECEIVER.as_mut().unwrap() };
+    hc_sr04::echo_edge(PORT_B);
+
+    // Only let the receiver see edges on its own pin
+    let level = port_levels(PORT_B) & (1 << 1) != 0;
+    if IR_LEVEL.swap(level, Ordering::SeqCst) == level {
+        return;
+    }
 
     // NOTE: Clock frequency is 10x the speed of what Receiver expects;
     // ensure we divide by 2
-    let now = CLOCK.now() >> 1;
+    let at = CLOCK.now_instant();
+    let now = (at.ticks() >> 1) as u32;
+
+    if let Some(cmd) = decode(now, level) {
+        enqueue(Received {
+            cmd,
+            at_ms: at.duration_since_epoch().to_millis() as u32,
+        });
+    }
+}
 
-    let event_instant = recv.event_instant(now).expect("Pin::Error is `Infallible`");
-    if let Some(cmd) = event_instant {
+/// Hand a command to [`fetch_message`]. Only call this from `PCINT0`, or with
+/// interrupts disabled.
+#[cfg(target_arch = "avr")]
+fn enqueue(received: Received) {
+    let producer = unsafe { PRODUCER.as_mut() };
+    let queued = producer.is_some_and(|producer| producer.enqueue(received).is_ok());
+    if !queued {
         avr_device::interrupt::free(|cs| {
-            let cmd_cell = CMD.borrow(cs);
-            cmd_cell.set(Some(cmd));
+            let overflows = OVERFLOWS.borrow(cs);
+            overflows.set(overflows.get().saturating_add(1));
         });
     }
 }
 
-pub fn fetch_message() -> Option<NecCommand> {
-    avr_device::interrupt::free(|cs| CMD.borrow(cs).take())
+#[cfg(all(target_arch = "avr", not(feature = "multi-protocol-ir")))]
+fn decode(now: u32, _level: bool) -> Option<IrCommand> {
+    let recv = unsafe { RECEIVER.as_mut().unwrap() };
+    recv.event_instant(now)
+        .expect("Pin::Error is `Infallible`")
+        .map(IrCommand::from)
+}
+
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
+fn decode(now: u32, level: bool) -> Option<IrCommand> {
+    let multi = unsafe { RECEIVER.as_mut().unwrap() };
+    let dt = now.wrapping_sub(multi.last_edge);
+    multi.last_edge = now;
+
+    // The receiver pulls its output low while it sees the carrier
+    let mark = !level;
+    // Ticks are 50 µs at the receivers' 20 kHz
+    let sirc = multi
+        .sirc
+        .event(mark, dt.saturating_mul(50))
+        .map(IrCommand::from);
+
+    let toggles = &mut multi.toggles;
+    let other = multi
+        .receiver
+        .event_generic_iter(dt, mark)
+        .find_map(|cmd| tag(cmd, toggles));
+    sirc.or(other)
+}
+
+/// Decode a SIRC frame whose trailing space is still going. Those have no
+/// end marker, so without this a press only shows up when the next one
+/// starts. Call this from the main loop.
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
+pub fn poll() {
+    avr_device::interrupt::free(|_| {
+        let Some(multi) = (unsafe { RECEIVER.as_mut() }) else {
+            return;
+        };
+        let at = CLOCK.now_instant();
+        let idle = ((at.ticks() >> 1) as u32).wrapping_sub(multi.last_edge);
+        if let Some(cmd) = multi.sirc.poll(idle.saturating_mul(50)) {
+            enqueue(Received {
+                cmd: cmd.into(),
+                at_ms: at.duration_since_epoch().to_millis() as u32,
+            });
+        }
+    })
+}
+
+/// Nothing to do for NEC, which ends its frames with a stop bit.
+#[cfg(all(target_arch = "avr", not(feature = "multi-protocol-ir")))]
+pub fn poll() {}
+
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
+fn tag(cmd: CmdEnum, toggles: &mut Toggles) -> Option<IrCommand> {
+    let (protocol, addr, cmd, repeat) = match cmd {
+        CmdEnum::Nec(cmd) => return Some(cmd.into()),
+        CmdEnum::NecSamsung(cmd) => (Protocol::Samsung, cmd.addr.into(), cmd.cmd, cmd.repeat),
+        CmdEnum::Rc5(cmd) => {
+            let addr = cmd.addr.into();
+            let repeat = toggles.repeat(Protocol::Rc5, addr, cmd.cmd, cmd.toggle);
+            (Protocol::Rc5, addr, cmd.cmd, repeat)
+        }
+        CmdEnum::Rc6(cmd) => {
+            let addr = cmd.addr.into();
+            let repeat = toggles.repeat(Protocol::Rc6, addr, cmd.cmd, cmd.toggle);
+            (Protocol::Rc6, addr, cmd.cmd, repeat)
+        }
+        _ => return None,
+    };
+    Some(IrCommand {
+        protocol,
+        addr,
+        cmd,
+        repeat,
+    })
+}
+
+/// Oldest command not handled yet.
+#[cfg(target_arch = "avr")]
+pub fn fetch_message() -> Option<Received> {
+    unsafe { CONSUMER.as_mut() }?.dequeue()
+}
+
+/// Every command not handled yet, oldest first.
+#[cfg(target_arch = "avr")]
+pub fn drain() -> impl Iterator<Item = Received> {
+    core::iter::from_fn(fetch_message)
+}
+
+/// How many commands were lost to a full queue since this was last asked.
+#[cfg(target_arch = "avr")]
+pub fn take_overflows() -> u16 {
+    avr_device::interrupt::free(|cs| OVERFLOWS.borrow(cs).replace(0))
 }
 
+#[cfg(target_arch = "avr")]
+fn init_queue() {
+    // Safety: called once, before interrupts are enabled
+    let (producer, consumer) = unsafe { QUEUE.split() };
+    unsafe {
+        PRODUCER.replace(producer);
+        CONSUMER.replace(consumer);
+    }
+}
+
+#[cfg(all(target_arch = "avr", not(feature = "multi-protocol-ir")))]
 fn replace_receiver(receiver: Receiver<Nec, Pin<Input<Floating>, PB1>, u32, NecCommand>) {
     unsafe { RECEIVER.replace(receiver) };
 }
 
+#[cfg(all(target_arch = "avr", not(feature = "multi-protocol-ir")))]
 pub fn init_receiver(pin: Pin<Input<Floating>, PB1>) {
+    init_queue();
     let receiver = Receiver::with_pin(Clock::<20, 8>::FREQ, pin);
     replace_receiver(receiver);
 }
+
+/// Listen for NEC, Samsung, SIRC, RC5 and RC6 remotes all at once.
+#[cfg(all(target_arch = "avr", feature = "multi-protocol-ir"))]
+pub fn init_receiver(pin: Pin<Input<Floating>, PB1>) {
+    init_queue();
+    let multi = Multi {
+        receiver: MultiReceiver::new(Clock::<20, 8>::FREQ, pin),
+        sirc: sirc::Sirc::new(),
+        toggles: Toggles::default(),
+        last_edge: CLOCK.now() >> 1,
+    };
+    unsafe { RECEIVER.replace(multi) };
+}
+
+/// [`CommandSource`] fed by the IR receiver on `D9`.
+///
+/// [`init_receiver`] must have been called for this to produce anything.
+#[cfg(target_arch = "avr")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IrRemote;
+
+#[cfg(target_arch = "avr")]
+impl CommandSource for IrRemote {
+    fn next_command(&mut self) -> Option<Received> {
+        fetch_message()
+    }
+
+    fn take_overflows(&mut self) -> u16 {
+        take_overflows()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toggle_bit_tells_held_buttons_from_new_presses() {
+        let mut toggles = Toggles::default();
+
+        assert!(!toggles.repeat(Protocol::Rc5, 0, 16, false));
+        assert!(toggles.repeat(Protocol::Rc5, 0, 16, false));
+        // Pressed again
+        assert!(!toggles.repeat(Protocol::Rc5, 0, 16, true));
+        // Another button, or the same code from another protocol
+        assert!(!toggles.repeat(Protocol::Rc5, 0, 17, true));
+        assert!(!toggles.repeat(Protocol::Rc6, 0, 17, true));
+    }
+}
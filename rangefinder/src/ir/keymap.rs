@@ -0,0 +1,328 @@
+//! Which button on which remote does what.
+
+#[cfg(target_arch = "avr")]
+use arduino_hal::Eeprom;
+use heapless::LinearMap;
+
+use super::{
+    IrCommand, Protocol, CMD0, CMD1, CMD2, CMD3, CMD4, CMD5, CMD6, CMD7, CMD8, CMD9, DOWN, HASHTAG,
+    LEFT, OK, RIGHT, STAR, UP,
+};
+use crate::turret::TurretAction;
+
+/// Most buttons that can be bound at once, across all remotes
+pub const KEYMAP_CAPACITY: usize = 24;
+
+/// Address the HackPack remote sends
+pub const HACKPACK_ADDR: u16 = 0;
+
+/// Size of a key map written out with [`KeyMap::to_bytes`]
+pub const KEYMAP_BYTES: usize = HEADER + KEYMAP_CAPACITY * ENTRY + 1;
+
+/// Marks the start of a saved key map, so blank or foreign EEPROM is ignored
+const MAGIC: [u8; 2] = *b"K1";
+/// Magic and binding count
+const HEADER: usize = 3;
+/// Protocol, address (little endian), command and action
+const ENTRY: usize = 5;
+
+/// What each button on the HackPack remote does, with the number buttons going
+/// to presets
+const HACKPACK: [(u8, TurretAction); 17] = [
+    (UP, TurretAction::Up),
+    (DOWN, TurretAction::Down),
+    (LEFT, TurretAction::Left),
+    (RIGHT, TurretAction::Right),
+    (OK, TurretAction::Fire),
+    (STAR, TurretAction::FireAll),
+    (HASHTAG, TurretAction::ToggleSentry),
+    (CMD0, TurretAction::Preset(0)),
+    (CMD1, TurretAction::Preset(1)),
+    (CMD2, TurretAction::Preset(2)),
+    (CMD3, TurretAction::Preset(3)),
+    (CMD4, TurretAction::Preset(4)),
+    (CMD5, TurretAction::Preset(5)),
+    (CMD6, TurretAction::Preset(6)),
+    (CMD7, TurretAction::Preset(7)),
+    (CMD8, TurretAction::Preset(8)),
+    (CMD9, TurretAction::Preset(9)),
+];
+
+/// The HackPack remote's button for `action`, if it has one.
+pub fn hackpack_cmd(action: TurretAction) -> Option<u8> {
+    HACKPACK
+        .iter()
+        .find(|(_, bound)| *bound == action)
+        .map(|(cmd, _)| *cmd)
+}
+
+/// A button on a remote.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub struct Key {
+    pub protocol: Protocol,
+    pub addr: u16,
+    pub cmd: u8,
+}
+
+impl From<&IrCommand> for Key {
+    fn from(cmd: &IrCommand) -> Self {
+        Self {
+            protocol: cmd.protocol,
+            addr: cmd.addr,
+            cmd: cmd.cmd,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum KeyMapError {
+    /// All [`KEYMAP_CAPACITY`] bindings are taken
+    Full,
+}
+
+/// Table from remote buttons to what the turret does about them.
+#[derive(Clone, Debug)]
+pub struct KeyMap {
+    bindings: LinearMap<Key, TurretAction, KEYMAP_CAPACITY>,
+}
+
+impl Default for KeyMap {
+    /// The HackPack remote, with the number buttons going to presets
+    fn default() -> Self {
+        Self::hackpack(HACKPACK_ADDR)
+    }
+}
+
+impl KeyMap {
+    /// No button does anything
+    pub const fn new() -> Self {
+        Self {
+            bindings: LinearMap::new(),
+        }
+    }
+
+    /// The HackPack remote's buttons, sent from `addr`. Followers listening to
+    /// a leader that broadcasts to its own address use this.
+    pub fn hackpack(addr: u16) -> Self {
+        let mut keymap = Self::new();
+        for (cmd, action) in HACKPACK {
+            // Far fewer than `KEYMAP_CAPACITY`
+            let _ = keymap.bind(
+                Key {
+                    protocol: Protocol::Nec,
+                    addr,
+                    cmd,
+                },
+                action,
+            );
+        }
+        keymap
+    }
+
+    /// Make `key` do `action`, replacing whatever it did before.
+    pub fn bind(&mut self, key: Key, action: TurretAction) -> Result<(), KeyMapError> {
+        self.bindings
+            .insert(key, action)
+            .map(|_| ())
+            .map_err(|_| KeyMapError::Full)
+    }
+
+    /// Make `key` do nothing, returning what it used to do.
+    pub fn unbind(&mut self, key: &Key) -> Option<TurretAction> {
+        self.bindings.remove(key)
+    }
+
+    /// What `cmd` should make the turret do, if anything.
+    pub fn action(&self, cmd: &IrCommand) -> Option<TurretAction> {
+        self.bindings.get(&Key::from(cmd)).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &TurretAction)> {
+        self.bindings.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.bindings.clear();
+    }
+
+    /// Flatten the key map for saving, ending in an XOR checksum.
+    pub fn to_bytes(&self) -> [u8; KEYMAP_BYTES] {
+        let mut bytes = [0; KEYMAP_BYTES];
+        bytes[..2].copy_from_slice(&MAGIC);
+        bytes[2] = self.len() as u8;
+        for ((key, action), entry) in self.iter().zip(bytes[HEADER..].chunks_exact_mut(ENTRY)) {
+            let [addr_lo, addr_hi] = key.addr.to_le_bytes();
+            entry.copy_from_slice(&[
+                key.protocol as u8,
+                addr_lo,
+                addr_hi,
+                key.cmd,
+                action.to_byte(),
+            ]);
+        }
+        bytes[KEYMAP_BYTES - 1] = checksum(&bytes[..KEYMAP_BYTES - 1]);
+        bytes
+    }
+
+    /// Read back a key map written by [`KeyMap::to_bytes`], or `None` if
+    /// `bytes` don't hold one.
+    pub fn from_bytes(bytes: &[u8; KEYMAP_BYTES]) -> Option<Self> {
+        let count = bytes[2] as usize;
+        if bytes[..2] != MAGIC
+            || count > KEYMAP_CAPACITY
+            || checksum(&bytes[..KEYMAP_BYTES - 1]) != bytes[KEYMAP_BYTES - 1]
+        {
+            return None;
+        }
+
+        let mut keymap = Self::new();
+        for entry in bytes[HEADER..].chunks_exact(ENTRY).take(count) {
+            let key = Key {
+                protocol: Protocol::from_byte(entry[0])?,
+                addr: u16::from_le_bytes([entry[1], entry[2]]),
+                cmd: entry[3],
+            };
+            keymap.bind(key, TurretAction::from_byte(entry[4])?).ok()?;
+        }
+        Some(keymap)
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum ^ byte)
+}
+
+/// Keeps the key map in EEPROM, so a learned remote survives a power cycle.
+#[cfg(target_arch = "avr")]
+pub struct KeyMapStore {
+    eeprom: Eeprom,
+}
+
+#[cfg(target_arch = "avr")]
+impl KeyMapStore {
+    /// Where in the EEPROM the key map lives
+    pub const OFFSET: u16 = 0;
+
+    pub fn new(eeprom: Eeprom) -> Self {
+        Self { eeprom }
+    }
+
+    /// The saved key map, or `None` if nothing has been saved yet.
+    pub fn load(&self) -> Option<KeyMap> {
+        let mut bytes = [0; KEYMAP_BYTES];
+        self.eeprom.read(Self::OFFSET, &mut bytes).ok()?;
+        KeyMap::from_bytes(&bytes)
+    }
+
+    pub fn save(&mut self, keymap: &KeyMap) {
+        // `KEYMAP_BYTES` is far less than the EEPROM holds
+        let _ = self.eeprom.write(Self::OFFSET, &keymap.to_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(protocol: Protocol, addr: u16, cmd: u8) -> IrCommand {
+        IrCommand {
+            protocol,
+            addr,
+            cmd,
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn default_matches_the_hackpack_remote() {
+        let keymap = KeyMap::default();
+
+        let hackpack = |cmd| keymap.action(&command(Protocol::Nec, HACKPACK_ADDR, cmd));
+        assert_eq!(hackpack(UP), Some(TurretAction::Up));
+        assert_eq!(hackpack(OK), Some(TurretAction::Fire));
+        assert_eq!(hackpack(HASHTAG), Some(TurretAction::ToggleSentry));
+        assert_eq!(hackpack(CMD7), Some(TurretAction::Preset(7)));
+
+        // Same command from another remote
+        assert_eq!(keymap.action(&command(Protocol::Nec, 0x04, UP)), None);
+        assert_eq!(keymap.action(&command(Protocol::Rc5, 0, UP)), None);
+
+        assert_eq!(hackpack_cmd(TurretAction::Preset(7)), Some(CMD7));
+        assert_eq!(hackpack_cmd(TurretAction::Broadcast), None);
+    }
+
+    #[test]
+    fn bindings_are_per_protocol_and_address() {
+        let mut keymap = KeyMap::new();
+        // Channel up on a Philips TV remote
+        let key = Key {
+            protocol: Protocol::Rc5,
+            addr: 0,
+            cmd: 32,
+        };
+
+        keymap.bind(key, TurretAction::Up).unwrap();
+        keymap.bind(key, TurretAction::Down).unwrap();
+        assert_eq!(keymap.len(), 1);
+        assert_eq!(
+            keymap.action(&command(Protocol::Rc5, 0, 32)),
+            Some(TurretAction::Down)
+        );
+        assert_eq!(keymap.action(&command(Protocol::Rc6, 0, 32)), None);
+
+        assert_eq!(keymap.unbind(&key), Some(TurretAction::Down));
+        assert!(keymap.is_empty());
+    }
+
+    #[test]
+    fn refuses_bindings_once_full() {
+        let mut keymap = KeyMap::new();
+        for cmd in 0..KEYMAP_CAPACITY as u8 {
+            let key = Key {
+                protocol: Protocol::Sirc,
+                addr: 1,
+                cmd,
+            };
+            keymap.bind(key, TurretAction::Fire).unwrap();
+        }
+
+        let key = Key {
+            protocol: Protocol::Sirc,
+            addr: 1,
+            cmd: 100,
+        };
+        assert_eq!(keymap.bind(key, TurretAction::Fire), Err(KeyMapError::Full));
+    }
+
+    #[test]
+    fn survives_a_round_trip_through_bytes() {
+        let mut keymap = KeyMap::default();
+        let key = Key {
+            protocol: Protocol::Rc6,
+            addr: 0x1234,
+            cmd: 7,
+        };
+        keymap.bind(key, TurretAction::Preset(4)).unwrap();
+
+        let mut bytes = keymap.to_bytes();
+        let loaded = KeyMap::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.len(), keymap.len());
+        for (key, action) in keymap.iter() {
+            let cmd = command(key.protocol, key.addr, key.cmd);
+            assert_eq!(loaded.action(&cmd), Some(*action));
+        }
+
+        // Blank EEPROM, and a flipped bit
+        assert!(KeyMap::from_bytes(&[0xFF; KEYMAP_BYTES]).is_none());
+        bytes[HEADER + 3] ^= 0x10;
+        assert!(KeyMap::from_bytes(&bytes).is_none());
+    }
+}
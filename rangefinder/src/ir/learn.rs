@@ -0,0 +1,131 @@
+//! Binding a new remote by pressing its buttons.
+
+use super::{IrCommand, Key, KeyMap};
+use crate::turret::TurretAction;
+
+/// Actions asked for in learn mode, in order
+pub const LEARNABLE: [TurretAction; 18] = [
+    TurretAction::Up,
+    TurretAction::Down,
+    TurretAction::Left,
+    TurretAction::Right,
+    TurretAction::Fire,
+    TurretAction::FireAll,
+    TurretAction::ToggleSentry,
+    TurretAction::Broadcast,
+    TurretAction::Preset(0),
+    TurretAction::Preset(1),
+    TurretAction::Preset(2),
+    TurretAction::Preset(3),
+    TurretAction::Preset(4),
+    TurretAction::Preset(5),
+    TurretAction::Preset(6),
+    TurretAction::Preset(7),
+    TurretAction::Preset(8),
+    TurretAction::Preset(9),
+];
+
+/// What became of a button press in learn mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Learned {
+    /// The button now does this
+    Bound(TurretAction),
+    /// The button was already taken, so this action is left unbound
+    Skipped(TurretAction),
+}
+
+/// Builds a key map by asking for the button for each of [`LEARNABLE`] in
+/// turn.
+#[derive(Clone, Debug, Default)]
+pub struct Learner {
+    next: usize,
+    keymap: KeyMap,
+}
+
+impl Learner {
+    pub fn new() -> Self {
+        Self {
+            next: 0,
+            keymap: KeyMap::new(),
+        }
+    }
+
+    /// The action waiting for a button, or `None` once they all have one.
+    pub fn prompt(&self) -> Option<TurretAction> {
+        LEARNABLE.get(self.next).copied()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.prompt().is_none()
+    }
+
+    /// Bind the button `cmd` came from to the action being asked for.
+    ///
+    /// Held buttons are ignored, so one press only answers one prompt.
+    /// Pressing a button that is already bound skips the action instead, which
+    /// saves going through all the presets on remotes without number buttons.
+    pub fn feed(&mut self, cmd: &IrCommand) -> Option<Learned> {
+        let action = self.prompt()?;
+        if cmd.repeat {
+            return None;
+        }
+        self.next += 1;
+
+        if self.keymap.action(cmd).is_some() {
+            return Some(Learned::Skipped(action));
+        }
+        // There are fewer actions than bindings fit
+        let _ = self.keymap.bind(Key::from(cmd), action);
+        Some(Learned::Bound(action))
+    }
+
+    pub fn into_keymap(self) -> KeyMap {
+        self.keymap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::Protocol;
+
+    fn press(cmd: u8, repeat: bool) -> IrCommand {
+        IrCommand {
+            protocol: Protocol::Rc5,
+            addr: 0,
+            cmd,
+            repeat,
+        }
+    }
+
+    #[test]
+    fn asks_for_each_action_in_turn() {
+        let mut learner = Learner::new();
+        assert_eq!(learner.prompt(), Some(TurretAction::Up));
+
+        assert_eq!(
+            learner.feed(&press(32, false)),
+            Some(Learned::Bound(TurretAction::Up))
+        );
+        // Still holding it down
+        assert_eq!(learner.feed(&press(32, true)), None);
+        assert_eq!(learner.prompt(), Some(TurretAction::Down));
+        assert_eq!(
+            learner.feed(&press(33, false)),
+            Some(Learned::Bound(TurretAction::Down))
+        );
+
+        // Skip the rest by pressing up again
+        while !learner.is_done() {
+            assert!(matches!(
+                learner.feed(&press(32, false)),
+                Some(Learned::Skipped(_))
+            ));
+        }
+        assert_eq!(learner.feed(&press(40, false)), None);
+
+        let keymap = learner.into_keymap();
+        assert_eq!(keymap.len(), 2);
+        assert_eq!(keymap.action(&press(33, false)), Some(TurretAction::Down));
+    }
+}
@@ -0,0 +1,227 @@
+//! Sony SIRC decoder.
+//!
+//! The `infrared` crate doesn't speak SIRC, so the receiver feeds it the same
+//! edges it hands the other protocols. A frame is a 2.4 ms header mark, then
+//! 12, 15 or 20 bits, least significant first, each a 600 µs space and a mark
+//! of 1.2 ms for a one or 600 µs for a zero. Remotes send a held button every
+//! 45 ms, at least three times.
+
+/// Longest gap between frames that still counts as the button being held
+const REPEAT_GAP_US: u32 = 60_000;
+
+/// Longest space inside a frame
+const BIT_SPACE_US: u32 = 900;
+
+/// A decoded SIRC frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SircCommand {
+    /// Device address, with the extended bits of 20-bit frames above the
+    /// five address bits
+    pub addr: u16,
+    pub cmd: u8,
+    /// Whether this is the same button sent again while it is held
+    pub repeat: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mark {
+    Zero,
+    One,
+    Header,
+}
+
+impl Mark {
+    fn classify(us: u32) -> Option<Mark> {
+        match us {
+            300..=900 => Some(Mark::Zero),
+            901..=1_600 => Some(Mark::One),
+            1_900..=3_000 => Some(Mark::Header),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sirc {
+    /// Between a header and the end of its frame
+    receiving: bool,
+    /// Whether the pulse going on now is a space
+    space: bool,
+    bits: u32,
+    count: u8,
+    /// Space before the frame being received
+    gap: u32,
+    last: Option<SircCommand>,
+}
+
+impl Sirc {
+    pub const fn new() -> Self {
+        Self {
+            receiving: false,
+            space: true,
+            bits: 0,
+            count: 0,
+            gap: u32::MAX,
+            last: None,
+        }
+    }
+
+    /// Feed an edge from the receiver. `mark` is whether the pulse that just
+    /// started is a mark, i.e. the receiver's output went low, and `dt_us` how
+    /// long the previous pulse lasted.
+    ///
+    /// Frames have no end marker, so one is only decoded when the space after
+    /// it turns out to be longer than a bit's. [`Sirc::poll`] notices that
+    /// while the space is still going; this only catches what it missed.
+    pub fn event(&mut self, mark: bool, dt_us: u32) -> Option<SircCommand> {
+        self.space = !mark;
+        if mark {
+            // The space before this mark just ended
+            if dt_us <= BIT_SPACE_US {
+                return None;
+            }
+            // Too long ago to act on now
+            let decoded = self.finish().filter(|_| dt_us <= REPEAT_GAP_US);
+            self.gap = dt_us;
+            return decoded;
+        }
+
+        match (Mark::classify(dt_us), self.receiving) {
+            (Some(Mark::Header), _) => {
+                self.receiving = true;
+                self.bits = 0;
+                self.count = 0;
+            }
+            (Some(bit), true) if self.count < 20 => {
+                if bit == Mark::One {
+                    self.bits |= 1 << self.count;
+                }
+                self.count += 1;
+            }
+            _ => self.receiving = false,
+        }
+        None
+    }
+
+    /// Decode the frame just received once the receiver has been quiet for
+    /// `idle_us`, longer than any space inside a frame. Call this between
+    /// edges, so a lone press doesn't wait for the next one.
+    pub fn poll(&mut self, idle_us: u32) -> Option<SircCommand> {
+        if !self.space || idle_us <= BIT_SPACE_US {
+            return None;
+        }
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Option<SircCommand> {
+        if !self.receiving {
+            return None;
+        }
+        self.receiving = false;
+
+        let cmd = (self.bits & 0x7F) as u8;
+        let addr = match self.count {
+            12 => (self.bits >> 7) & 0x1F,
+            15 => (self.bits >> 7) & 0xFF,
+            20 => (self.bits >> 7) & 0x1FFF,
+            _ => return None,
+        } as u16;
+
+        let repeat = matches!(
+            self.last,
+            Some(last) if last.addr == addr && last.cmd == cmd && self.gap <= REPEAT_GAP_US
+        );
+        let command = SircCommand { addr, cmd, repeat };
+        self.last = Some(command);
+        Some(command)
+    }
+}
+
+impl Default for Sirc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Edges for one frame of `count` bits, preceded by a space of `gap` µs
+    fn frame(sirc: &mut Sirc, gap: u32, bits: u32, count: u8) -> Option<SircCommand> {
+        let decoded = sirc.event(true, gap);
+        sirc.event(false, 2_400);
+        for i in 0..count {
+            assert_eq!(sirc.event(true, 600), None);
+            let mark = if bits & (1 << i) != 0 { 1_200 } else { 600 };
+            assert_eq!(sirc.event(false, mark), None);
+        }
+        decoded
+    }
+
+    #[test]
+    fn decodes_twelve_bit_frames_when_the_next_one_starts() {
+        let mut sirc = Sirc::new();
+        // Volume up on a TV: command 18, address 1
+        let bits = 18 | (1 << 7);
+
+        assert_eq!(frame(&mut sirc, 100_000, bits, 12), None);
+        assert_eq!(
+            frame(&mut sirc, 25_000, bits, 12),
+            Some(SircCommand {
+                addr: 1,
+                cmd: 18,
+                repeat: false
+            })
+        );
+        // The third copy of the frame shows it being held
+        assert_eq!(
+            frame(&mut sirc, 25_000, bits, 12),
+            Some(SircCommand {
+                addr: 1,
+                cmd: 18,
+                repeat: true
+            })
+        );
+    }
+
+    #[test]
+    fn reads_extended_addresses_and_drops_short_frames() {
+        let mut sirc = Sirc::new();
+        let bits = 0x2A | (0x1ABC << 7);
+
+        frame(&mut sirc, 100_000, bits, 20);
+        let decoded = sirc.poll(1_000).unwrap();
+        assert_eq!(decoded.cmd, 0x2A);
+        assert_eq!(decoded.addr, 0x1ABC);
+
+        // Seven bits isn't a frame
+        frame(&mut sirc, 100_000, 0, 7);
+        assert_eq!(sirc.poll(1_000), None);
+    }
+
+    #[test]
+    fn decodes_a_lone_frame_once_the_receiver_goes_quiet() {
+        let mut sirc = Sirc::new();
+        let bits = 18 | (1 << 7);
+
+        assert_eq!(frame(&mut sirc, 100_000, bits, 12), None);
+        // Could still be the space before another bit
+        assert_eq!(sirc.poll(600), None);
+        assert_eq!(
+            sirc.poll(1_000),
+            Some(SircCommand {
+                addr: 1,
+                cmd: 18,
+                repeat: false
+            })
+        );
+        // Only the once, not again when the next press starts
+        assert_eq!(sirc.poll(80_000), None);
+        assert_eq!(frame(&mut sirc, 100_000, bits, 12), None);
+
+        // Nothing polled during the space, and the press is stale by the time
+        // the next one starts
+        assert_eq!(frame(&mut sirc, 100_000, bits, 12), None);
+    }
+}
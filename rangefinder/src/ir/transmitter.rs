@@ -0,0 +1,205 @@
+//! Sending commands to other turrets over IR.
+//!
+//! An IR LED on one of Timer2's output pins, `D11` (OC2A) or `D3` (OC2B), is
+//! switched on and off at the 38 kHz the receivers listen for. Frames are NEC,
+//! encoded by the `infrared` crate's sender and stepped through on every tick
+//! of [`CLOCK`](crate::clock::CLOCK).
+
+#[cfg(target_arch = "avr")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_arch = "avr")]
+use arduino_hal::{
+    hal::port::{PB3, PD3},
+    pac::TC2,
+    port::{mode::Output, Pin},
+};
+use infrared::protocol::nec::NecCommand;
+#[cfg(target_arch = "avr")]
+use infrared::{
+    protocol::Nec,
+    sender::{Sender, Status},
+};
+
+use super::{IrCommand, Protocol};
+#[cfg(target_arch = "avr")]
+use crate::{clock::Clock, hal::Transmitter};
+
+/// Carrier the receivers are tuned to
+pub const CARRIER_HZ: u32 = 38_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum TransmitError {
+    /// The turret has no IR LED
+    NoTransmitter,
+    /// Only NEC commands with 8-bit addresses can be sent
+    Unsupported,
+}
+
+/// The NEC frame that sends `cmd`.
+pub fn nec_command(cmd: &IrCommand) -> Result<NecCommand, TransmitError> {
+    if cmd.protocol != Protocol::Nec {
+        return Err(TransmitError::Unsupported);
+    }
+    Ok(NecCommand {
+        addr: u8::try_from(cmd.addr).map_err(|_| TransmitError::Unsupported)?,
+        cmd: cmd.cmd,
+        repeat: cmd.repeat,
+    })
+}
+
+/// Timer2 runs straight off the 16 MHz clock and toggles the pin twice a
+/// carrier period
+#[cfg(target_arch = "avr")]
+const CARRIER_TOP: u8 = (16_000_000 / (2 * CARRIER_HZ) - 1) as u8;
+
+/// Room for the header, 32 bits and the stop bit of a NEC frame
+#[cfg(target_arch = "avr")]
+const PULSES: usize = 96;
+
+#[cfg(target_arch = "avr")]
+type NecSender = Sender<Nec, { Clock::<40, 8>::FREQ }, PULSES>;
+
+/// Only touched from [`tick`], or while it is not sending
+#[cfg(target_arch = "avr")]
+static mut SENDER: Option<NecSender> = None;
+/// Timer2, and whether the LED is on OC2A
+#[cfg(target_arch = "avr")]
+static mut CARRIER: Option<(TC2, bool)> = None;
+/// A frame is going out
+#[cfg(target_arch = "avr")]
+static SENDING: AtomicBool = AtomicBool::new(false);
+
+/// Timer2 output pins that can carry the LED.
+#[cfg(target_arch = "avr")]
+pub trait CarrierPin {
+    /// Whether this is OC2A rather than OC2B
+    const CHANNEL_A: bool;
+}
+
+/// D11
+#[cfg(target_arch = "avr")]
+impl CarrierPin for PB3 {
+    const CHANNEL_A: bool = true;
+}
+
+/// D3
+#[cfg(target_arch = "avr")]
+impl CarrierPin for PD3 {
+    const CHANNEL_A: bool = false;
+}
+
+/// [`Transmitter`] for an IR LED on `PIN`. Takes over Timer2.
+#[cfg(target_arch = "avr")]
+pub struct IrTransmitter<PIN> {
+    /// Held low between marks, and while the carrier isn't connected
+    _pin: Pin<Output, PIN>,
+}
+
+#[cfg(target_arch = "avr")]
+impl<PIN> IrTransmitter<PIN>
+where
+    PIN: CarrierPin,
+{
+    pub fn new(tc2: TC2, mut pin: Pin<Output, PIN>) -> Self {
+        pin.set_low();
+
+        // CTC with TOP in OCR2A, which gives both channels the same frequency
+        tc2.tccr2a.write(|w| w.wgm2().ctc());
+        tc2.ocr2a.write(|w| w.bits(CARRIER_TOP));
+        tc2.ocr2b.write(|w| w.bits(0));
+        tc2.tccr2b.write(|w| w.cs2().direct());
+
+        avr_device::interrupt::free(|_| unsafe {
+            SENDER.replace(NecSender::new());
+            CARRIER.replace((tc2, PIN::CHANNEL_A));
+        });
+        Self { _pin: pin }
+    }
+}
+
+#[cfg(target_arch = "avr")]
+impl<PIN> Transmitter for IrTransmitter<PIN>
+where
+    PIN: CarrierPin,
+{
+    fn send(&mut self, cmd: &IrCommand) -> nb::Result<(), TransmitError> {
+        let frame = nec_command(cmd)?;
+        if SENDING.load(Ordering::SeqCst) {
+            return Err(nb::Error::WouldBlock);
+        }
+        // Safety: `tick` leaves the sender alone until `SENDING` is set
+        if let Some(sender) = unsafe { SENDER.as_mut() } {
+            sender.load(&frame);
+            SENDING.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+/// Step the frame going out, if there is one. Called from the clock's
+/// interrupt.
+#[cfg(target_arch = "avr")]
+pub(crate) fn tick() {
+    if !SENDING.load(Ordering::SeqCst) {
+        return;
+    }
+    let Some(sender) = (unsafe { SENDER.as_mut() }) else {
+        return;
+    };
+    match sender.tick() {
+        Status::Transmit(on) => carrier(on),
+        Status::Idle | Status::Error => {
+            carrier(false);
+            SENDING.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Connect the timer to the LED pin, or leave the pin at its low level.
+#[cfg(target_arch = "avr")]
+fn carrier(on: bool) {
+    let Some((tc2, channel_a)) = (unsafe { CARRIER.as_ref() }) else {
+        return;
+    };
+    match (channel_a, on) {
+        (true, true) => tc2.tccr2a.modify(|_, w| w.com2a().match_toggle()),
+        (true, false) => tc2.tccr2a.modify(|_, w| w.com2a().disconnected()),
+        (false, true) => tc2.tccr2a.modify(|_, w| w.com2b().match_toggle()),
+        (false, false) => tc2.tccr2a.modify(|_, w| w.com2b().disconnected()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_sends_nec_with_short_addresses() {
+        let cmd = IrCommand {
+            protocol: Protocol::Nec,
+            addr: 0x12,
+            cmd: 0x1C,
+            repeat: false,
+        };
+        assert_eq!(
+            nec_command(&cmd),
+            Ok(NecCommand {
+                addr: 0x12,
+                cmd: 0x1C,
+                repeat: false
+            })
+        );
+
+        let long = IrCommand {
+            addr: 0x1234,
+            ..cmd
+        };
+        assert_eq!(nec_command(&long), Err(TransmitError::Unsupported));
+        let rc5 = IrCommand {
+            protocol: Protocol::Rc5,
+            ..cmd
+        };
+        assert_eq!(nec_command(&rc5), Err(TransmitError::Unsupported));
+    }
+}
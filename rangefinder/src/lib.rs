@@ -1,8 +1,9 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(incomplete_features)]
-#![feature(abi_avr_interrupt)]
+#![cfg_attr(target_arch = "avr", feature(abi_avr_interrupt))]
 #![feature(generic_const_exprs)]
 
+#[cfg(target_arch = "avr")]
 use arduino_hal::{
     hal::port::{PD0, PD1},
     pac::USART0,
@@ -14,11 +15,24 @@ use arduino_hal::{
 };
 
 pub mod clock;
+pub mod hal;
 pub mod hc_sr04;
+#[cfg(target_arch = "avr")]
 pub mod interrupt;
 pub mod ir;
-#[cfg(feature = "servo")]
+pub mod link;
+pub mod log;
+pub mod motion;
+pub mod range_map;
+pub mod rx;
+#[cfg(all(target_arch = "avr", feature = "servo"))]
 pub mod servo;
+pub mod shell;
+pub mod sync;
+pub mod task;
+pub mod temperature;
+pub mod timer;
 pub mod turret;
 
+#[cfg(target_arch = "avr")]
 pub type Serial = Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>;
@@ -0,0 +1,193 @@
+//! The serial port, shared by the text [`shell`](crate::shell) and the binary
+//! [`turret_protocol`].
+//!
+//! Typed text never has a zero byte in it, while frames start with one, so
+//! everything from a zero up to the zero that ends the frame goes to the frame
+//! decoder and the rest to the shell. A zero that turns out not to start a
+//! frame, because more bytes follow it than any frame has or because they
+//! stop coming for [`FRAME_GAP_MS`], is given up on so the shell gets its
+//! bytes back.
+
+use heapless::Vec;
+use turret_protocol::{DecodeError, Decoder};
+pub use turret_protocol::{Frame, Message, MAX_FRAME};
+
+use crate::{
+    ir::Received,
+    shell::{ParseError, Shell, ShellCommand},
+};
+
+/// How often the turret sends its pose to a host unasked
+pub const TELEMETRY_MS: u32 = 500;
+
+/// How long a frame can go without a byte before it's given up on. Hosts send
+/// a frame all at once, while a person typing after a stray zero is slower.
+pub const FRAME_GAP_MS: u32 = 100;
+
+/// Something that came in over the serial port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Line(Result<ShellCommand, ParseError>),
+    Frame(Result<Frame, DecodeError>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Link {
+    shell: Shell,
+    decoder: Decoder,
+    /// Between the zero that starts a frame and the one that ends it
+    in_frame: bool,
+    /// Bytes of the frame so far, not counting zeros
+    frame_len: usize,
+    /// When the last byte came in
+    last_byte_ms: u32,
+    /// A good frame has come in, so there is a host on the other end
+    binary: bool,
+    /// Sequence number for the next frame the turret sends unasked
+    telemetry_seq: u8,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Self {
+            shell: Shell::new(),
+            decoder: Decoder::new(),
+            in_frame: false,
+            frame_len: 0,
+            last_byte_ms: 0,
+            binary: false,
+            telemetry_seq: 0,
+        }
+    }
+
+    /// Take the next byte read off the serial port, which came in at `now_ms`.
+    pub fn feed(&mut self, byte: u8, now_ms: u32) -> Option<Input> {
+        let last_ms = core::mem::replace(&mut self.last_byte_ms, now_ms);
+        if self.in_frame && now_ms.wrapping_sub(last_ms) >= FRAME_GAP_MS {
+            self.leave_frame();
+        }
+
+        if byte != 0 && !self.in_frame {
+            return self.shell.feed(byte).map(Input::Line);
+        }
+        if byte == 0 && !self.in_frame {
+            self.in_frame = true;
+            return None;
+        }
+
+        if byte != 0 {
+            self.frame_len += 1;
+            if self.frame_len > MAX_FRAME {
+                self.leave_frame();
+                return Some(Input::Frame(Err(DecodeError::TooLong)));
+            }
+        }
+
+        // A zero right after the start, like the trailing zero of one frame
+        // followed by the leading one of the next, doesn't end anything
+        let frame = self.decoder.feed(byte)?;
+        self.leave_frame();
+        self.binary |= frame.is_ok();
+        Some(Input::Frame(frame))
+    }
+
+    /// Drop whatever there is of a frame and hand bytes to the shell again.
+    fn leave_frame(&mut self) {
+        self.in_frame = false;
+        self.frame_len = 0;
+        self.decoder = Decoder::new();
+    }
+
+    /// Whether a host has talked the binary protocol, i.e. wants telemetry.
+    pub fn is_binary(&self) -> bool {
+        self.binary
+    }
+
+    /// Frame a message the turret sends unasked, with a sequence number of
+    /// its own.
+    pub fn telemetry(&mut self, message: Message) -> Frame {
+        let seq = self.telemetry_seq;
+        self.telemetry_seq = seq.wrapping_add(1);
+        Frame::new(seq, message)
+    }
+}
+
+/// `frame` as it goes out over the wire.
+pub fn wire(frame: &Frame) -> Vec<u8, MAX_FRAME> {
+    let mut out = [0; MAX_FRAME];
+    let len = frame.encode(&mut out);
+    // `len` is never more than `MAX_FRAME`
+    Vec::from_slice(&out[..len]).unwrap_or_default()
+}
+
+/// Telemetry for a remote button press.
+pub fn ir_event(received: &Received) -> Message {
+    let cmd = received.cmd;
+    Message::IrEvent {
+        protocol: cmd.protocol as u8,
+        addr: cmd.addr,
+        cmd: cmd.cmd,
+        repeat: cmd.repeat,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::turret::TurretAction;
+
+    #[test]
+    fn tells_frames_from_typed_text() {
+        let mut link = Link::new();
+        let mut inputs = std::vec::Vec::new();
+        let mut feed = |link: &mut Link, bytes: &[u8]| {
+            inputs.extend(bytes.iter().filter_map(|&byte| link.feed(byte, 0)));
+        };
+
+        feed(&mut link, b"fire\n");
+        assert!(!link.is_binary());
+        let status = Frame::new(3, Message::GetStatus);
+        // Back to back, and with a newline in the middle of one
+        feed(&mut link, &wire(&status));
+        let turn = Frame::new(10, Message::TurnTo { degrees: 10 });
+        assert!(wire(&turn).contains(&b'\n'));
+        feed(&mut link, &wire(&turn));
+        feed(&mut link, b"up 2\r\n");
+
+        assert_eq!(
+            inputs,
+            [
+                Input::Line(Ok(ShellCommand::Action(TurretAction::Fire, 1))),
+                Input::Frame(Ok(status)),
+                Input::Frame(Ok(turn)),
+                Input::Line(Ok(ShellCommand::Action(TurretAction::Up, 2))),
+            ]
+        );
+        assert!(link.is_binary());
+    }
+
+    #[test]
+    fn stray_zeros_dont_swallow_typed_text() {
+        let feed = |link: &mut Link, bytes: &[u8], now_ms| {
+            let inputs = bytes.iter().filter_map(|&byte| link.feed(byte, now_ms));
+            inputs.collect::<std::vec::Vec<_>>()
+        };
+        let help = [Input::Line(Ok(ShellCommand::Help))];
+
+        // Noise on the line as the port opens, then someone types
+        let mut link = Link::new();
+        assert_eq!(feed(&mut link, &[0], 0), []);
+        assert_eq!(feed(&mut link, b"help\n", FRAME_GAP_MS), help);
+
+        // Too much to be a frame, even with no pause
+        let mut link = Link::new();
+        let mut noise = std::vec::Vec::from([0]);
+        noise.extend([b'x'; MAX_FRAME + 1]);
+        assert_eq!(
+            feed(&mut link, &noise, 0),
+            [Input::Frame(Err(DecodeError::TooLong))]
+        );
+        assert_eq!(feed(&mut link, b"help\n", 0), help);
+        assert!(!link.is_binary());
+    }
+}
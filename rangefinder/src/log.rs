@@ -0,0 +1,405 @@
+//! Log records from anywhere in the firmware, without passing the serial port
+//! around.
+//!
+//! [`error!`], [`warn!`], [`info!`], [`debug!`] and [`trace!`] take what
+//! `ufmt::uwriteln!` takes, minus the writer. Records go into a small buffer
+//! that the main loop empties onto the serial port with [`drain`], so logging
+//! from an interrupt handler is fine. A record that doesn't fit is dropped
+//! whole and counted.
+//!
+//! What gets logged is decided twice:
+//! - When building, by the `log-error` to `log-trace` features. Finer records
+//!   are compiled out along with their strings, and with none of the features
+//!   on there is no logging at all.
+//! - At run time, for each [`Target`], with [`set_level`].
+//!
+//! A record is one line, like `INFO turret: Lost 2 commands`. With the
+//! `log-header-byte` feature the level and target names are swapped for a
+//! single [`header`] byte of `0x80` and up, which typed text never has. Only
+//! the names shrink: the message after the header is still formatted as text.
+//!
+//! [`error!`]: crate::error
+//! [`warn!`]: crate::warn
+//! [`info!`]: crate::info
+//! [`debug!`]: crate::debug
+//! [`trace!`]: crate::trace
+
+use heapless::{Deque, Vec};
+use ufmt::uWrite;
+
+use crate::sync::Lock;
+
+/// Bytes waiting for [`drain`]
+pub const BUFFER: usize = 128;
+/// Longest record, anything past it is cut off
+pub const RECORD: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ufmt::derive::uDebug)]
+pub enum Level {
+    /// Nothing is logged
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// The finest level built in, picked with the `log-*` features
+pub const STATIC_MAX_LEVEL: Level = if cfg!(feature = "log-trace") {
+    Level::Trace
+} else if cfg!(feature = "log-debug") {
+    Level::Debug
+} else if cfg!(feature = "log-info") {
+    Level::Info
+} else if cfg!(feature = "log-warn") {
+    Level::Warn
+} else if cfg!(feature = "log-error") {
+    Level::Error
+} else {
+    Level::Off
+};
+
+/// The part of the firmware a record came from, worked out from its module
+/// path when building.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum Target {
+    /// The crate root and the firmware's main loop
+    Main,
+    Clock,
+    HcSr04,
+    Ir,
+    /// The serial shell and the binary protocol
+    Link,
+    Servo,
+    Turret,
+    /// Anything else
+    Other,
+}
+
+impl Target {
+    pub const ALL: [Target; 8] = [
+        Target::Main,
+        Target::Clock,
+        Target::HcSr04,
+        Target::Ir,
+        Target::Link,
+        Target::Servo,
+        Target::Turret,
+        Target::Other,
+    ];
+
+    /// The target for code in `module`, as given by `module_path!()`
+    pub const fn of(module: &str) -> Self {
+        let module = module.as_bytes();
+        let Some(rest) = strip_segment(module, b"rangefinder") else {
+            return Target::Other;
+        };
+        if rest.is_empty() {
+            Target::Main
+        } else if strip_segment(rest, b"clock").is_some() {
+            Target::Clock
+        } else if strip_segment(rest, b"hc_sr04").is_some() {
+            Target::HcSr04
+        } else if strip_segment(rest, b"ir").is_some() {
+            Target::Ir
+        } else if strip_segment(rest, b"link").is_some() || strip_segment(rest, b"shell").is_some()
+        {
+            Target::Link
+        } else if strip_segment(rest, b"servo").is_some() {
+            Target::Servo
+        } else if strip_segment(rest, b"turret").is_some() {
+            Target::Turret
+        } else {
+            Target::Other
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Main => "main",
+            Target::Clock => "clock",
+            Target::HcSr04 => "hc_sr04",
+            Target::Ir => "ir",
+            Target::Link => "link",
+            Target::Servo => "servo",
+            Target::Turret => "turret",
+            Target::Other => "other",
+        }
+    }
+}
+
+/// What's left of `path` after its first segment, if that is `segment`
+const fn strip_segment<'a>(path: &'a [u8], segment: &[u8]) -> Option<&'a [u8]> {
+    if path.len() < segment.len() {
+        return None;
+    }
+    let mut i = 0;
+    while i < segment.len() {
+        if path[i] != segment[i] {
+            return None;
+        }
+        i += 1;
+    }
+    let (_, rest) = path.split_at(segment.len());
+    match rest {
+        [] => Some(rest),
+        [b':', b':', ..] => Some(rest.split_at(2).1),
+        _ => None,
+    }
+}
+
+/// First byte of a compact record: the top bit set, the level in the next
+/// three and the target in the bottom three.
+pub const fn header(level: Level, target: Target) -> u8 {
+    0x80 | ((level as u8) << 4) | target as u8
+}
+
+/// A record being written.
+pub struct Record {
+    bytes: Vec<u8, RECORD>,
+}
+
+impl Record {
+    fn new(level: Level, target: Target) -> Self {
+        let mut record = Self { bytes: Vec::new() };
+        if cfg!(feature = "log-header-byte") {
+            let _ = record.bytes.push(header(level, target));
+        } else {
+            let _ = ufmt::uwrite!(&mut record, "{} {}: ", level.name(), target.name());
+        }
+        record
+    }
+
+    /// End the line, cutting the record short if need be.
+    fn finish(mut self) -> Vec<u8, RECORD> {
+        if self.bytes.is_full() {
+            self.bytes.pop();
+        }
+        let _ = self.bytes.push(b'\n');
+        self.bytes
+    }
+}
+
+impl uWrite for Record {
+    type Error = core::convert::Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        let room = self.bytes.capacity() - self.bytes.len();
+        let _ = self
+            .bytes
+            .extend_from_slice(&s.as_bytes()[..s.len().min(room)]);
+        Ok(())
+    }
+}
+
+struct State<const N: usize> {
+    levels: [Level; Target::ALL.len()],
+    bytes: Deque<u8, N>,
+    dropped: u16,
+}
+
+/// Run-time levels and the buffer records wait in. The firmware has the one
+/// [`LOGGER`].
+pub struct Logger<const N: usize> {
+    state: Lock<State<N>>,
+}
+
+impl<const N: usize> Default for Logger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Logger<N> {
+    /// Every target logs whatever is built in.
+    pub const fn new() -> Self {
+        Self {
+            state: Lock::new(State {
+                levels: [STATIC_MAX_LEVEL; Target::ALL.len()],
+                bytes: Deque::new(),
+                dropped: 0,
+            }),
+        }
+    }
+
+    pub fn level(&self, target: Target) -> Level {
+        self.state.lock(|state| state.levels[target as usize])
+    }
+
+    /// Log records from `target` at `level` and worse, as far as
+    /// [`STATIC_MAX_LEVEL`] allows.
+    pub fn set_level(&self, target: Target, level: Level) {
+        self.state
+            .lock(|state| state.levels[target as usize] = level.min(STATIC_MAX_LEVEL));
+    }
+
+    pub fn enabled(&self, level: Level, target: Target) -> bool {
+        level != Level::Off && level <= self.level(target)
+    }
+
+    /// Write a record with `message`, if `target` logs `level`. It's
+    /// formatted before the buffer is locked, so interrupts aren't held up.
+    pub fn log<F>(&self, level: Level, target: Target, message: F)
+    where
+        F: FnOnce(&mut Record) -> Result<(), core::convert::Infallible>,
+    {
+        if !self.enabled(level, target) {
+            return;
+        }
+        let mut record = Record::new(level, target);
+        let _ = message(&mut record);
+        let record = record.finish();
+
+        self.state.lock(|state| {
+            if state.bytes.capacity() - state.bytes.len() < record.len() {
+                state.dropped = state.dropped.saturating_add(1);
+                return;
+            }
+            for &byte in &record {
+                let _ = state.bytes.push_back(byte);
+            }
+        });
+    }
+
+    /// The next byte to go out, if there is one.
+    pub fn pop(&self) -> Option<u8> {
+        self.state.lock(|state| state.bytes.pop_front())
+    }
+
+    /// How many records didn't fit since the last call.
+    pub fn take_dropped(&self) -> u16 {
+        self.state.lock(|state| core::mem::take(&mut state.dropped))
+    }
+}
+
+pub static LOGGER: Logger<BUFFER> = Logger::new();
+
+/// See [`Logger::set_level`].
+pub fn set_level(target: Target, level: Level) {
+    LOGGER.set_level(target, level);
+}
+
+/// Every byte logged so far, to write out to the serial port.
+pub fn drain() -> impl Iterator<Item = u8> {
+    core::iter::from_fn(|| LOGGER.pop())
+}
+
+/// See [`Logger::take_dropped`].
+pub fn take_dropped() -> u16 {
+    LOGGER.take_dropped()
+}
+
+/// Log a record at a [`Level`], e.g. `log!(Level::Info, "Ready")`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $level <= $crate::log::STATIC_MAX_LEVEL {
+            const TARGET: $crate::log::Target = $crate::log::Target::of(module_path!());
+            $crate::log::LOGGER.log($level, TARGET, |record| ufmt::uwrite!(record, $($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain<const N: usize>(logger: &Logger<N>) -> std::string::String {
+        let bytes: std::vec::Vec<u8> = core::iter::from_fn(|| logger.pop()).collect();
+        std::string::String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[test]
+    fn targets_come_from_module_paths() {
+        assert_eq!(Target::of("rangefinder"), Target::Main);
+        assert_eq!(Target::of("rangefinder::turret::hold"), Target::Turret);
+        assert_eq!(Target::of("rangefinder::ir"), Target::Ir);
+        assert_eq!(Target::of("rangefinder::shell"), Target::Link);
+        assert_eq!(Target::of("rangefinder::irrigation"), Target::Other);
+        assert_eq!(Target::of("turret_sim"), Target::Other);
+        const HERE: Target = Target::of(module_path!());
+        assert_eq!(HERE, Target::Other);
+        assert_eq!(header(Level::Info, Target::Turret), 0xB6);
+    }
+
+    #[test]
+    fn keeps_what_each_target_asks_for() {
+        let logger = Logger::<BUFFER>::new();
+        // Compile everything in, to test the run-time side
+        logger.state.lock(|state| state.levels = [Level::Trace; 8]);
+        logger
+            .state
+            .lock(|state| state.levels[Target::Servo as usize] = Level::Warn);
+
+        logger.log(Level::Debug, Target::Servo, |r| {
+            ufmt::uwrite!(r, "Writing {} us", 1500)
+        });
+        logger.log(Level::Warn, Target::Servo, |r| ufmt::uwrite!(r, "Stuck"));
+        logger.log(Level::Trace, Target::Clock, |r| ufmt::uwrite!(r, "{}", 40));
+        logger.log(Level::Off, Target::Clock, |r| ufmt::uwrite!(r, "Never"));
+
+        let expected = if cfg!(feature = "log-header-byte") {
+            "\u{fffd}Stuck\n\u{fffd}40\n"
+        } else {
+            "WARN servo: Stuck\nTRACE clock: 40\n"
+        };
+        assert_eq!(drain(&logger), expected);
+    }
+
+    #[test]
+    fn drops_records_that_dont_fit() {
+        let logger = Logger::<40>::new();
+        logger.state.lock(|state| state.levels = [Level::Trace; 8]);
+        let long = "x".repeat(100);
+        logger.log(Level::Error, Target::Main, |r| {
+            ufmt::uwrite!(r, "{}", long.as_str())
+        });
+        logger.log(Level::Error, Target::Main, |r| {
+            ufmt::uwrite!(r, "{}", long.as_str())
+        });
+        assert_eq!(logger.take_dropped(), 2);
+
+        logger.log(Level::Error, Target::Main, |r| ufmt::uwrite!(r, "short"));
+        assert!(drain(&logger).ends_with("short\n"));
+        assert_eq!(logger.take_dropped(), 0);
+    }
+}
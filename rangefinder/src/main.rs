@@ -8,15 +8,36 @@
 #![feature(generic_const_exprs)]
 
 use arduino_hal::{prelude::*, Pins, Usart};
+use fugit::ExtU64;
 use panic_halt as _;
 
-use rangefinder::{clock::CLOCK, interrupt::AttachPCInterrupt, ir::init_receiver, turret::Turret};
+#[cfg(not(feature = "ir-transmitter"))]
+use rangefinder::hal::NoTransmitter;
+#[cfg(feature = "ir-transmitter")]
+use rangefinder::ir::IrTransmitter;
+use rangefinder::{
+    clock::{SystemTimebase, CLOCK},
+    hal::Timebase,
+    interrupt::AttachPCInterrupt,
+    ir::{self, init_receiver, KeyMapStore},
+    link::{ir_event, wire, Frame, Link, TELEMETRY_MS},
+    log, rx,
+    task::{Executor, Wake},
+    timer,
+    turret::AvrTurret,
+    Serial,
+};
+#[cfg(feature = "internal-temperature")]
+use rangefinder::{
+    hal::Thermometer,
+    temperature::{InternalCalibration, InternalThermometer},
+};
 
 #[arduino_hal::entry]
 fn main() -> ! {
     let dp = arduino_hal::Peripherals::take().unwrap();
     let pins: Pins = arduino_hal::pins!(dp);
-    let mut serial: Usart<_, _, _> = arduino_hal::default_serial!(dp, pins, 57600);
+    let serial: Usart<_, _, _> = arduino_hal::default_serial!(dp, pins, 57600);
 
     // Disable interrupts while we initialize them
     avr_device::interrupt::disable();
@@ -28,64 +49,168 @@ fn main() -> ! {
 
     init_receiver(pins.d9);
 
+    // Bytes off the serial port wait in a buffer until the shell gets to them
+    rx::listen();
+
+    // Idle between rounds until the next interrupt, which at the clock's
+    // 40 kHz is never far off
+    dp.CPU.smcr.write(|w| w.sm().idle().se().set_bit());
+
+    // Other turrets hear what this one does through an IR LED on D3, which
+    // leaves the range finder's echo on D2
+    #[cfg(feature = "ir-transmitter")]
+    let (echo, transmitter) = (pins.d2, IrTransmitter::new(dp.TC2, pins.d3.into_output()));
+    #[cfg(not(feature = "ir-transmitter"))]
+    let (echo, transmitter) = (pins.d3, NoTransmitter);
+
     #[cfg(feature = "servo")]
     rangefinder::servo::donate_tc1(dp.TC1);
     #[cfg(feature = "servo")]
-    let mut turret = Turret::builder()
-        .range_finder(pins.d8.into_output(), pins.d3)
+    let mut turret = AvrTurret::builder()
+        .range_finder(pins.d8.into_output(), echo)
+        .expect("Failed to initialize range finder")
         .yaw(pins.d10.into_output())
         .expect("Failed to initialize yaw servo")
         .pitch(pins.d11.into_output())
         .expect("Failed to initialize pitch servo")
         .roll(pins.d12.into_output())
         .expect("Failed to initialize roll servo")
-        .build();
+        .build(transmitter);
 
     #[cfg(not(feature = "servo"))]
-    let mut turret = Turret::new(pins.d8.into_output(), pins.d3);
+    let mut turret = AvrTurret::new(pins.d8.into_output(), echo, transmitter)
+        .expect("Failed to initialize range finder");
     #[cfg(not(feature = "servo"))]
     turret.attach();
 
+    // A remote bound in learn mode, if there is one
+    let keymap_store = KeyMapStore::new(arduino_hal::Eeprom::new(dp.EEPROM));
+    if let Some(keymap) = keymap_store.load() {
+        *turret.keymap_mut() = keymap;
+    }
+
+    #[cfg(feature = "internal-temperature")]
+    let thermometer = InternalThermometer::new(dp.ADC, InternalCalibration::default());
+
     // Enable interrupts now that receiver is initialized
     unsafe { avr_device::interrupt::enable() };
 
-    ufmt::uwriteln!(&mut serial, "Ready to receive IR signals").unwrap_infallible();
-
-    let mut counter = 0;
-
-    loop {
-        turret.handle_command(&mut serial);
-
-        // TODO: Move this into turret code, and search for target after a specific button is
-        // pressed
-        //
-        if counter % 100 == 0 {
-            ufmt::uwriteln!(&mut serial, "Clock: {}", CLOCK.now()).unwrap_infallible();
-            // ufmt::uwriteln!(&mut serial, "Measuring time").unwrap_infallible();
-            // let distance = turret.range_finder_mut().measure_distance(&dp.EXINT);
-            // if let Ok(distance) = distance {
-            //     if distance > Length::new::<meter>(1.0) {
-            //         ufmt::uwriteln!(
-            //             &mut serial,
-            //             "Distance: {} m",
-            //             uFmt_f32::Two(distance.get::<meter>())
-            //         )
-            //         .unwrap_infallible();
-            //     } else {
-            //         ufmt::uwriteln!(
-            //             &mut serial,
-            //             "Distance: {} cm",
-            //             uFmt_f32::Two(distance.get::<centimeter>())
-            //         )
-            //         .unwrap_infallible();
-            //     }
-            // } else {
-            //     ufmt::uwriteln!(&mut serial, "Error: {:?}", distance.unwrap_err())
-            //         .unwrap_infallible();
-            // }
+    rangefinder::info!("Ready to receive IR signals");
+
+    let mut executor = Executor::<Firmware>::new();
+    executor.spawn("ir", 3, Wake::Always, ir_commands).unwrap();
+    executor
+        .spawn("serial", 2, Wake::Always, serial_input)
+        .unwrap();
+    executor.spawn("motion", 1, Wake::Always, motion).unwrap();
+    executor
+        .spawn(
+            "telemetry",
+            1,
+            Wake::On(timer::every((TELEMETRY_MS as u64).millis()).unwrap()),
+            telemetry,
+        )
+        .unwrap();
+    #[cfg(feature = "internal-temperature")]
+    executor
+        .spawn(
+            "temperature",
+            0,
+            Wake::On(timer::every(5.secs()).unwrap()),
+            measure_temperature,
+        )
+        .unwrap();
+    executor.spawn("log", 0, Wake::Always, write_log).unwrap();
+
+    let mut firmware = Firmware {
+        turret,
+        serial,
+        link: Link::new(),
+        keymap_store,
+        #[cfg(feature = "internal-temperature")]
+        thermometer,
+    };
+    executor.run(&mut firmware, timer::fired, avr_device::asm::sleep)
+}
+
+/// What the tasks share
+struct Firmware {
+    turret: AvrTurret,
+    serial: Serial,
+    link: Link,
+    keymap_store: KeyMapStore,
+    #[cfg(feature = "internal-temperature")]
+    thermometer: InternalThermometer,
+}
+
+/// Carry out what the remote sent
+fn ir_commands(fw: &mut Firmware) {
+    ir::poll();
+    while let Some(received) = fw.turret.handle_command(&mut fw.serial) {
+        if fw.link.is_binary() {
+            send(&mut fw.serial, &fw.link.telemetry(ir_event(&received)));
+        }
+    }
+}
+
+/// Whatever has been typed into the console, or sent by a host, so far
+fn serial_input(fw: &mut Firmware) {
+    let Firmware {
+        turret,
+        serial,
+        link,
+        ..
+    } = fw;
+    let lost = rx::take_overruns();
+    if lost > 0 {
+        rangefinder::warn!("Lost {} serial bytes", lost);
+    }
+    let now = SystemTimebase.now_ms();
+    for byte in rx::drain() {
+        if let Some(reply) = turret.dispatch(link, byte, now, serial) {
+            send(serial, &reply);
         }
+    }
+}
+
+/// Moves in progress, ranging and sentry duty
+fn motion(fw: &mut Firmware) {
+    fw.turret.poll();
+    if let Some(reply) = fw.turret.poll_range(&mut fw.serial) {
+        send(&mut fw.serial, &reply);
+    }
+
+    if fw.turret.take_keymap_learned() {
+        fw.keymap_store.save(fw.turret.keymap());
+    }
+}
+
+fn telemetry(fw: &mut Firmware) {
+    rangefinder::trace!("Clock: {}", CLOCK.now_instant().ticks());
+    if fw.link.is_binary() {
+        send(&mut fw.serial, &fw.link.telemetry(fw.turret.pose_message()));
+    }
+}
+
+#[cfg(feature = "internal-temperature")]
+fn measure_temperature(fw: &mut Firmware) {
+    if let Some(temperature) = fw.thermometer.temperature() {
+        fw.turret.set_temperature(temperature);
+    }
+}
+
+fn write_log(fw: &mut Firmware) {
+    let dropped = log::take_dropped();
+    if dropped > 0 {
+        rangefinder::warn!("Dropped {} log records", dropped);
+    }
+    for byte in log::drain() {
+        fw.serial.write_byte(byte);
+    }
+}
 
-        counter += 1;
-        arduino_hal::delay_ms(5);
+fn send(serial: &mut Serial, frame: &Frame) {
+    for byte in wire(frame) {
+        serial.write_byte(byte);
     }
 }
@@ -0,0 +1,211 @@
+//! Non-blocking servo motion.
+//!
+//! Instead of writing a servo and then sitting in `delay_ms` until it is time
+//! to write it again, moves are broken up into [`Segment`]s: a value to write
+//! and how long to hold it. Each axis has its own queue, and
+//! [`MotionEngine::poll`] moves on to the next segment once the current one has
+//! run its course, so the axes move at the same time and the main loop keeps
+//! running in between.
+
+use heapless::Deque;
+
+/// How many segments can be waiting on a single axis.
+pub const QUEUE_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum Axis {
+    Yaw = 0,
+    Pitch = 1,
+    Roll = 2,
+}
+
+impl Axis {
+    pub const ALL: [Axis; 3] = [Axis::Yaw, Axis::Pitch, Axis::Roll];
+}
+
+/// Write `value` to a servo and leave it there for `duration_ms`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub value: i16,
+    pub duration_ms: u16,
+}
+
+impl Segment {
+    pub const fn new(value: i16, duration_ms: u16) -> Self {
+        Self { value, duration_ms }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Running {
+    segment: Segment,
+    started_ms: u32,
+}
+
+#[derive(Debug, Default)]
+struct Track {
+    running: Option<Running>,
+    queue: Deque<Segment, QUEUE_DEPTH>,
+}
+
+#[derive(Debug, Default)]
+pub struct MotionEngine {
+    tracks: [Track; 3],
+}
+
+impl MotionEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue up a segment behind everything already waiting on `axis`.
+    ///
+    /// Hands the segment back if the queue is full.
+    pub fn enqueue(&mut self, axis: Axis, segment: Segment) -> Result<(), Segment> {
+        self.tracks[axis as usize].queue.push_back(segment)
+    }
+
+    /// Room left in the queue for `axis`
+    pub fn free(&self, axis: Axis) -> usize {
+        QUEUE_DEPTH - self.tracks[axis as usize].queue.len()
+    }
+
+    /// Drop everything still waiting on `axis`. The running segment is left
+    /// alone, so the servo ends up wherever that one leaves it.
+    pub fn clear(&mut self, axis: Axis) {
+        self.tracks[axis as usize].queue.clear();
+    }
+
+    /// Segments still to come on `axis`, starting with whatever is left of the
+    /// one that is running.
+    pub fn pending(&self, axis: Axis, now_ms: u32) -> impl Iterator<Item = Segment> + '_ {
+        let track = &self.tracks[axis as usize];
+        let running = track.running.map(|running| {
+            let elapsed = now_ms.wrapping_sub(running.started_ms);
+            let left = (running.segment.duration_ms as u32).saturating_sub(elapsed);
+            Segment::new(running.segment.value, left as u16)
+        });
+        running.into_iter().chain(track.queue.iter().copied())
+    }
+
+    pub fn is_idle(&self, axis: Axis) -> bool {
+        let track = &self.tracks[axis as usize];
+        track.running.is_none() && track.queue.is_empty()
+    }
+
+    pub fn is_busy(&self) -> bool {
+        Axis::ALL.iter().any(|&axis| !self.is_idle(axis))
+    }
+
+    /// Advance every axis to `now_ms`, calling `write` for each segment that
+    /// starts.
+    pub fn poll(&mut self, now_ms: u32, mut write: impl FnMut(Axis, i16)) {
+        for axis in Axis::ALL {
+            let track = &mut self.tracks[axis as usize];
+            if let Some(running) = track.running {
+                let elapsed = now_ms.wrapping_sub(running.started_ms);
+                if elapsed < running.segment.duration_ms as u32 {
+                    continue;
+                }
+                track.running = None;
+            }
+
+            if let Some(segment) = track.queue.pop_front() {
+                write(axis, segment.value);
+                track.running = Some(Running {
+                    segment,
+                    started_ms: now_ms,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(engine: &mut MotionEngine, from: u32, to: u32) -> Vec<(u32, Axis, i16)> {
+        let mut writes = Vec::new();
+        for now in from..=to {
+            engine.poll(now, |axis, value| writes.push((now, axis, value)));
+        }
+        writes
+    }
+
+    #[test]
+    fn segments_run_back_to_back() {
+        let mut engine = MotionEngine::new();
+        engine.enqueue(Axis::Roll, Segment::new(0, 115)).unwrap();
+        engine.enqueue(Axis::Roll, Segment::new(90, 5)).unwrap();
+
+        let writes = run(&mut engine, 0, 200);
+
+        assert_eq!(writes, [(0, Axis::Roll, 0), (115, Axis::Roll, 90)]);
+        assert!(!engine.is_busy());
+    }
+
+    #[test]
+    fn axes_move_concurrently() {
+        let mut engine = MotionEngine::new();
+        engine.enqueue(Axis::Yaw, Segment::new(180, 75)).unwrap();
+        engine.enqueue(Axis::Yaw, Segment::new(90, 5)).unwrap();
+        engine.enqueue(Axis::Pitch, Segment::new(92, 50)).unwrap();
+        engine.enqueue(Axis::Pitch, Segment::new(84, 50)).unwrap();
+
+        let writes = run(&mut engine, 0, 200);
+
+        assert_eq!(
+            writes,
+            [
+                (0, Axis::Yaw, 180),
+                (0, Axis::Pitch, 92),
+                (50, Axis::Pitch, 84),
+                (75, Axis::Yaw, 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn survives_clock_wraparound() {
+        let mut engine = MotionEngine::new();
+        engine.enqueue(Axis::Yaw, Segment::new(180, 10)).unwrap();
+        engine.enqueue(Axis::Yaw, Segment::new(90, 5)).unwrap();
+
+        let mut writes = Vec::new();
+        for offset in 0..20u32 {
+            let now = (u32::MAX - 4).wrapping_add(offset);
+            engine.poll(now, |_, value| writes.push((offset, value)));
+        }
+
+        assert_eq!(writes, [(0, 180), (10, 90)]);
+    }
+
+    #[test]
+    fn pending_includes_rest_of_running_segment() {
+        let mut engine = MotionEngine::new();
+        engine.enqueue(Axis::Yaw, Segment::new(180, 75)).unwrap();
+        engine.enqueue(Axis::Yaw, Segment::new(90, 5)).unwrap();
+        engine.poll(0, |_, _| {});
+
+        let pending: Vec<_> = engine.pending(Axis::Yaw, 25).collect();
+
+        assert_eq!(pending, [Segment::new(180, 50), Segment::new(90, 5)]);
+        assert_eq!(engine.pending(Axis::Roll, 25).count(), 0);
+    }
+
+    #[test]
+    fn full_queue_hands_segment_back() {
+        let mut engine = MotionEngine::new();
+        for _ in 0..QUEUE_DEPTH {
+            engine.enqueue(Axis::Pitch, Segment::new(100, 50)).unwrap();
+        }
+
+        assert_eq!(engine.free(Axis::Pitch), 0);
+        assert_eq!(
+            engine.enqueue(Axis::Pitch, Segment::new(92, 50)),
+            Err(Segment::new(92, 50))
+        );
+        assert_eq!(engine.free(Axis::Yaw), QUEUE_DEPTH);
+    }
+}
@@ -0,0 +1,346 @@
+//! Distances around the turret, binned by where it was pointing.
+//!
+//! A [`RangeMap`] keeps the latest reading for every yaw/pitch bin it has seen.
+//! Capture a baseline of the empty room with [`RangeMap::capture_baseline`], and
+//! from then on [`RangeMap::changes`] lists the bins where something came, went
+//! or moved.
+
+use heapless::LinearMap;
+use uom::si::{
+    angle::degree,
+    f32::{Angle, Length},
+};
+
+use crate::{hal::RangeSensor, hc_sr04::HcSr04Error, turret::normalize};
+
+/// What the range finder saw in a bin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reading {
+    /// Something this far away
+    Echo(Length),
+    /// Nothing within range of the sensor
+    Clear,
+}
+
+impl Reading {
+    /// Whether going from `self` to `other` is more than sensor noise
+    pub fn differs_from(&self, other: &Reading, threshold: Length) -> bool {
+        match (*self, *other) {
+            (Reading::Echo(a), Reading::Echo(b)) => {
+                let diff = a - b;
+                diff > threshold || -diff > threshold
+            }
+            (Reading::Clear, Reading::Clear) => false,
+            _ => true,
+        }
+    }
+}
+
+/// A patch of the room, as yaw and pitch bin indices.
+///
+/// Bin `0, 0` is centered on yaw 0 and pitch 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub struct Bin {
+    pub yaw: i16,
+    pub pitch: i16,
+}
+
+/// A bin whose latest reading doesn't match the baseline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Change {
+    pub bin: Bin,
+    pub baseline: Reading,
+    pub latest: Reading,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ufmt::derive::uDebug)]
+pub enum RangeMapError {
+    /// Every bin is taken and the reading didn't fit in any of them
+    Full,
+    /// The range finder failed, so there is nothing to record
+    Sensor(HcSr04Error),
+    /// A bin size that isn't positive, or so small the bin indices would
+    /// overflow
+    BadBin,
+}
+
+/// Narrowest yaw bin, in degrees. Any narrower and a turn takes more bins than
+/// an `i16` can count.
+pub const MIN_YAW_BIN: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug)]
+struct Cell {
+    latest: Reading,
+    baseline: Option<Reading>,
+}
+
+/// Up to `N` bins worth of readings.
+#[derive(Debug)]
+pub struct RangeMap<const N: usize> {
+    /// Degrees of yaw per bin
+    yaw_bin: f32,
+    /// Pitch servo values per bin
+    pitch_bin: i16,
+    cells: LinearMap<Bin, Cell, N>,
+}
+
+impl<const N: usize> RangeMap<N> {
+    /// Fails with [`RangeMapError::BadBin`] unless `yaw_bin` is at least
+    /// [`MIN_YAW_BIN`] and `pitch_bin` is positive.
+    pub fn new(yaw_bin: Angle, pitch_bin: i16) -> Result<Self, RangeMapError> {
+        let yaw_bin = yaw_bin.get::<degree>();
+        // Written this way round so NaN fails too
+        if !(yaw_bin >= MIN_YAW_BIN && yaw_bin.is_finite()) || pitch_bin <= 0 {
+            return Err(RangeMapError::BadBin);
+        }
+        Ok(Self {
+            yaw_bin,
+            pitch_bin,
+            cells: LinearMap::new(),
+        })
+    }
+
+    /// Bin a reading taken at `yaw` and pitch servo value `pitch` falls in.
+    pub fn bin(&self, yaw: Angle, pitch: i16) -> Bin {
+        let turns = nearest(360.0 / self.yaw_bin);
+        let mut yaw = nearest(normalize(yaw.get::<degree>()) / self.yaw_bin);
+        // -180 and 180 degrees are the same direction
+        if yaw * 2 >= turns {
+            yaw -= turns;
+        }
+        Bin {
+            yaw,
+            pitch: nearest(pitch as f32 / self.pitch_bin as f32),
+        }
+    }
+
+    /// Yaw and pitch servo value at the middle of `bin`, e.g. to aim at it.
+    pub fn center(&self, bin: Bin) -> (Angle, i16) {
+        (
+            Angle::new::<degree>(bin.yaw as f32 * self.yaw_bin),
+            bin.pitch * self.pitch_bin,
+        )
+    }
+
+    /// File the result of a measurement taken at `yaw` and `pitch`.
+    ///
+    /// [`HcSr04Error::NoEcho`] means nothing is in range, which is recorded as
+    /// [`Reading::Clear`]. Other errors leave the map alone.
+    pub fn record(
+        &mut self,
+        yaw: Angle,
+        pitch: i16,
+        distance: Result<Length, HcSr04Error>,
+    ) -> Result<Bin, RangeMapError> {
+        let latest = match distance {
+            Ok(distance) => Reading::Echo(distance),
+            Err(HcSr04Error::NoEcho) => Reading::Clear,
+            Err(e) => return Err(RangeMapError::Sensor(e)),
+        };
+
+        let bin = self.bin(yaw, pitch);
+        if let Some(cell) = self.cells.get_mut(&bin) {
+            cell.latest = latest;
+        } else {
+            let cell = Cell {
+                latest,
+                baseline: None,
+            };
+            self.cells
+                .insert(bin, cell)
+                .map_err(|_| RangeMapError::Full)?;
+        }
+        Ok(bin)
+    }
+
+    /// Measure with `sensor` and record the result, see [`RangeMap::record`].
+    pub fn scan<S: RangeSensor>(
+        &mut self,
+        sensor: &mut S,
+        yaw: Angle,
+        pitch: i16,
+    ) -> Result<Bin, RangeMapError> {
+        let distance = sensor.measure_distance();
+        self.record(yaw, pitch, distance)
+    }
+
+    pub fn latest(&self, bin: Bin) -> Option<Reading> {
+        self.cells.get(&bin).map(|cell| cell.latest)
+    }
+
+    pub fn baseline(&self, bin: Bin) -> Option<Reading> {
+        self.cells.get(&bin).and_then(|cell| cell.baseline)
+    }
+
+    /// Every bin with a reading, and the latest reading in it
+    pub fn iter(&self) -> impl Iterator<Item = (Bin, Reading)> + '_ {
+        self.cells.iter().map(|(&bin, cell)| (bin, cell.latest))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Forget every reading, baseline included.
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    /// Take the latest readings as what the room normally looks like.
+    pub fn capture_baseline(&mut self) {
+        for (_, cell) in self.cells.iter_mut() {
+            cell.baseline = Some(cell.latest);
+        }
+    }
+
+    /// Bins where the latest reading differs from the baseline by more than
+    /// `threshold`. Bins first seen after the baseline was captured have
+    /// nothing to compare against, so they are never reported.
+    pub fn changes(&self, threshold: Length) -> impl Iterator<Item = Change> + '_ {
+        self.cells.iter().filter_map(move |(&bin, cell)| {
+            let baseline = cell.baseline?;
+            baseline
+                .differs_from(&cell.latest, threshold)
+                .then_some(Change {
+                    bin,
+                    baseline,
+                    latest: cell.latest,
+                })
+        })
+    }
+}
+
+/// Round to the nearest whole number, halfway cases away from zero
+fn nearest(x: f32) -> i16 {
+    if x < 0.0 {
+        (x - 0.5) as i16
+    } else {
+        (x + 0.5) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uom::si::length::{centimeter, meter};
+
+    use super::*;
+    use crate::hal::host::ScriptedRangeSensor;
+
+    fn map() -> RangeMap<8> {
+        RangeMap::new(Angle::new::<degree>(15.0), 20).unwrap()
+    }
+
+    fn deg(degrees: f32) -> Angle {
+        Angle::new::<degree>(degrees)
+    }
+
+    fn m(meters: f32) -> Length {
+        Length::new::<meter>(meters)
+    }
+
+    #[test]
+    fn readings_land_in_the_nearest_bin() {
+        let map = map();
+
+        assert_eq!(map.bin(deg(14.9), 100), Bin { yaw: 1, pitch: 5 });
+        assert_eq!(map.bin(deg(-7.4), 109), Bin { yaw: 0, pitch: 5 });
+        assert_eq!(map.bin(deg(-8.0), 111), Bin { yaw: -1, pitch: 6 });
+        assert_eq!(map.bin(deg(180.0), 0), map.bin(deg(-180.0), 0));
+        assert_eq!(map.bin(deg(375.0), 0), Bin { yaw: 1, pitch: 0 });
+        assert_eq!(map.center(Bin { yaw: -2, pitch: 5 }), (deg(-30.0), 100));
+    }
+
+    #[test]
+    fn bins_must_be_positive() {
+        for (yaw, pitch) in [
+            (0.0, 20),
+            (-15.0, 20),
+            (MIN_YAW_BIN / 2.0, 20),
+            (f32::NAN, 20),
+            (15.0, 0),
+            (15.0, -5),
+        ] {
+            assert_eq!(
+                RangeMap::<8>::new(deg(yaw), pitch).err(),
+                Some(RangeMapError::BadBin),
+                "{yaw} {pitch}"
+            );
+        }
+
+        // The narrowest bins still count a whole turn without overflowing
+        let fine = RangeMap::<8>::new(deg(MIN_YAW_BIN), 1).unwrap();
+        assert_eq!(fine.bin(deg(180.0), 0), fine.bin(deg(-180.0), 0));
+        assert_eq!(fine.bin(deg(179.9), i16::MAX).pitch, i16::MAX);
+    }
+
+    #[test]
+    fn reports_changes_from_the_baseline() {
+        let mut map = map();
+        map.record(deg(-15.0), 100, Ok(m(2.0))).unwrap();
+        map.record(deg(0.0), 100, Ok(m(3.0))).unwrap();
+        map.record(deg(15.0), 100, Err(HcSr04Error::NoEcho))
+            .unwrap();
+        map.record(deg(30.0), 100, Ok(m(1.0))).unwrap();
+        map.capture_baseline();
+        assert_eq!(map.changes(m(0.1)).count(), 0);
+
+        // Jitter, something moved in front of the wall, something walked into
+        // an empty patch, and something left
+        map.record(deg(-14.0), 100, Ok(m(2.03))).unwrap();
+        map.record(deg(1.0), 100, Ok(m(1.2))).unwrap();
+        map.record(deg(16.0), 100, Ok(m(2.5))).unwrap();
+        map.record(deg(29.0), 100, Err(HcSr04Error::NoEcho))
+            .unwrap();
+
+        let changes: Vec<_> = map
+            .changes(Length::new::<centimeter>(10.0))
+            .map(|c| (c.bin.yaw, c.baseline, c.latest))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (0, Reading::Echo(m(3.0)), Reading::Echo(m(1.2))),
+                (1, Reading::Clear, Reading::Echo(m(2.5))),
+                (2, Reading::Echo(m(1.0)), Reading::Clear),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_bins_have_no_baseline() {
+        let mut map = map();
+        map.record(deg(0.0), 100, Ok(m(3.0))).unwrap();
+        map.capture_baseline();
+
+        let bin = map.record(deg(90.0), 100, Ok(m(0.5))).unwrap();
+
+        assert_eq!(map.baseline(bin), None);
+        assert_eq!(map.latest(bin), Some(Reading::Echo(m(0.5))));
+        assert_eq!(map.changes(m(0.1)).count(), 0);
+    }
+
+    #[test]
+    fn sensor_errors_and_full_map_are_reported() {
+        let mut map: RangeMap<1> = RangeMap::new(deg(15.0), 20).unwrap();
+        let mut sensor = ScriptedRangeSensor::new();
+        sensor.push(Err(HcSr04Error::InvalidResult));
+        sensor.push(Ok(m(1.0)));
+        sensor.push(Ok(m(2.0)));
+
+        assert_eq!(
+            map.scan(&mut sensor, deg(0.0), 100),
+            Err(RangeMapError::Sensor(HcSr04Error::InvalidResult))
+        );
+        assert!(map.is_empty());
+        map.scan(&mut sensor, deg(0.0), 100).unwrap();
+        assert_eq!(
+            map.scan(&mut sensor, deg(45.0), 100),
+            Err(RangeMapError::Full)
+        );
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+//! Bytes coming in over the serial port, caught by the `USART_RX` interrupt.
+//!
+//! The USART only holds two bytes, and a host sends whole frames at once, so
+//! reading it from the main loop loses bytes whenever a task runs long. The
+//! handler puts each byte into [`RX`] as it comes in, and the main loop takes
+//! them out with [`drain`]. Bytes that don't fit are dropped and counted.
+
+#[cfg(target_arch = "avr")]
+use arduino_hal::pac::USART0;
+use heapless::Deque;
+
+use crate::sync::Lock;
+
+/// Bytes waiting for [`drain`], room for a couple of frames
+pub const BUFFER: usize = 64;
+
+struct State<const N: usize> {
+    bytes: Deque<u8, N>,
+    overruns: u16,
+}
+
+/// Received bytes waiting for the main loop. The firmware has the one [`RX`].
+pub struct RxBuffer<const N: usize> {
+    state: Lock<State<N>>,
+}
+
+impl<const N: usize> Default for RxBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RxBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            state: Lock::new(State {
+                bytes: Deque::new(),
+                overruns: 0,
+            }),
+        }
+    }
+
+    /// Keep a byte that just came in, unless the buffer is full.
+    pub fn push(&self, byte: u8) {
+        self.state.lock(|state| {
+            if state.bytes.push_back(byte).is_err() {
+                state.overruns = state.overruns.saturating_add(1);
+            }
+        });
+    }
+
+    /// The oldest byte waiting, if there is one.
+    pub fn pop(&self) -> Option<u8> {
+        self.state.lock(|state| state.bytes.pop_front())
+    }
+
+    /// How many bytes didn't fit since the last call.
+    pub fn take_overruns(&self) -> u16 {
+        self.state
+            .lock(|state| core::mem::take(&mut state.overruns))
+    }
+}
+
+pub static RX: RxBuffer<BUFFER> = RxBuffer::new();
+
+/// Every byte received so far, oldest first.
+pub fn drain() -> impl Iterator<Item = u8> {
+    core::iter::from_fn(|| RX.pop())
+}
+
+/// See [`RxBuffer::take_overruns`].
+pub fn take_overruns() -> u16 {
+    RX.take_overruns()
+}
+
+/// Have the USART raise `USART_RX` for each byte. Reading it any other way
+/// from then on races the handler.
+#[cfg(target_arch = "avr")]
+pub fn listen() {
+    // Safety: only the receive interrupt enable is touched, which the HAL's
+    // `Usart` leaves alone after setting up
+    let usart = unsafe { &*USART0::ptr() };
+    usart.ucsr0b.modify(|_, w| w.rxcie0().set_bit());
+}
+
+#[cfg(target_arch = "avr")]
+#[avr_device::interrupt(atmega328p)]
+fn USART_RX() {
+    // Reading the byte is what clears the interrupt
+    let usart = unsafe { &*USART0::ptr() };
+    RX.push(usart.udr0.read().bits());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_bytes_in_order_and_counts_what_doesnt_fit() {
+        let rx = RxBuffer::<4>::new();
+        for byte in b"help\n" {
+            rx.push(*byte);
+        }
+        assert_eq!(rx.take_overruns(), 1);
+        assert_eq!(rx.take_overruns(), 0);
+
+        let bytes: std::vec::Vec<u8> = core::iter::from_fn(|| rx.pop()).collect();
+        assert_eq!(bytes, b"help");
+    }
+}
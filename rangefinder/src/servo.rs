@@ -4,13 +4,12 @@ use arduino_hal::{
     hal::port::Dynamic,
     pac::TC1,
     port::{mode::Output, Pin, PinOps},
-    prelude::_unwrap_infallible_UnwrapInfallible,
 };
 use avr_device::interrupt::Mutex;
 use heapless::Vec;
 use vcell::VolatileCell;
 
-use crate::Serial;
+use crate::hal::Actuator;
 
 const MAX_SERVOS: usize = 12;
 const REFRESH_INTERVAL: u16 = 20_000;
@@ -196,32 +195,47 @@ impl Servo<ServoAttached> {
         }
     }
 
-    pub fn write(&self, value: u8, serial: &mut Serial) {
-        ufmt::uwriteln!(serial, "Writing {} in range\r", value).unwrap_infallible();
+    pub fn write(&self, value: u8) {
+        crate::debug!("Writing {} in range", value);
         let value = value.clamp(0, 180);
         let value = map(value as i16, 0, 180, self.servo_min(), self.servo_max());
-        self.write_us(value, serial);
+        self.write_us(value);
     }
 
-    pub fn write_us(&self, value: i16, serial: &mut Serial) {
+    pub fn write_us(&self, value: i16) {
+        let value = self.pulse_ticks(value);
+
+        crate::debug!("Writing {} us", value);
+
+        self.set_ticks(value);
+    }
+
+    /// Convert a pulse width to timer ticks, clamped to this servo's limits
+    fn pulse_ticks(&self, value: i16) -> u16 {
         // ensure pulse width is valid
         let value = value.clamp(self.servo_min(), self.servo_max());
 
         // convert to ticks after compensating for interrupt overhead - 12 Aug 2009
         let value = value - TRIM_DURATION;
-        let value = us_to_ticks(value as u32);
-
-        ufmt::uwriteln!(serial, "Writing {} us\r", value).unwrap_infallible();
+        us_to_ticks(value as u32) as u16
+    }
 
+    fn set_ticks(&self, ticks: u16) {
         avr_device::interrupt::free(|cs| {
             let mut servos = SERVOS.borrow(cs).borrow_mut();
             // This can't panic because the servo was successfully constructed
             let servo = &mut servos[self.index];
-            servo.ticks.set(value as u16);
+            servo.ticks.set(ticks);
         });
     }
 }
 
+impl Actuator for Servo<ServoAttached> {
+    fn write(&mut self, value: i16) {
+        Servo::write(self, value.clamp(0, 180) as u8);
+    }
+}
+
 pub fn donate_tc1(tc1: TC1) {
     // Safety: TC1 must be initialized before calling Servo::new()
     // So no Servo instances can exist before this function is called
@@ -0,0 +1,211 @@
+//! Driving the turret from a serial console, a line at a time.
+//!
+//! [`Shell::feed`] takes bytes as they come in off the USART, without ever
+//! waiting for more, and hands back a [`ShellCommand`] once a whole line is in.
+//! [`Turret::execute`](crate::turret::Turret::execute) carries it out.
+
+use heapless::String;
+
+use crate::turret::{TurretAction, PRESETS};
+
+/// Longest line the shell takes
+pub const LINE_LEN: usize = 32;
+
+/// Printed for `help`
+pub const HELP: &str = "\
+up|down|left|right [n]  move n steps
+yaw <degrees>           turn to face, + is left
+fire [n]                fire n darts
+fireall                 fire every dart
+sentry                  toggle sentry mode
+preset <0-9>            go to a saved pose
+lead                    toggle leading other turrets
+range                   measure the distance
+status                  where the turret is and what it's doing
+help                    this";
+
+/// A line typed into the shell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShellCommand {
+    /// What a remote button would do, this many times over
+    Action(TurretAction, u8),
+    /// Turn to face this many degrees from straight ahead, positive to the left
+    Yaw(i16),
+    Range,
+    Status,
+    Help,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum ParseError {
+    /// Not a command the shell knows
+    Unknown,
+    /// A number was missing, malformed or out of range
+    BadArgument,
+    /// The line was longer than [`LINE_LEN`]
+    TooLong,
+}
+
+impl core::str::FromStr for ShellCommand {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or(ParseError::Unknown)?;
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(ParseError::BadArgument);
+        }
+
+        let times = |arg: Option<&str>| match arg {
+            None => Ok(1),
+            Some(arg) => arg
+                .parse::<u8>()
+                .ok()
+                .filter(|&times| times > 0)
+                .ok_or(ParseError::BadArgument),
+        };
+        let no_arg = |command| match arg {
+            None => Ok(command),
+            Some(_) => Err(ParseError::BadArgument),
+        };
+
+        match name {
+            "up" => Ok(ShellCommand::Action(TurretAction::Up, times(arg)?)),
+            "down" => Ok(ShellCommand::Action(TurretAction::Down, times(arg)?)),
+            "left" => Ok(ShellCommand::Action(TurretAction::Left, times(arg)?)),
+            "right" => Ok(ShellCommand::Action(TurretAction::Right, times(arg)?)),
+            "fire" => Ok(ShellCommand::Action(TurretAction::Fire, times(arg)?)),
+            "fireall" => no_arg(ShellCommand::Action(TurretAction::FireAll, 1)),
+            "sentry" => no_arg(ShellCommand::Action(TurretAction::ToggleSentry, 1)),
+            "lead" => no_arg(ShellCommand::Action(TurretAction::Broadcast, 1)),
+            "preset" => arg
+                .and_then(|arg| arg.parse::<u8>().ok())
+                .filter(|&index| (index as usize) < PRESETS)
+                .map(|index| ShellCommand::Action(TurretAction::Preset(index), 1))
+                .ok_or(ParseError::BadArgument),
+            "yaw" => arg
+                .and_then(|arg| arg.parse::<i16>().ok())
+                .filter(|degrees| (-180..=180).contains(degrees))
+                .map(ShellCommand::Yaw)
+                .ok_or(ParseError::BadArgument),
+            "range" => no_arg(ShellCommand::Range),
+            "status" => no_arg(ShellCommand::Status),
+            "help" | "?" => no_arg(ShellCommand::Help),
+            _ => Err(ParseError::Unknown),
+        }
+    }
+}
+
+/// Collects bytes into lines and parses them.
+#[derive(Clone, Debug, Default)]
+pub struct Shell {
+    line: String<LINE_LEN>,
+    /// The line being typed has already run past [`LINE_LEN`]
+    overflowed: bool,
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Self {
+            line: String::new(),
+            overflowed: false,
+        }
+    }
+
+    /// Take the next byte from the console. Returns the command once a line
+    /// ends, and `None` until then or for blank lines.
+    ///
+    /// Lines end in `\r`, `\n` or both, and backspace takes back the last
+    /// character.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<ShellCommand, ParseError>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let overflowed = core::mem::take(&mut self.overflowed);
+                let line = core::mem::take(&mut self.line);
+                if overflowed {
+                    Some(Err(ParseError::TooLong))
+                } else if line.trim().is_empty() {
+                    None
+                } else {
+                    Some(line.parse())
+                }
+            }
+            // Backspace and delete
+            0x08 | 0x7F => {
+                self.line.pop();
+                None
+            }
+            // Only printable ASCII, so the line is always valid UTF-8
+            b' '..=b'~' => {
+                if self.line.push(byte as char).is_err() {
+                    self.overflowed = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_line(shell: &mut Shell, line: &str) -> Option<Result<ShellCommand, ParseError>> {
+        let mut result = None;
+        for byte in line.bytes() {
+            result = shell.feed(byte);
+        }
+        result
+    }
+
+    #[test]
+    fn parses_commands_with_and_without_arguments() {
+        let parse = |line: &str| line.parse::<ShellCommand>();
+        assert_eq!(parse("up 3"), Ok(ShellCommand::Action(TurretAction::Up, 3)));
+        assert_eq!(
+            parse("  fire "),
+            Ok(ShellCommand::Action(TurretAction::Fire, 1))
+        );
+        assert_eq!(parse("yaw -45"), Ok(ShellCommand::Yaw(-45)));
+        assert_eq!(
+            parse("preset 7"),
+            Ok(ShellCommand::Action(TurretAction::Preset(7), 1))
+        );
+        assert_eq!(parse("status"), Ok(ShellCommand::Status));
+
+        assert_eq!(parse("jump"), Err(ParseError::Unknown));
+        assert_eq!(parse("up 0"), Err(ParseError::BadArgument));
+        assert_eq!(parse("up lots"), Err(ParseError::BadArgument));
+        assert_eq!(parse("yaw 270"), Err(ParseError::BadArgument));
+        assert_eq!(parse("preset"), Err(ParseError::BadArgument));
+        assert_eq!(parse("preset 10"), Err(ParseError::BadArgument));
+        assert_eq!(parse("range 2"), Err(ParseError::BadArgument));
+    }
+
+    #[test]
+    fn builds_lines_from_bytes() {
+        let mut shell = Shell::new();
+        // Typo fixed with backspace, ended with CRLF
+        assert_eq!(
+            type_line(&mut shell, "rang\x08\x08nge\r"),
+            Some(Ok(ShellCommand::Range))
+        );
+        assert_eq!(type_line(&mut shell, "\n"), None);
+        assert_eq!(
+            type_line(&mut shell, "left 2\n"),
+            Some(Ok(ShellCommand::Action(TurretAction::Left, 2)))
+        );
+
+        let long = [b'x'; LINE_LEN + 1];
+        for byte in long {
+            assert_eq!(shell.feed(byte), None);
+        }
+        assert_eq!(shell.feed(b'\n'), Some(Err(ParseError::TooLong)));
+        assert_eq!(
+            type_line(&mut shell, "help\n"),
+            Some(Ok(ShellCommand::Help))
+        );
+    }
+}
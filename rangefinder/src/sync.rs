@@ -0,0 +1,77 @@
+//! Sharing data between the main loop and interrupt handlers.
+
+#[cfg(target_arch = "avr")]
+use core::cell::RefCell;
+#[cfg(not(target_arch = "avr"))]
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(target_arch = "avr")]
+use avr_device::interrupt::Mutex;
+
+/// A value behind a lock. On the Nano holding the lock keeps interrupts off, so
+/// keep it short. On the host, where tests run on several threads, it spins.
+///
+/// Taking the lock again while holding it panics on the Nano and hangs on the
+/// host.
+pub struct Lock<T> {
+    #[cfg(target_arch = "avr")]
+    value: Mutex<RefCell<T>>,
+    #[cfg(not(target_arch = "avr"))]
+    locked: AtomicBool,
+    #[cfg(not(target_arch = "avr"))]
+    value: UnsafeCell<T>,
+}
+
+// Only one thread at a time gets at the value
+#[cfg(not(target_arch = "avr"))]
+unsafe impl<T: Send> Sync for Lock<T> {}
+
+impl<T> Lock<T> {
+    #[cfg(target_arch = "avr")]
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: Mutex::new(RefCell::new(value)),
+        }
+    }
+
+    #[cfg(not(target_arch = "avr"))]
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg(target_arch = "avr")]
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        avr_device::interrupt::free(|cs| f(&mut self.value.borrow(cs).borrow_mut()))
+    }
+
+    #[cfg(not(target_arch = "avr"))]
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let _unlock = Unlock(&self.locked);
+        // The flag keeps everyone else out until it is cleared again
+        f(unsafe { &mut *self.value.get() })
+    }
+}
+
+/// Clears the flag once the value is done with, even if that was a panic
+#[cfg(not(target_arch = "avr"))]
+struct Unlock<'a>(&'a AtomicBool);
+
+#[cfg(not(target_arch = "avr"))]
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
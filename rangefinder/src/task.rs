@@ -0,0 +1,225 @@
+//! Running the firmware's jobs side by side, without any one of them waiting
+//! on another.
+//!
+//! Each job is a [`Task`]: a function that does what it can right away and
+//! returns, never waiting on hardware. An [`Executor`] keeps a fixed table of
+//! them and, each round, runs the ones that are due, highest priority first.
+//! A task is due every round, or when its [`Alarm`] has fired. Tasks woken by
+//! a one-shot alarm run the once and are then dropped, freeing their place.
+
+use heapless::Vec;
+
+use crate::timer::Alarm;
+
+/// Tasks an [`Executor`] can have, if not told otherwise
+pub const TASKS: usize = 8;
+
+/// The task table is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Full;
+
+/// When a task is due
+#[derive(Debug)]
+pub enum Wake {
+    /// Every round
+    Always,
+    /// Whenever the alarm has fired since the last round. For alarms that
+    /// keep going, from [`timer::every`](crate::timer::every).
+    On(Alarm),
+    /// When the alarm fires, after which the task is dropped. For one-shot
+    /// alarms, from [`timer::once`](crate::timer::once).
+    Once(Alarm),
+}
+
+/// A job, and what it needs to run.
+pub struct Task<C> {
+    name: &'static str,
+    priority: u8,
+    wake: Wake,
+    run: fn(&mut C),
+}
+
+impl<C> Task<C> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+/// Runs up to `N` tasks sharing `C`, the state they work on.
+pub struct Executor<C, const N: usize = TASKS> {
+    /// Highest priority first, and in the order they were added within a
+    /// priority
+    tasks: Vec<Task<C>, N>,
+}
+
+impl<C, const N: usize> Default for Executor<C, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C, const N: usize> Executor<C, N> {
+    pub const fn new() -> Self {
+        Self { tasks: Vec::new() }
+    }
+
+    /// Add a task. Higher `priority` runs first in each round.
+    pub fn spawn(
+        &mut self,
+        name: &'static str,
+        priority: u8,
+        wake: Wake,
+        run: fn(&mut C),
+    ) -> Result<(), Full> {
+        let index = self
+            .tasks
+            .iter()
+            .position(|task| task.priority < priority)
+            .unwrap_or(self.tasks.len());
+        self.tasks
+            .insert(
+                index,
+                Task {
+                    name,
+                    priority,
+                    wake,
+                    run,
+                },
+            )
+            .map_err(|_| Full)
+    }
+
+    pub fn tasks(&self) -> &[Task<C>] {
+        &self.tasks
+    }
+
+    /// Run every task that's due, once. `fired` says whether an alarm went
+    /// off, as [`timer::fired`](crate::timer::fired) does. Returns how many
+    /// tasks ran.
+    pub fn run_once(&mut self, context: &mut C, mut fired: impl FnMut(&Alarm) -> bool) -> usize {
+        let mut ran = 0;
+        let mut index = 0;
+        while let Some(task) = self.tasks.get(index) {
+            let (due, once) = match &task.wake {
+                Wake::Always => (true, false),
+                Wake::On(alarm) => (fired(alarm), false),
+                Wake::Once(alarm) => (fired(alarm), true),
+            };
+            if due {
+                (task.run)(context);
+                ran += 1;
+            }
+            if due && once {
+                self.tasks.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+        ran
+    }
+
+    /// Run rounds for good, calling `idle` between them.
+    pub fn run(
+        &mut self,
+        context: &mut C,
+        mut fired: impl FnMut(&Alarm) -> bool,
+        mut idle: impl FnMut(),
+    ) -> ! {
+        loop {
+            self.run_once(context, &mut fired);
+            idle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fugit::ExtU64;
+
+    use super::*;
+    use crate::{clock::Instant, timer::Timers};
+
+    type Ran = std::vec::Vec<&'static str>;
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let mut executor = Executor::<Ran, 4>::new();
+        executor
+            .spawn("log", 0, Wake::Always, |ran| ran.push("log"))
+            .unwrap();
+        executor
+            .spawn("ir", 3, Wake::Always, |ran| ran.push("ir"))
+            .unwrap();
+        executor
+            .spawn("shell", 2, Wake::Always, |ran| ran.push("shell"))
+            .unwrap();
+        executor
+            .spawn("motion", 2, Wake::Always, |ran| ran.push("motion"))
+            .unwrap();
+        assert_eq!(
+            executor.spawn("ranging", 1, Wake::Always, |ran| ran.push("ranging")),
+            Err(Full)
+        );
+
+        let mut ran = Ran::new();
+        assert_eq!(executor.run_once(&mut ran, |_| false), 4);
+        assert_eq!(executor.run_once(&mut ran, |_| false), 4);
+        assert_eq!(
+            ran,
+            ["ir", "shell", "motion", "log", "ir", "shell", "motion", "log"]
+        );
+        let priorities: Ran = executor.tasks().iter().map(Task::name).collect();
+        assert_eq!(priorities, ["ir", "shell", "motion", "log"]);
+    }
+
+    #[test]
+    fn alarms_wake_tasks() {
+        let timers = Timers::<2>::new();
+        let start = Instant::from_ticks(0);
+        let mut executor = Executor::<Ran>::new();
+        executor
+            .spawn("motion", 1, Wake::Always, |ran| ran.push("motion"))
+            .unwrap();
+        let every = timers.every(start, 1.millis()).unwrap();
+        executor
+            .spawn("telemetry", 2, Wake::On(every), |ran| ran.push("telemetry"))
+            .unwrap();
+        let once = timers.once(start, 2.millis()).unwrap();
+        executor
+            .spawn("scan", 0, Wake::Once(once), |ran| ran.push("scan"))
+            .unwrap();
+
+        let mut ran = Ran::new();
+        let mut clock = 0;
+        for _ in 0..3 {
+            // A round every half millisecond
+            for _ in 0..20 {
+                clock += 1;
+                timers.tick(|| Instant::from_ticks(clock));
+            }
+            executor.run_once(&mut ran, |alarm| timers.fired(alarm));
+            ran.push("|");
+        }
+        assert_eq!(
+            ran,
+            ["motion", "|", "telemetry", "motion", "|", "motion", "|"]
+        );
+        for _ in 0..20 {
+            clock += 1;
+            timers.tick(|| Instant::from_ticks(clock));
+        }
+        ran.clear();
+        executor.run_once(&mut ran, |alarm| timers.fired(alarm));
+        assert_eq!(ran, ["telemetry", "motion", "scan"]);
+
+        // Done with, so it's dropped
+        assert_eq!(executor.tasks().len(), 2);
+        ran.clear();
+        executor.run_once(&mut ran, |alarm| timers.fired(alarm));
+        assert_eq!(ran, ["motion"]);
+    }
+}
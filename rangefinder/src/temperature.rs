@@ -0,0 +1,186 @@
+//! Where the air temperature for the speed of sound comes from.
+//!
+//! Either the ATmega328P's own temperature sensor on ADC channel 8, which needs
+//! no wiring but reads the chip rather than the room, or an NTC thermistor in a
+//! divider on an analog pin.
+
+#[cfg(target_arch = "avr")]
+use arduino_hal::{
+    adc::{channel, AdcSettings, Channel, ReferenceVoltage},
+    pac::ADC,
+    Adc,
+};
+use uom::si::{f32::TemperatureInterval, temperature_interval::degree_celsius};
+
+#[cfg(target_arch = "avr")]
+use crate::hal::Thermometer;
+
+/// Full scale reading of the 10-bit ADC
+const ADC_MAX: f32 = 1023.0;
+const KELVIN: f32 = 273.15;
+
+/// Line from the internal sensor's ADC reading to degrees Celsius.
+///
+/// The datasheet only promises ±10 °C out of the box, so for anything better
+/// measure a couple of points and fill these in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InternalCalibration {
+    /// Reading at 0 °C
+    pub offset: f32,
+    /// Readings per degree
+    pub gain: f32,
+}
+
+impl Default for InternalCalibration {
+    fn default() -> Self {
+        Self {
+            offset: 324.31,
+            gain: 1.22,
+        }
+    }
+}
+
+impl InternalCalibration {
+    pub fn temperature(&self, raw: u16) -> TemperatureInterval {
+        TemperatureInterval::new::<degree_celsius>((raw as f32 - self.offset) / self.gain)
+    }
+}
+
+/// An NTC thermistor between the analog pin and ground, with a fixed resistor
+/// from the pin up to the ADC reference.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermistorCurve {
+    /// The fixed resistor, in ohms
+    pub series_ohms: f32,
+    /// Thermistor resistance at `nominal_celsius`, in ohms
+    pub nominal_ohms: f32,
+    pub nominal_celsius: f32,
+    /// B coefficient from the thermistor's datasheet
+    pub beta: f32,
+}
+
+impl Default for ThermistorCurve {
+    /// The common 10 kΩ, B = 3950 thermistor with a 10 kΩ resistor
+    fn default() -> Self {
+        Self {
+            series_ohms: 10_000.0,
+            nominal_ohms: 10_000.0,
+            nominal_celsius: 25.0,
+            beta: 3950.0,
+        }
+    }
+}
+
+impl ThermistorCurve {
+    /// `None` if the pin reads as shorted or open.
+    pub fn temperature(&self, raw: u16) -> Option<TemperatureInterval> {
+        let raw = raw as f32;
+        if raw <= 0.0 || raw >= ADC_MAX {
+            return None;
+        }
+        let ohms = self.series_ohms * raw / (ADC_MAX - raw);
+        let inverse = 1.0 / (self.nominal_celsius + KELVIN)
+            + libm::logf(ohms / self.nominal_ohms) / self.beta;
+        Some(TemperatureInterval::new::<degree_celsius>(
+            1.0 / inverse - KELVIN,
+        ))
+    }
+}
+
+/// The ATmega328P's built-in temperature sensor.
+///
+/// It only reads right against the internal 1.1 V reference, so this takes the
+/// whole ADC for itself.
+#[cfg(target_arch = "avr")]
+pub struct InternalThermometer {
+    adc: Adc,
+    calibration: InternalCalibration,
+}
+
+#[cfg(target_arch = "avr")]
+impl InternalThermometer {
+    pub fn new(adc: ADC, calibration: InternalCalibration) -> Self {
+        let settings = AdcSettings {
+            ref_voltage: ReferenceVoltage::Internal,
+            ..AdcSettings::default()
+        };
+        Self {
+            adc: Adc::new(adc, settings),
+            calibration,
+        }
+    }
+}
+
+#[cfg(target_arch = "avr")]
+impl Thermometer for InternalThermometer {
+    fn temperature(&mut self) -> Option<TemperatureInterval> {
+        let raw = self.adc.read_blocking(&channel::Temperature);
+        Some(self.calibration.temperature(raw))
+    }
+}
+
+/// A thermistor on one of the analog pins, see [`ThermistorCurve`].
+#[cfg(target_arch = "avr")]
+pub struct Thermistor {
+    adc: Adc,
+    channel: Channel,
+    curve: ThermistorCurve,
+}
+
+#[cfg(target_arch = "avr")]
+impl Thermistor {
+    /// `channel` is the pin the thermistor is on, e.g.
+    /// `pins.a0.into_analog_input(&mut adc).into_channel()`.
+    pub fn new(adc: Adc, channel: Channel, curve: ThermistorCurve) -> Self {
+        Self {
+            adc,
+            channel,
+            curve,
+        }
+    }
+}
+
+#[cfg(target_arch = "avr")]
+impl Thermometer for Thermistor {
+    fn temperature(&mut self) -> Option<TemperatureInterval> {
+        let raw = self.adc.read_blocking(&self.channel);
+        self.curve.temperature(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn celsius(temperature: TemperatureInterval) -> f32 {
+        temperature.get::<degree_celsius>()
+    }
+
+    #[test]
+    fn internal_sensor_follows_calibration() {
+        let calibration = InternalCalibration::default();
+
+        assert!((celsius(calibration.temperature(355)) - 25.2).abs() < 0.1);
+
+        let calibration = InternalCalibration {
+            offset: 300.0,
+            gain: 1.0,
+        };
+        assert_eq!(celsius(calibration.temperature(320)), 20.0);
+    }
+
+    #[test]
+    fn thermistor_reads_nominal_at_midpoint() {
+        let curve = ThermistorCurve::default();
+
+        let middle = celsius(curve.temperature(512).unwrap());
+        assert!((middle - 25.0).abs() < 0.1);
+
+        // NTC: colder means more resistance, so a higher reading
+        let cold = celsius(curve.temperature(700).unwrap());
+        assert!(cold < 10.0 && cold > 0.0);
+
+        assert_eq!(curve.temperature(0), None);
+        assert_eq!(curve.temperature(1023), None);
+    }
+}
@@ -0,0 +1,334 @@
+//! Alarms on [`CLOCK`], for work that should happen later or every so often.
+//!
+//! An alarm goes off in the `TIMER0_COMPA` interrupt, which only raises its
+//! flag. The main loop asks [`fired`] and does the work itself, so nothing
+//! slow ever runs with interrupts off.
+//!
+//! The interrupt only counts down to the next alarm due, and looks at the
+//! alarms themselves when that one is. With nothing due for a while it still
+//! looks every [`u16::MAX`] ticks, about 1.6 seconds.
+//!
+//! [`CLOCK`]: crate::clock::CLOCK
+
+use crate::{
+    clock::{Duration, Instant},
+    sync::Lock,
+};
+
+/// Alarms that can be set at once on [`TIMERS`]
+pub const ALARMS: usize = 8;
+
+/// Every alarm is set already.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Full;
+
+/// A set alarm, to ask about or cancel.
+///
+/// Once a one-shot alarm has fired and been asked about, or any alarm has been
+/// cancelled, its slot goes to the next alarm set. The handle knows which
+/// alarm it was for, so it can't be mixed up with the new one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Alarm {
+    slot: u8,
+    generation: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    generation: u8,
+    state: SlotState,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    Set {
+        /// [`CLOCK`](crate::clock::CLOCK) tick it goes off at
+        due: u64,
+        /// Ticks until it goes off again, if it does
+        period: Option<u64>,
+        /// Gone off since it was last asked about
+        fired: bool,
+    },
+    /// A one-shot alarm that went off, waiting to be asked about
+    Done,
+}
+
+struct State<const N: usize> {
+    slots: [Slot; N],
+    /// Ticks until the interrupt should look at the alarms again
+    countdown: u16,
+}
+
+/// A fixed number of alarms. The firmware has the one [`TIMERS`].
+pub struct Timers<const N: usize> {
+    state: Lock<State<N>>,
+}
+
+impl<const N: usize> Default for Timers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Timers<N> {
+    pub const fn new() -> Self {
+        Self {
+            state: Lock::new(State {
+                slots: [Slot {
+                    generation: 0,
+                    state: SlotState::Free,
+                }; N],
+                countdown: u16::MAX,
+            }),
+        }
+    }
+
+    /// Set an alarm to go off once, `delay` after `now`.
+    pub fn once(&self, now: Instant, delay: Duration) -> Result<Alarm, Full> {
+        self.set(now, delay, None)
+    }
+
+    /// Set an alarm to go off every `period` from `now` on. If the main loop
+    /// falls behind, it sees one firing for all the ones it missed.
+    pub fn every(&self, now: Instant, period: Duration) -> Result<Alarm, Full> {
+        // An alarm every tick would be all the interrupt does
+        let period = period.ticks().max(1);
+        self.set(now, Duration::from_ticks(period), Some(period))
+    }
+
+    fn set(&self, now: Instant, delay: Duration, period: Option<u64>) -> Result<Alarm, Full> {
+        self.state.lock(|state| {
+            let (index, slot) = state
+                .slots
+                .iter_mut()
+                .enumerate()
+                .find(|(_, slot)| slot.state == SlotState::Free)
+                .ok_or(Full)?;
+            slot.state = SlotState::Set {
+                due: now.ticks() + delay.ticks(),
+                period,
+                fired: false,
+            };
+            state.countdown = state.countdown.min(countdown(delay.ticks()));
+            Ok(Alarm {
+                slot: index as u8,
+                generation: slot.generation,
+            })
+        })
+    }
+
+    /// Whether `alarm` went off since it was last asked about. A one-shot
+    /// alarm that has fired is done with once this says so.
+    pub fn fired(&self, alarm: &Alarm) -> bool {
+        self.state.lock(|state| {
+            let Some(slot) = slot(state, alarm) else {
+                return false;
+            };
+            match &mut slot.state {
+                SlotState::Set { fired, .. } => core::mem::take(fired),
+                SlotState::Done => {
+                    free(slot);
+                    true
+                }
+                SlotState::Free => false,
+            }
+        })
+    }
+
+    /// Whether `alarm` is still to go off, or has gone off and not been asked
+    /// about.
+    pub fn is_set(&self, alarm: &Alarm) -> bool {
+        self.state
+            .lock(|state| slot(state, alarm).is_some_and(|slot| slot.state != SlotState::Free))
+    }
+
+    /// Stop `alarm` from going off again. Returns whether it was still set.
+    pub fn cancel(&self, alarm: Alarm) -> bool {
+        self.state.lock(|state| match slot(state, &alarm) {
+            Some(slot) if slot.state != SlotState::Free => {
+                free(slot);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    /// Count a tick of the clock, looking at the alarms if one may be due.
+    /// `now` is only read when it's needed.
+    pub fn tick(&self, now: impl FnOnce() -> Instant) {
+        self.state.lock(|state| {
+            state.countdown = state.countdown.saturating_sub(1);
+            if state.countdown == 0 {
+                state.countdown = fire(&mut state.slots, now().ticks());
+            }
+        });
+    }
+}
+
+/// Raise the flags of alarms due by `now` and set the periodic ones again.
+/// Returns the ticks until the next one is due.
+fn fire<const N: usize>(slots: &mut [Slot; N], now: u64) -> u16 {
+    let mut next = u16::MAX;
+    for slot in slots.iter_mut() {
+        let SlotState::Set { due, period, fired } = &mut slot.state else {
+            continue;
+        };
+        if *due <= now {
+            match *period {
+                Some(period) => {
+                    *fired = true;
+                    let late = now - *due;
+                    // Dividing a u64 is slow on the AVR, and this runs in the
+                    // clock's interrupt, so only catch up the long way after
+                    // missing whole periods. Either way it goes off once.
+                    *due += if late < period {
+                        period
+                    } else {
+                        late / period * period + period
+                    };
+                }
+                None => {
+                    slot.state = SlotState::Done;
+                    continue;
+                }
+            }
+        }
+        next = next.min(countdown(*due - now));
+    }
+    next
+}
+
+/// The countdown for something `ticks` away
+fn countdown(ticks: u64) -> u16 {
+    ticks.clamp(1, u16::MAX as u64) as u16
+}
+
+/// The slot `alarm` was set in, if it hasn't been reused since
+fn slot<'a, const N: usize>(state: &'a mut State<N>, alarm: &Alarm) -> Option<&'a mut Slot> {
+    state
+        .slots
+        .get_mut(alarm.slot as usize)
+        .filter(|slot| slot.generation == alarm.generation)
+}
+
+fn free(slot: &mut Slot) {
+    slot.state = SlotState::Free;
+    slot.generation = slot.generation.wrapping_add(1);
+}
+
+pub static TIMERS: Timers<ALARMS> = Timers::new();
+
+/// See [`Timers::once`], from now.
+pub fn once(delay: Duration) -> Result<Alarm, Full> {
+    TIMERS.once(crate::clock::CLOCK.now_instant(), delay)
+}
+
+/// See [`Timers::every`], from now.
+pub fn every(period: Duration) -> Result<Alarm, Full> {
+    TIMERS.every(crate::clock::CLOCK.now_instant(), period)
+}
+
+/// See [`Timers::fired`].
+pub fn fired(alarm: &Alarm) -> bool {
+    TIMERS.fired(alarm)
+}
+
+/// See [`Timers::cancel`].
+pub fn cancel(alarm: Alarm) -> bool {
+    TIMERS.cancel(alarm)
+}
+
+#[cfg(test)]
+mod tests {
+    use fugit::ExtU64;
+
+    use super::*;
+
+    /// Run `timers` for `ticks`, from `clock` on
+    fn run<const N: usize>(timers: &Timers<N>, clock: &mut u64, ticks: u64) {
+        for _ in 0..ticks {
+            *clock += 1;
+            timers.tick(|| Instant::from_ticks(*clock));
+        }
+    }
+
+    #[test]
+    fn one_shot_alarms_go_off_once() {
+        let timers = Timers::<2>::new();
+        let mut clock = 0;
+        let alarm = timers.once(Instant::from_ticks(0), 5.millis()).unwrap();
+
+        run(&timers, &mut clock, 199);
+        assert!(!timers.fired(&alarm));
+        run(&timers, &mut clock, 1);
+        assert!(timers.is_set(&alarm));
+        assert!(timers.fired(&alarm));
+        assert!(!timers.fired(&alarm));
+        assert!(!timers.is_set(&alarm));
+        assert!(!timers.cancel(alarm));
+    }
+
+    #[test]
+    fn periodic_alarms_keep_going_until_cancelled() {
+        let timers = Timers::<2>::new();
+        let mut clock = 0;
+        let alarm = timers.every(Instant::from_ticks(0), 1.millis()).unwrap();
+
+        for _ in 0..3 {
+            run(&timers, &mut clock, 39);
+            assert!(!timers.fired(&alarm));
+            run(&timers, &mut clock, 1);
+            assert!(timers.fired(&alarm));
+        }
+        // Falling behind shows up as one firing, and the period keeps time
+        run(&timers, &mut clock, 130);
+        assert!(timers.fired(&alarm));
+        assert!(!timers.fired(&alarm));
+        run(&timers, &mut clock, 30);
+        assert!(timers.fired(&alarm));
+
+        assert!(timers.cancel(alarm));
+        run(&timers, &mut clock, 40);
+        assert!(timers
+            .state
+            .lock(|state| state.slots.iter().all(|slot| slot.state == SlotState::Free)));
+    }
+
+    #[test]
+    fn late_periodic_alarms_skip_what_they_missed() {
+        let timers = Timers::<1>::new();
+        let alarm = timers.every(Instant::from_ticks(0), 1.millis()).unwrap();
+
+        // Due at 40, but the clock's interrupt was held off until 100
+        let next = timers.state.lock(|state| fire(&mut state.slots, 100));
+        assert_eq!(next, 20);
+        assert!(timers.fired(&alarm));
+        assert!(!timers.fired(&alarm));
+        // Back on time from there
+        assert_eq!(timers.state.lock(|state| fire(&mut state.slots, 120)), 40);
+        assert!(timers.fired(&alarm));
+    }
+
+    #[test]
+    fn alarms_far_off_and_slots_reused() {
+        let timers = Timers::<2>::new();
+        let mut clock = 0;
+        let far = timers.once(Instant::from_ticks(0), 5.secs()).unwrap();
+        let near = timers.once(Instant::from_ticks(0), 1.millis()).unwrap();
+        assert_eq!(timers.once(Instant::from_ticks(0), 1.millis()), Err(Full));
+
+        // The near one is cancelled, and its slot goes to a new alarm that
+        // the old handle knows nothing about
+        assert!(timers.cancel(near));
+        let reused = timers.once(Instant::from_ticks(0), 2.millis()).unwrap();
+        assert_eq!(reused.slot, 1);
+
+        run(&timers, &mut clock, 80);
+        assert!(timers.fired(&reused));
+        run(&timers, &mut clock, 5 * 40_000 - 81);
+        assert!(!timers.fired(&far));
+        run(&timers, &mut clock, 1);
+        assert!(timers.fired(&far));
+    }
+}
@@ -1,5 +1,8 @@
-use arduino_hal::{delay_ms, hal::port::PD3, prelude::*};
-#[cfg(not(feature = "servo"))]
+#[cfg(all(target_arch = "avr", feature = "ir-transmitter"))]
+use arduino_hal::hal::port::PD2;
+#[cfg(target_arch = "avr")]
+use arduino_hal::hal::port::PD3;
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
 use arduino_hal::{
     hal::port::PB0,
     port::{
@@ -8,18 +11,32 @@ use arduino_hal::{
     },
 };
 
-#[cfg(not(feature = "servo"))]
-use uom::si::{f32::TemperatureInterval, temperature_interval::degree_celsius};
+use ufmt::uWrite;
+use unwrap_infallible::UnwrapInfallible;
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
+use uom::si::temperature_interval::degree_celsius;
+use uom::si::{
+    angle::degree,
+    angular_velocity::degree_per_second,
+    f32::{Angle, AngularVelocity, TemperatureInterval},
+};
 
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
+use crate::hc_sr04::SetupError;
+#[cfg(all(target_arch = "avr", feature = "ir-transmitter"))]
+use crate::ir::IrTransmitter;
+#[cfg(target_arch = "avr")]
+use crate::{clock::SystemTimebase, hc_sr04::HcSr04, ir::IrRemote};
 use crate::{
-    hc_sr04::HcSr04,
-    ir::{self, fetch_message},
-    Serial,
+    hal::{Actuator, CommandSource, NoTransmitter, RangeSensor, Timebase, Transmitter},
+    ir::{IrCommand, KeyMap, Learned, Learner, Received},
+    motion::{Axis, MotionEngine, Segment},
+    range_map::{Bin, RangeMap, RangeMapError},
 };
-#[cfg(not(feature = "servo"))]
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
 use arduino_sys::Servo;
 
-#[cfg(feature = "servo")]
+#[cfg(all(target_arch = "avr", feature = "servo"))]
 use crate::servo::{Servo, ServoAttached};
 
 pub const PITCH_MOVE_SPEED: i16 = 8;
@@ -31,285 +48,1166 @@ pub const ROLL_STOP_SPEED: i16 = 90;
 pub const YAW_PRECISION: u16 = 75;
 pub const ROLL_PRECISION: u16 = 115;
 
+/// How long to give the pitch servo to reach each step
+pub const PITCH_SETTLE: u16 = 50;
+/// How long to hold a continuous servo at stop before it may move again
+pub const STOP_SETTLE: u16 = 5;
+
 pub const PITCH_MAX: i16 = 175;
 pub const PITCH_MIN: i16 = 10;
 
-#[cfg(feature = "servo")]
-mod builder;
+/// Pitch the turret starts out at, roughly level.
+pub const PITCH_START: i16 = 100;
 
-#[cfg(feature = "servo")]
-#[derive(Debug)]
-pub struct Turret {
-    /// Yaw Servo Motor (Horizontal))
-    yaw: Servo<ServoAttached>,
-    /// Pitch Servo Motor (Vertical)
-    pitch: Servo<ServoAttached>,
-    /// Roll Servo Motor (Fire)
-    roll: Servo<ServoAttached>,
+/// How fast the yaw servo turns the turret when driven at full speed, in
+/// degrees per second. This varies from servo to servo, so measure yours and
+/// pass it to [`Turret::set_yaw_rate`].
+pub const YAW_DEG_PER_SEC: f32 = 360.0;
 
-    /// Keep track of the current pitch value,
-    /// so we don't go too far.
-    pitch_value: i16,
+/// How long to hold the button for [`TurretAction::ToggleSentry`] to get into
+/// learn mode
+pub const LEARN_HOLD_MS: u32 = 3_000;
+
+/// How long learn mode waits for a button before giving up and keeping the
+/// key map it had
+pub const LEARN_TIMEOUT_MS: u32 = 15_000;
+
+/// How many poses can be saved with [`Turret::set_preset`], one per number
+/// button
+pub const PRESETS: usize = 10;
+
+/// Something the turret can be told to do, e.g. by a button on the remote.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum TurretAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    /// One dart
+    Fire,
+    /// Every dart
+    FireAll,
+    ToggleSentry,
+    /// Go to the pose saved with [`Turret::set_preset`]
+    Preset(u8),
+    /// Start or stop passing commands on to other turrets, see
+    /// [`Turret::set_leading`]
+    Broadcast,
+}
+
+impl TurretAction {
+    /// Up, down, left or right, which keep going while the button is held
+    pub fn is_movement(self) -> bool {
+        matches!(
+            self,
+            TurretAction::Up | TurretAction::Down | TurretAction::Left | TurretAction::Right
+        )
+    }
+
+    /// One byte per action, for saving key maps
+    pub fn to_byte(self) -> u8 {
+        match self {
+            TurretAction::Up => 0,
+            TurretAction::Down => 1,
+            TurretAction::Left => 2,
+            TurretAction::Right => 3,
+            TurretAction::Fire => 4,
+            TurretAction::FireAll => 5,
+            TurretAction::ToggleSentry => 6,
+            TurretAction::Broadcast => 7,
+            TurretAction::Preset(index) => 0x80 | index,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => TurretAction::Up,
+            1 => TurretAction::Down,
+            2 => TurretAction::Left,
+            3 => TurretAction::Right,
+            4 => TurretAction::Fire,
+            5 => TurretAction::FireAll,
+            6 => TurretAction::ToggleSentry,
+            7 => TurretAction::Broadcast,
+            0x80..=0xFF if ((byte & 0x7F) as usize) < PRESETS => TurretAction::Preset(byte & 0x7F),
+            _ => return None,
+        })
+    }
+}
 
-    #[allow(unused)]
-    range_finder: HcSr04<PD3>,
+/// A saved pose to return to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Preset {
+    pub yaw: Angle,
+    pub pitch: i16,
 }
 
-#[cfg(not(feature = "servo"))]
+mod broadcast;
+#[cfg(all(target_arch = "avr", feature = "servo"))]
+mod builder;
+mod hold;
+mod link;
+mod ranging;
+mod sentry;
+mod shell;
+mod yaw;
+
+pub use broadcast::{BroadcastTo, ECHO_MS};
+pub use hold::HoldConfig;
+pub use sentry::{closest_target, RangeReading, Sentry, SentryConfig, Target};
+pub(crate) use yaw::normalize;
+pub use yaw::{BadYawRate, YawEstimator};
+
+/// The pin the range finder's echo is wired to.
+#[cfg(all(target_arch = "avr", not(feature = "ir-transmitter")))]
+pub type EchoPin = PD3;
+
+/// The pin the range finder's echo is wired to. Timer2 can only drive the IR
+/// LED from D3 or D11, and D11 is the pitch servo's, so the echo moves to D2.
+#[cfg(all(target_arch = "avr", feature = "ir-transmitter"))]
+pub type EchoPin = PD2;
+
+/// What passes commands on to other turrets.
+#[cfg(all(target_arch = "avr", not(feature = "ir-transmitter")))]
+pub type AvrTransmitter = NoTransmitter;
+
+/// What passes commands on to other turrets: an IR LED on D3.
+#[cfg(all(target_arch = "avr", feature = "ir-transmitter"))]
+pub type AvrTransmitter = IrTransmitter<PD3>;
+
+/// The turret as it is wired up on the Nano.
+#[cfg(all(target_arch = "avr", feature = "servo"))]
+pub type AvrTurret = Turret<
+    Servo<ServoAttached>,
+    Servo<ServoAttached>,
+    Servo<ServoAttached>,
+    HcSr04<EchoPin>,
+    SystemTimebase,
+    IrRemote,
+    AvrTransmitter,
+>;
+
+/// The turret as it is wired up on the Nano.
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
+pub type AvrTurret =
+    Turret<Servo, Servo, Servo, HcSr04<EchoPin>, SystemTimebase, IrRemote, AvrTransmitter>;
+
 #[derive(Debug)]
-pub struct Turret {
+pub struct Turret<Y, P, R, S, T, C, X = NoTransmitter> {
     /// Yaw Servo Motor (Horizontal))
-    yaw: Servo,
+    yaw: Y,
     /// Pitch Servo Motor (Vertical)
-    pitch: Servo,
+    pitch: P,
     /// Roll Servo Motor (Fire)
-    roll: Servo,
+    roll: R,
 
     /// Keep track of the current pitch value,
     /// so we don't go too far.
     pitch_value: i16,
 
-    #[allow(unused)]
-    range_finder: HcSr04<PD3>,
+    /// Servo moves that are still to come
+    motion: MotionEngine,
+
+    /// Best guess at which way the turret is facing
+    yaw_estimate: YawEstimator,
+
+    /// Autonomous search and engage
+    sentry: Sentry,
+
+    range_finder: S,
+    /// Who asked for the ping in flight, if it was the shell or a host
+    range_request: Option<ranging::RangeRequest>,
+
+    timebase: T,
+    commands: C,
+    /// Passes commands on to other turrets
+    transmitter: X,
+    leader: broadcast::Leader,
+    /// What the buttons on each kind of remote do
+    keymap: KeyMap,
+    presets: [Option<Preset>; PRESETS],
+    /// Building a new key map, one button at a time
+    learning: Option<Learner>,
+    /// When learn mode last asked for a button
+    prompted_ms: u32,
+    /// A key map has been learned since [`Turret::take_keymap_learned`] was
+    /// last asked
+    keymap_learned: bool,
+    /// When the sentry button was pressed, while it is being held
+    hold_since: Option<u32>,
+    hold_config: HoldConfig,
+    /// The movement button being held down, if any
+    held: Option<hold::Hold>,
 }
 
-impl Turret {
-    #[cfg(feature = "servo")]
+#[cfg(all(target_arch = "avr", feature = "servo"))]
+impl AvrTurret {
     pub fn builder(
     ) -> builder::Builder<builder::NoYaw, builder::NoPitch, builder::NoRoll, builder::NoRangeFinder>
     {
         builder::Builder::default()
     }
+}
 
-    #[cfg(not(feature = "servo"))]
-    pub fn new(d8: Pin<Output, PB0>, d3: Pin<Input<Floating>, PD3>) -> Self {
+#[cfg(all(target_arch = "avr", not(feature = "servo")))]
+impl AvrTurret {
+    pub fn new(
+        d8: Pin<Output, PB0>,
+        echo: Pin<Input<Floating>, EchoPin>,
+        transmitter: AvrTransmitter,
+    ) -> Result<Self, SetupError> {
         let yaw = unsafe { Servo::new() };
         let pitch = unsafe { Servo::new() };
         let roll = unsafe { Servo::new() };
 
-        let range_finder = HcSr04::new(TemperatureInterval::new::<degree_celsius>(23.0), d8, d3);
+        let range_finder = HcSr04::new(TemperatureInterval::new::<degree_celsius>(23.0), d8, echo)?;
 
+        Ok(
+            Turret::from_parts(yaw, pitch, roll, range_finder, SystemTimebase, IrRemote)
+                .with_transmitter(transmitter),
+        )
+    }
+
+    pub fn attach(&mut self) {
+        unsafe { self.yaw.attach(10) };
+        unsafe { self.pitch.attach(11) };
+        unsafe { self.roll.attach(12) };
+    }
+}
+
+impl<Y, P, R, S, T, C> Turret<Y, P, R, S, T, C>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+{
+    /// Assemble a turret out of already initialized hardware.
+    pub fn from_parts(
+        yaw: Y,
+        pitch: P,
+        roll: R,
+        range_finder: S,
+        timebase: T,
+        commands: C,
+    ) -> Self {
         Self {
             yaw,
             pitch,
             roll,
 
-            pitch_value: 100,
+            pitch_value: PITCH_START,
+            motion: MotionEngine::new(),
+            yaw_estimate: YawEstimator::new(AngularVelocity::new::<degree_per_second>(
+                YAW_DEG_PER_SEC,
+            )),
+            sentry: Sentry::new(SentryConfig::default()),
             range_finder,
+            range_request: None,
+
+            timebase,
+            commands,
+            transmitter: NoTransmitter,
+            leader: broadcast::Leader::default(),
+            keymap: KeyMap::default(),
+            presets: [None; PRESETS],
+            learning: None,
+            prompted_ms: 0,
+            keymap_learned: false,
+            hold_since: None,
+            hold_config: HoldConfig::default(),
+            held: None,
         }
     }
 
-    #[cfg(not(feature = "servo"))]
-    pub fn attach(&mut self) {
-        unsafe { self.yaw.attach(10) };
-        unsafe { self.pitch.attach(11) };
-        unsafe { self.roll.attach(12) };
+    /// Give the turret a way to pass commands on to other turrets, see
+    /// [`Turret::set_leading`].
+    pub fn with_transmitter<X>(self, transmitter: X) -> Turret<Y, P, R, S, T, C, X>
+    where
+        X: Transmitter,
+    {
+        Turret {
+            yaw: self.yaw,
+            pitch: self.pitch,
+            roll: self.roll,
+            pitch_value: self.pitch_value,
+            motion: self.motion,
+            yaw_estimate: self.yaw_estimate,
+            sentry: self.sentry,
+            range_finder: self.range_finder,
+            range_request: self.range_request,
+            timebase: self.timebase,
+            commands: self.commands,
+            transmitter,
+            leader: self.leader,
+            keymap: self.keymap,
+            presets: self.presets,
+            learning: self.learning,
+            prompted_ms: self.prompted_ms,
+            keymap_learned: self.keymap_learned,
+            hold_since: self.hold_since,
+            hold_config: self.hold_config,
+            held: self.held,
+        }
     }
+}
 
-    pub fn move_up(&mut self, moves: u32, #[cfg(feature = "servo")] serial: &mut Serial) {
-        for _ in 0..moves {
-            if self.pitch_value > PITCH_MIN {
-                self.pitch_value -= PITCH_MOVE_SPEED;
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    /// Start whatever servo moves are due. Call this from the main loop as
+    /// often as possible.
+    pub fn poll(&mut self) {
+        let now = self.timebase.now_ms();
+        let Self {
+            yaw,
+            pitch,
+            roll,
+            motion,
+            yaw_estimate,
+            ..
+        } = self;
+        motion.poll(now, |axis, value| match axis {
+            Axis::Yaw => {
+                yaw.write(value);
+                yaw_estimate.set_speed(value, now);
+            }
+            Axis::Pitch => pitch.write(value),
+            Axis::Roll => roll.write(value),
+        });
 
-                #[cfg(feature = "servo")]
-                self.pitch.write(self.pitch_value as u8, serial);
+        self.poll_hold();
+        self.poll_sentry();
+    }
+
+    /// Whether any servo still has moves queued up or running
+    pub fn is_moving(&self) -> bool {
+        self.motion.is_busy()
+    }
 
-                #[cfg(not(feature = "servo"))]
-                unsafe {
-                    self.pitch.write(self.pitch_value)
-                };
-                delay_ms(50);
+    pub fn move_up(&mut self, moves: u32) {
+        for _ in 0..moves {
+            if self.pitch_value > PITCH_MIN {
+                let value = (self.pitch_value - PITCH_MOVE_SPEED).max(PITCH_MIN);
+                if !self.step_pitch(value) {
+                    break;
+                }
             }
         }
     }
 
-    pub fn move_down(&mut self, moves: u32, #[cfg(feature = "servo")] serial: &mut Serial) {
+    pub fn move_down(&mut self, moves: u32) {
         for _ in 0..moves {
             if self.pitch_value < PITCH_MAX {
-                self.pitch_value += PITCH_MOVE_SPEED;
-
-                #[cfg(feature = "servo")]
-                self.pitch.write(self.pitch_value as u8, serial);
-
-                #[cfg(not(feature = "servo"))]
-                unsafe {
-                    self.pitch.write(self.pitch_value)
-                };
-                delay_ms(50);
+                let value = (self.pitch_value + PITCH_MOVE_SPEED).min(PITCH_MAX);
+                if !self.step_pitch(value) {
+                    break;
+                }
             }
         }
     }
 
-    pub fn move_left(&mut self, moves: u32, #[cfg(feature = "servo")] serial: &mut Serial) {
+    pub fn move_left(&mut self, moves: u32) {
         for _ in 0..moves {
-            #[cfg(feature = "servo")]
-            self.yaw
-                .write((YAW_STOP_SPEED + YAW_MOVE_SPEED) as u8, serial);
-
-            #[cfg(not(feature = "servo"))]
-            unsafe {
-                self.yaw.write(YAW_STOP_SPEED + YAW_MOVE_SPEED)
-            };
-            delay_ms(YAW_PRECISION);
-
-            #[cfg(feature = "servo")]
-            self.yaw.write(YAW_STOP_SPEED as u8, serial);
-
-            #[cfg(not(feature = "servo"))]
-            unsafe {
-                self.yaw.write(YAW_STOP_SPEED)
-            };
-
-            delay_ms(5);
+            if !self.pulse(Axis::Yaw, YAW_STOP_SPEED + YAW_MOVE_SPEED, YAW_PRECISION) {
+                break;
+            }
         }
     }
 
-    pub fn move_right(&mut self, moves: u32, #[cfg(feature = "servo")] serial: &mut Serial) {
+    pub fn move_right(&mut self, moves: u32) {
         for _ in 0..moves {
-            #[cfg(feature = "servo")]
-            self.roll
-                .write((YAW_STOP_SPEED - YAW_MOVE_SPEED) as u8, serial);
+            if !self.pulse(Axis::Yaw, YAW_STOP_SPEED - YAW_MOVE_SPEED, YAW_PRECISION) {
+                break;
+            }
+        }
+    }
 
-            #[cfg(not(feature = "servo"))]
-            unsafe {
-                self.yaw.write(YAW_STOP_SPEED - YAW_MOVE_SPEED)
-            };
+    /// Queue up one dart. Returns `false`, firing nothing, if the roll
+    /// servo's queue is full.
+    pub fn fire(&mut self) -> bool {
+        self.pulse(
+            Axis::Roll,
+            ROLL_STOP_SPEED - ROLL_MOVE_SPEED,
+            ROLL_PRECISION,
+        )
+    }
 
-            delay_ms(YAW_PRECISION);
+    /// Queue up every dart, or return `false` like [`Turret::fire`].
+    pub fn fire_all(&mut self) -> bool {
+        self.pulse(
+            Axis::Roll,
+            ROLL_STOP_SPEED - ROLL_MOVE_SPEED,
+            ROLL_PRECISION * 6,
+        )
+    }
 
-            #[cfg(feature = "servo")]
-            self.roll.write(YAW_STOP_SPEED as u8, serial);
+    /// Which way the turret is facing, going by how long the yaw servo has been
+    /// told to turn for. Positive is to the left.
+    pub fn yaw_estimate(&self) -> Angle {
+        self.yaw_estimate.angle(self.timebase.now_ms())
+    }
 
-            #[cfg(not(feature = "servo"))]
-            unsafe {
-                self.yaw.write(YAW_STOP_SPEED)
-            };
+    /// How fast the yaw servo is taken to turn at full speed.
+    pub fn yaw_rate(&self) -> AngularVelocity {
+        self.yaw_estimate.rate()
+    }
 
-            delay_ms(5);
-        }
+    /// Calibrate how fast the yaw servo turns at full speed.
+    ///
+    /// Fails, keeping the old calibration, unless `rate` is positive.
+    pub fn set_yaw_rate(&mut self, rate: AngularVelocity) -> Result<(), BadYawRate> {
+        let now = self.timebase.now_ms();
+        self.yaw_estimate.set_rate(rate, now)
     }
 
-    pub fn fire(&mut self, #[cfg(feature = "servo")] serial: &mut Serial) {
-        #[cfg(feature = "servo")]
-        self.roll
-            .write((ROLL_STOP_SPEED - ROLL_MOVE_SPEED) as u8, serial);
+    /// Declare the turret to currently be facing `angle`.
+    pub fn reset_yaw(&mut self, angle: Angle) {
+        let now = self.timebase.now_ms();
+        self.yaw_estimate.reset(angle, now);
+    }
 
-        #[cfg(not(feature = "servo"))]
-        unsafe {
-            self.roll.write(ROLL_STOP_SPEED - ROLL_MOVE_SPEED)
-        };
+    /// Where the yaw estimate will end up once every queued move has run, in
+    /// degrees.
+    pub fn planned_yaw(&self) -> f32 {
+        let now = self.timebase.now_ms();
+        let queued: f32 = self
+            .motion
+            .pending(Axis::Yaw, now)
+            .map(|segment| self.yaw_estimate.travel(segment.value, segment.duration_ms))
+            .sum();
+        yaw::normalize(self.yaw_estimate.angle(now).get::<degree>() + queued)
+    }
 
-        delay_ms(ROLL_PRECISION);
+    /// Turn the shortest way round to face `angle`, after whatever yaw moves
+    /// are already queued.
+    pub fn turn_to(&mut self, angle: Angle) {
+        let delta = yaw::normalize(angle.get::<degree>() - self.planned_yaw());
+        let Some((speed, mut duration_ms)) = self.yaw_estimate.pulse_for(delta) else {
+            return;
+        };
+        while duration_ms > 0 {
+            let chunk = duration_ms.min(u16::MAX as u32) as u16;
+            if !self.pulse(Axis::Yaw, speed, chunk) {
+                break;
+            }
+            duration_ms -= chunk as u32;
+        }
+    }
 
-        #[cfg(feature = "servo")]
-        self.roll.write(ROLL_STOP_SPEED as u8, serial);
+    /// Queue a move of the pitch servo to `value`.
+    ///
+    /// Returns `false` if there was no room left to queue it.
+    fn step_pitch(&mut self, value: i16) -> bool {
+        let queued = self
+            .motion
+            .enqueue(Axis::Pitch, Segment::new(value, PITCH_SETTLE))
+            .is_ok();
+        if queued {
+            self.pitch_value = value;
+        }
+        queued
+    }
 
-        #[cfg(not(feature = "servo"))]
-        unsafe {
-            self.roll.write(ROLL_STOP_SPEED)
+    /// Queue a spin of a continuous servo at `speed` for `duration_ms`,
+    /// followed by a stop.
+    ///
+    /// Returns `false` if there was no room left to queue it.
+    fn pulse(&mut self, axis: Axis, speed: i16, duration_ms: u16) -> bool {
+        if self.motion.free(axis) < 2 {
+            return false;
+        }
+        let stop = match axis {
+            Axis::Roll => ROLL_STOP_SPEED,
+            _ => YAW_STOP_SPEED,
         };
-        delay_ms(5);
+        // There is room for both, so neither of these can fail
+        let _ = self.motion.enqueue(axis, Segment::new(speed, duration_ms));
+        let _ = self.motion.enqueue(axis, Segment::new(stop, STOP_SETTLE));
+        true
     }
 
-    pub fn fire_all(&mut self, #[cfg(feature = "servo")] serial: &mut Serial) {
-        #[cfg(feature = "servo")]
-        self.roll
-            .write((ROLL_STOP_SPEED - ROLL_MOVE_SPEED) as u8, serial);
+    /// Act on the oldest command from the remote, if there is one, and
+    /// return it.
+    pub fn handle_command<W>(&mut self, serial: &mut W) -> Option<Received>
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        self.report_overflows();
+        self.give_up_learning(serial);
+        let received = self.commands.next_command()?;
+        self.dispatch(received, serial);
+        Some(received)
+    }
 
-        #[cfg(not(feature = "servo"))]
-        unsafe {
-            self.roll.write(ROLL_STOP_SPEED - ROLL_MOVE_SPEED)
-        };
+    /// Act on every command waiting, oldest first.
+    pub fn handle_commands<W>(&mut self, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        self.report_overflows();
+        self.give_up_learning(serial);
+        while let Some(received) = self.commands.next_command() {
+            self.dispatch(received, serial);
+        }
+    }
 
-        delay_ms(ROLL_PRECISION * 6);
+    fn report_overflows(&mut self) {
+        let lost = self.commands.take_overflows();
+        if lost > 0 {
+            crate::warn!("Lost {} commands", lost);
+        }
+    }
 
-        #[cfg(feature = "servo")]
-        self.roll.write(ROLL_STOP_SPEED as u8, serial);
+    fn dispatch<W>(&mut self, received: Received, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        if self.is_echo(&received) {
+            return;
+        }
+        let cmd = received.cmd;
+        crate::debug!(
+            "Command(Proto: {:?}, Addr: {}, Cmd: {}, Rpt: {})",
+            cmd.protocol,
+            cmd.addr,
+            cmd.cmd,
+            cmd.repeat
+        );
+        if self.learning.is_some() {
+            self.learn(&cmd, serial);
+            return;
+        }
 
-        #[cfg(not(feature = "servo"))]
-        unsafe {
-            self.roll.write(ROLL_STOP_SPEED)
-        };
-        delay_ms(5);
-    }
-
-    pub fn handle_command(&mut self, serial: &mut Serial) {
-        if let Some(cmd) = fetch_message() {
-            ufmt::uwriteln!(
-                serial,
-                "Command(Addr: {}, Cmd: {}, Rpt: {})",
-                cmd.addr,
-                cmd.cmd,
-                cmd.repeat
-            )
-            .unwrap_infallible();
-            match cmd.cmd {
-                ir::UP => {
-                    ufmt::uwriteln!(serial, "UP").unwrap_infallible();
-                    self.move_up(
-                        1,
-                        #[cfg(feature = "servo")]
-                        serial,
-                    );
+        let action = self.keymap.action(&cmd);
+        if action != Some(TurretAction::ToggleSentry) {
+            self.hold_since = None;
+        }
+        if !action.is_some_and(TurretAction::is_movement) {
+            self.release();
+        }
+        if let Some(action) = action {
+            if self.is_leading() && !cmd.repeat && action != TurretAction::Broadcast {
+                self.relay(action);
+            }
+        }
+        match action {
+            Some(
+                movement @ (TurretAction::Up
+                | TurretAction::Down
+                | TurretAction::Left
+                | TurretAction::Right),
+            ) => {
+                if !cmd.repeat {
+                    ufmt::uwriteln!(serial, "{:?}", movement).unwrap_infallible();
                 }
-                ir::DOWN => {
-                    ufmt::uwriteln!(serial, "DOWN").unwrap_infallible();
-                    self.move_down(
-                        1,
-                        #[cfg(feature = "servo")]
-                        serial,
-                    );
+                self.press_movement(movement, cmd.repeat, received.at_ms);
+            }
+            Some(TurretAction::Fire) if cmd.repeat => {
+                ufmt::uwriteln!(serial, "Too soon").unwrap_infallible();
+            }
+            Some(TurretAction::ToggleSentry) => {
+                let now = received.at_ms;
+                if cmd.repeat {
+                    let held = self.hold_since.map(|since| now.wrapping_sub(since));
+                    if held.is_some_and(|held| held >= LEARN_HOLD_MS) {
+                        self.start_learning(serial);
+                    }
+                } else {
+                    self.hold_since = Some(now);
+                    self.perform(TurretAction::ToggleSentry, serial);
                 }
-                ir::LEFT => {
-                    ufmt::uwriteln!(serial, "LEFT").unwrap_infallible();
-                    self.move_left(
-                        1,
-                        #[cfg(feature = "servo")]
-                        serial,
-                    );
+            }
+            Some(action) => {
+                if !cmd.repeat {
+                    self.perform(action, serial);
                 }
-                ir::RIGHT => {
-                    ufmt::uwriteln!(serial, "RIGHT").unwrap_infallible();
-                    self.move_right(
-                        1,
-                        #[cfg(feature = "servo")]
-                        serial,
-                    );
+            }
+            None => {
+                ufmt::uwriteln!(serial, "Unknown").unwrap_infallible();
+            }
+        };
+    }
+
+    /// Do what one press of the button for `action` would, reporting it on
+    /// `serial`.
+    pub fn perform<W>(&mut self, action: TurretAction, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        if action.is_movement() {
+            ufmt::uwriteln!(serial, "{:?}", action).unwrap_infallible();
+        }
+        match action {
+            TurretAction::Up => self.move_up(1),
+            TurretAction::Down => self.move_down(1),
+            TurretAction::Left => self.move_left(1),
+            TurretAction::Right => self.move_right(1),
+            TurretAction::Fire => {
+                if self.fire() {
+                    ufmt::uwriteln!(serial, "FIRE").unwrap_infallible();
+                } else {
+                    ufmt::uwriteln!(serial, "Busy: too many shots queued").unwrap_infallible();
                 }
-                ir::OK => {
-                    if !cmd.repeat {
-                        self.fire(
-                            #[cfg(feature = "servo")]
-                            serial,
-                        );
-                        ufmt::uwriteln!(serial, "FIRE").unwrap_infallible();
-                    } else {
-                        ufmt::uwriteln!(serial, "Too soon").unwrap_infallible();
-                    }
+            }
+            TurretAction::FireAll => {
+                if self.fire_all() {
+                    ufmt::uwriteln!(serial, "BLASTOFF").unwrap_infallible();
+                } else {
+                    ufmt::uwriteln!(serial, "Busy: too many shots queued").unwrap_infallible();
                 }
-                ir::STAR => {
-                    if !cmd.repeat {
-                        ufmt::uwriteln!(serial, "BLASTOFF").unwrap_infallible();
-                        self.fire_all(
-                            #[cfg(feature = "servo")]
-                            serial,
-                        );
-                    }
+            }
+            TurretAction::ToggleSentry => {
+                self.toggle_sentry();
+                if self.sentry.is_active() {
+                    ufmt::uwriteln!(serial, "SENTRY ON").unwrap_infallible();
+                } else {
+                    ufmt::uwriteln!(serial, "SENTRY OFF").unwrap_infallible();
                 }
-                _ => {
-                    ufmt::uwriteln!(serial, "Unknown").unwrap_infallible();
+            }
+            TurretAction::Preset(index) => {
+                if self.go_to_preset(index as usize) {
+                    ufmt::uwriteln!(serial, "PRESET {}", index).unwrap_infallible();
+                } else {
+                    ufmt::uwriteln!(serial, "No preset {}", index).unwrap_infallible();
                 }
-            };
+            }
+            TurretAction::Broadcast => {
+                self.set_leading(!self.is_leading());
+                if self.is_leading() {
+                    ufmt::uwriteln!(serial, "LEADING ON").unwrap_infallible();
+                } else {
+                    ufmt::uwriteln!(serial, "LEADING OFF").unwrap_infallible();
+                }
+            }
         }
     }
 
+    /// Forget the key map and ask for each button again, see [`Learner`]. The
+    /// prompts go to `serial`.
+    pub fn start_learning<W>(&mut self, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        if self.sentry.is_active() {
+            self.toggle_sentry();
+        }
+        self.hold_since = None;
+        self.prompted_ms = self.timebase.now_ms();
+        let learner = Learner::new();
+        if let Some(action) = learner.prompt() {
+            ufmt::uwriteln!(serial, "LEARN: press the button for {:?}", action).unwrap_infallible();
+        }
+        self.learning = Some(learner);
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learning.is_some()
+    }
+
+    /// Whether learn mode has finished with a new key map since this was last
+    /// asked, i.e. it is time to save [`Turret::keymap`].
+    pub fn take_keymap_learned(&mut self) -> bool {
+        core::mem::take(&mut self.keymap_learned)
+    }
+
+    fn learn<W>(&mut self, cmd: &IrCommand, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        let Some(learner) = self.learning.as_mut() else {
+            return;
+        };
+        match learner.feed(cmd) {
+            Some(Learned::Bound(action)) => {
+                ufmt::uwriteln!(serial, "Bound {:?}", action).unwrap_infallible()
+            }
+            Some(Learned::Skipped(action)) => {
+                ufmt::uwriteln!(serial, "Skipped {:?}", action).unwrap_infallible()
+            }
+            None => return,
+        }
+
+        self.prompted_ms = self.timebase.now_ms();
+        if let Some(action) = learner.prompt() {
+            ufmt::uwriteln!(serial, "LEARN: press the button for {:?}", action).unwrap_infallible();
+        } else if let Some(learner) = self.learning.take() {
+            self.keymap = learner.into_keymap();
+            self.keymap_learned = true;
+            ufmt::uwriteln!(serial, "LEARNED").unwrap_infallible();
+        }
+    }
+
+    /// Leave learn mode if nobody has pressed a button for
+    /// [`LEARN_TIMEOUT_MS`], keeping the old key map.
+    fn give_up_learning<W>(&mut self, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        let waited = self.timebase.now_ms().wrapping_sub(self.prompted_ms);
+        if self.learning.is_some() && waited >= LEARN_TIMEOUT_MS {
+            self.learning = None;
+            ufmt::uwriteln!(serial, "LEARN: timed out").unwrap_infallible();
+        }
+    }
+
+    /// Save a pose for [`TurretAction::Preset`] to go to, or forget it for
+    /// `None`. Indices past [`PRESETS`] are ignored.
+    pub fn set_preset(&mut self, index: usize, preset: Option<Preset>) {
+        if let Some(slot) = self.presets.get_mut(index) {
+            *slot = preset;
+        }
+    }
+
+    pub fn preset(&self, index: usize) -> Option<Preset> {
+        self.presets.get(index).copied().flatten()
+    }
+
+    /// Turn and pitch to a saved pose. Returns `false` if there is none.
+    pub fn go_to_preset(&mut self, index: usize) -> bool {
+        let Some(preset) = self.preset(index) else {
+            return false;
+        };
+        self.turn_to(preset.yaw);
+        self.pitch_to(preset.pitch);
+        true
+    }
+
+    /// Current pitch servo angle
+    pub fn pitch_value(&self) -> i16 {
+        self.pitch_value
+    }
+
+    /// Tell the range finder how warm the air is, so its distances stay right.
+    pub fn set_temperature(&mut self, temperature: TemperatureInterval) {
+        self.range_finder.set_temperature(temperature);
+    }
+
+    /// Take a reading where the turret is pointing and file it in `map`.
+    pub fn scan_into<const N: usize>(
+        &mut self,
+        map: &mut RangeMap<N>,
+    ) -> Result<Bin, RangeMapError> {
+        let yaw = self.yaw_estimate();
+        map.scan(&mut self.range_finder, yaw, self.pitch_value)
+    }
+
+    pub fn keymap(&self) -> &KeyMap {
+        &self.keymap
+    }
+
+    /// Change what the remote's buttons do, e.g. to add another remote.
+    pub fn keymap_mut(&mut self) -> &mut KeyMap {
+        &mut self.keymap
+    }
+
+    pub fn commands_mut(&mut self) -> &mut C {
+        &mut self.commands
+    }
+
     #[allow(dead_code)]
-    pub fn range_finder(&self) -> &HcSr04<PD3> {
+    pub fn range_finder(&self) -> &S {
         &self.range_finder
     }
 
     #[allow(dead_code)]
-    pub fn range_finder_mut(&mut self) -> &mut HcSr04<PD3> {
+    pub fn range_finder_mut(&mut self) -> &mut S {
         &mut self.range_finder
     }
 }
+
+/// A turret built from the [`host`](crate::hal::host) stand-ins, for the
+/// tests here and in the submodules.
+#[cfg(test)]
+pub(super) mod test_support {
+    use ufmt::uWrite;
+
+    use super::{NoTransmitter, Turret};
+    use crate::hal::{
+        host::{ManualTimebase, QueuedCommands, RecordingActuator, ScriptedRangeSensor},
+        Transmitter,
+    };
+
+    pub(super) type HostTurret<X = NoTransmitter> = Turret<
+        RecordingActuator,
+        RecordingActuator,
+        RecordingActuator,
+        ScriptedRangeSensor,
+        ManualTimebase,
+        QueuedCommands,
+        X,
+    >;
+
+    /// Serial port that throws everything away
+    pub(super) struct Sink;
+
+    impl uWrite for Sink {
+        type Error = core::convert::Infallible;
+
+        fn write_str(&mut self, _: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    pub(super) fn turret() -> HostTurret {
+        Turret::from_parts(
+            RecordingActuator::new(),
+            RecordingActuator::new(),
+            RecordingActuator::new(),
+            ScriptedRangeSensor::new(),
+            ManualTimebase::new(),
+            QueuedCommands::new(),
+        )
+    }
+
+    /// Keep the clock running until every queued move is done
+    pub(super) fn settle<X: Transmitter>(turret: &mut HostTurret<X>) {
+        turret.poll();
+        while turret.is_moving() {
+            turret.timebase.advance(1);
+            turret.poll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use infrared::protocol::nec::NecCommand;
+
+    use super::{
+        test_support::{settle, turret, HostTurret, Sink},
+        *,
+    };
+    use crate::ir::{self, Key, Protocol};
+
+    #[test]
+    fn move_up_lowers_pitch_value() {
+        let mut turret = turret();
+        turret.move_up(2);
+        settle(&mut turret);
+
+        assert_eq!(
+            turret.pitch.writes(),
+            &[
+                PITCH_START - PITCH_MOVE_SPEED,
+                PITCH_START - 2 * PITCH_MOVE_SPEED
+            ]
+        );
+        assert_eq!(turret.pitch_value(), PITCH_START - 2 * PITCH_MOVE_SPEED);
+        assert_eq!(turret.timebase.now_ms(), 100);
+    }
+
+    #[test]
+    fn pitch_stays_within_limits() {
+        let mut turret = turret();
+
+        turret.move_up(100);
+        settle(&mut turret);
+        assert_eq!(turret.pitch_value(), PITCH_MIN);
+        assert!(turret.pitch.writes().iter().all(|&v| v >= PITCH_MIN));
+
+        turret.pitch.clear();
+        turret.move_up(1);
+        settle(&mut turret);
+        assert!(turret.pitch.writes().is_empty());
+
+        // More steps than fit in the queue at once
+        for _ in 0..3 {
+            turret.move_down(10);
+            settle(&mut turret);
+        }
+        assert_eq!(turret.pitch_value(), PITCH_MAX);
+        assert!(turret.pitch.writes().iter().all(|&v| v <= PITCH_MAX));
+    }
+
+    #[test]
+    fn move_left_and_right_pulse_yaw() {
+        let mut turret = turret();
+
+        turret.move_left(1);
+        settle(&mut turret);
+        assert_eq!(
+            turret.yaw.writes(),
+            &[YAW_STOP_SPEED + YAW_MOVE_SPEED, YAW_STOP_SPEED]
+        );
+        assert_eq!(turret.timebase.now_ms(), YAW_PRECISION as u32 + 5);
+
+        turret.yaw.clear();
+        turret.move_right(1);
+        settle(&mut turret);
+        assert_eq!(
+            turret.yaw.writes(),
+            &[YAW_STOP_SPEED - YAW_MOVE_SPEED, YAW_STOP_SPEED]
+        );
+        assert!(turret.roll.writes().is_empty());
+    }
+
+    #[test]
+    fn fire_spins_roll_for_one_dart() {
+        let mut turret = turret();
+
+        turret.fire();
+        settle(&mut turret);
+        assert_eq!(
+            turret.roll.writes(),
+            &[ROLL_STOP_SPEED - ROLL_MOVE_SPEED, ROLL_STOP_SPEED]
+        );
+        assert_eq!(turret.timebase.now_ms(), ROLL_PRECISION as u32 + 5);
+    }
+
+    #[test]
+    fn handle_command_dispatches_buttons() {
+        let mut turret = turret();
+        turret.commands.press(ir::UP);
+        turret.commands.press(ir::LEFT);
+        turret.commands.press(ir::OK);
+
+        for _ in 0..3 {
+            turret.handle_command(&mut Sink);
+        }
+        settle(&mut turret);
+
+        assert_eq!(turret.pitch_value(), PITCH_START - PITCH_MOVE_SPEED);
+        assert_eq!(turret.yaw.writes().len(), 2);
+        assert_eq!(turret.roll.writes().len(), 2);
+        assert!(turret.commands.is_empty());
+    }
+
+    #[test]
+    fn handle_command_ignores_repeated_fire() {
+        let mut turret = turret();
+        turret.commands.push(NecCommand {
+            addr: 0,
+            cmd: ir::OK,
+            repeat: true,
+        });
+
+        turret.handle_command(&mut Sink);
+        settle(&mut turret);
+
+        assert!(turret.roll.writes().is_empty());
+    }
+
+    #[test]
+    fn handle_command_looks_up_protocol_and_address() {
+        let mut turret = turret();
+        let samsung = Key {
+            protocol: Protocol::Samsung,
+            addr: 7,
+            cmd: 0x12,
+        };
+        turret.keymap_mut().bind(samsung, TurretAction::Up).unwrap();
+        let press = |key: Key| IrCommand {
+            protocol: key.protocol,
+            addr: key.addr,
+            cmd: key.cmd,
+            repeat: false,
+        };
+        turret.commands.push(press(samsung));
+        // The HackPack's up button, but from some other NEC remote
+        turret.commands.push(press(Key {
+            protocol: Protocol::Nec,
+            addr: 0x04,
+            cmd: ir::UP,
+        }));
+
+        turret.handle_command(&mut Sink);
+        turret.handle_command(&mut Sink);
+        settle(&mut turret);
+
+        assert_eq!(turret.pitch_value(), PITCH_START - PITCH_MOVE_SPEED);
+    }
+
+    #[test]
+    fn holding_the_sentry_button_learns_a_new_remote() {
+        let mut turret = turret();
+        let sony = |cmd, repeat| IrCommand {
+            protocol: Protocol::Sirc,
+            addr: 1,
+            cmd,
+            repeat,
+        };
+        let hashtag = |turret: &mut HostTurret, repeat, at_ms| {
+            let cmd = NecCommand {
+                addr: 0,
+                cmd: ir::HASHTAG,
+                repeat,
+            };
+            turret.commands.push_at(cmd, at_ms);
+            turret.handle_command(&mut Sink);
+        };
+
+        hashtag(&mut turret, false, 100);
+        hashtag(&mut turret, true, 100 + LEARN_HOLD_MS / 2);
+        assert!(!turret.is_learning());
+        hashtag(&mut turret, true, 100 + LEARN_HOLD_MS);
+        assert!(turret.is_learning());
+        assert!(!turret.sentry().is_active());
+
+        // Bind up, then skip everything else
+        turret.commands.push(sony(18, false));
+        turret.handle_command(&mut Sink);
+        for _ in 1..ir::LEARNABLE.len() {
+            turret.commands.push(sony(18, false));
+            turret.handle_command(&mut Sink);
+        }
+        assert!(!turret.is_learning());
+        assert!(turret.take_keymap_learned());
+        assert!(!turret.take_keymap_learned());
+
+        // The HackPack remote is forgotten
+        turret.commands.press(ir::UP);
+        turret.commands.push(sony(18, false));
+        turret.handle_command(&mut Sink);
+        turret.handle_command(&mut Sink);
+        settle(&mut turret);
+        assert_eq!(turret.pitch_value(), PITCH_START - PITCH_MOVE_SPEED);
+    }
+
+    #[test]
+    fn learn_mode_gives_up_when_nothing_is_pressed() {
+        let mut turret = turret();
+        turret.start_learning(&mut Sink);
+        turret.timebase.advance(LEARN_TIMEOUT_MS - 1);
+        turret.handle_commands(&mut Sink);
+        assert!(turret.is_learning());
+
+        turret.timebase.advance(1);
+        turret.handle_commands(&mut Sink);
+        assert!(!turret.is_learning());
+        assert!(!turret.take_keymap_learned());
+
+        // The HackPack remote still works
+        turret.commands.press(ir::UP);
+        turret.handle_command(&mut Sink);
+        settle(&mut turret);
+        assert_eq!(turret.pitch_value(), PITCH_START - PITCH_MOVE_SPEED);
+    }
+
+    #[test]
+    fn only_saved_presets_come_back_from_bytes() {
+        let last = TurretAction::Preset(PRESETS as u8 - 1);
+        assert_eq!(TurretAction::from_byte(last.to_byte()), Some(last));
+        assert_eq!(TurretAction::from_byte(0x80 | PRESETS as u8), None);
+        assert_eq!(TurretAction::from_byte(0xFF), None);
+    }
+
+    #[test]
+    fn handle_commands_drains_the_queue() {
+        let mut turret = turret();
+        for _ in 0..3 {
+            turret.commands.press(ir::UP);
+        }
+
+        turret.handle_commands(&mut Sink);
+        settle(&mut turret);
+
+        assert!(turret.commands.is_empty());
+        assert_eq!(turret.pitch_value(), PITCH_START - 3 * PITCH_MOVE_SPEED);
+    }
+
+    #[test]
+    fn number_buttons_go_to_presets() {
+        let mut turret = turret();
+        turret.set_preset(
+            3,
+            Some(Preset {
+                yaw: Angle::new::<degree>(90.0),
+                pitch: 60,
+            }),
+        );
+        turret.commands.press(ir::CMD1);
+        turret.commands.press(ir::CMD3);
+
+        turret.handle_command(&mut Sink);
+        assert!(!turret.is_moving());
+        turret.handle_command(&mut Sink);
+        settle(&mut turret);
+
+        assert_eq!(turret.pitch_value(), 60);
+        assert!((yaw_degrees(&turret) - 90.0).abs() < 1.0);
+    }
+
+    fn yaw_degrees(turret: &HostTurret) -> f32 {
+        turret.yaw_estimate().get::<degree>()
+    }
+
+    #[test]
+    fn yaw_estimate_follows_moves() {
+        let mut turret = turret();
+
+        turret.move_left(2);
+        settle(&mut turret);
+        let step = YAW_DEG_PER_SEC * YAW_PRECISION as f32 / 1_000.0;
+        assert!((yaw_degrees(&turret) - 2.0 * step).abs() < 0.01);
+
+        turret.move_right(3);
+        settle(&mut turret);
+        assert!((yaw_degrees(&turret) + step).abs() < 0.01);
+    }
+
+    #[test]
+    fn turn_to_goes_the_short_way() {
+        let mut turret = turret();
+        turret.reset_yaw(Angle::new::<degree>(170.0));
+
+        turret.turn_to(Angle::new::<degree>(-170.0));
+        assert!((turret.planned_yaw() + 170.0).abs() < 0.5);
+        settle(&mut turret);
+
+        assert_eq!(turret.yaw.writes()[0], YAW_STOP_SPEED + YAW_MOVE_SPEED);
+        assert!((yaw_degrees(&turret) + 170.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn turn_to_accounts_for_queued_moves() {
+        let mut turret = turret();
+        turret
+            .set_yaw_rate(AngularVelocity::new::<degree_per_second>(180.0))
+            .unwrap();
+
+        turret.move_left(1);
+        turret.turn_to(Angle::new::<degree>(0.0));
+        settle(&mut turret);
+
+        assert!(yaw_degrees(&turret).abs() < 0.5);
+    }
+
+    #[test]
+    fn handle_command_does_not_block() {
+        let mut turret = turret();
+        turret.commands.press(ir::STAR);
+
+        turret.handle_command(&mut Sink);
+
+        assert_eq!(turret.timebase.now_ms(), 0);
+        assert!(turret.is_moving());
+    }
+
+    #[test]
+    fn axes_move_at_the_same_time() {
+        let mut turret = turret();
+
+        turret.move_left(1);
+        turret.move_up(1);
+        turret.fire();
+        settle(&mut turret);
+
+        assert_eq!(
+            turret.timebase.now_ms(),
+            (ROLL_PRECISION + STOP_SETTLE) as u32
+        );
+        assert_eq!(turret.yaw.writes().len(), 2);
+        assert_eq!(turret.pitch.writes().len(), 1);
+        assert_eq!(turret.roll.writes().len(), 2);
+    }
+}
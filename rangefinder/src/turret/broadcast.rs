@@ -0,0 +1,209 @@
+//! Leading other turrets by passing on what this one is told to do.
+//!
+//! While leading, every button press the turret acts on is sent out again as
+//! the HackPack remote's button for the same action. Followers on the default
+//! key map react as if they had seen the remote themselves. Followers set up
+//! with [`KeyMap::hackpack`](crate::ir::KeyMap::hackpack) only react to a
+//! leader sending to their address.
+
+use super::{Turret, TurretAction};
+use crate::{
+    hal::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter},
+    ir::{hackpack_cmd, IrCommand, Protocol, Received, TransmitError, HACKPACK_ADDR},
+};
+
+/// How long after sending a frame to ignore the same frame coming in, as the
+/// turret's own receiver is likely to see it. A NEC frame takes 68 ms.
+pub const ECHO_MS: u32 = 200;
+
+/// Which turrets a leader talks to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ufmt::derive::uDebug)]
+pub enum BroadcastTo {
+    /// Send from the HackPack remote's address, which every turret on the
+    /// default key map listens to
+    Everyone,
+    /// Only followers listening on this address
+    Address(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Leader {
+    to: BroadcastTo,
+    leading: bool,
+    /// Last frame sent, and when
+    last: Option<(IrCommand, u32)>,
+}
+
+impl Default for Leader {
+    fn default() -> Self {
+        Self {
+            to: BroadcastTo::Everyone,
+            leading: false,
+            last: None,
+        }
+    }
+}
+
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    pub fn broadcast_to(&self) -> BroadcastTo {
+        self.leader.to
+    }
+
+    pub fn set_broadcast_to(&mut self, to: BroadcastTo) {
+        self.leader.to = to;
+    }
+
+    pub fn is_leading(&self) -> bool {
+        self.leader.leading
+    }
+
+    /// Pass every command the turret acts on to other turrets, or stop doing
+    /// so. [`TurretAction::Broadcast`] toggles this.
+    pub fn set_leading(&mut self, leading: bool) {
+        self.leader.leading = leading;
+    }
+
+    /// Tell the other turrets to do `action`, whether leading or not.
+    pub fn broadcast(&mut self, action: TurretAction) -> nb::Result<(), TransmitError> {
+        let cmd = hackpack_cmd(action).ok_or(TransmitError::Unsupported)?;
+        let addr = match self.leader.to {
+            BroadcastTo::Everyone => HACKPACK_ADDR,
+            BroadcastTo::Address(addr) => addr.into(),
+        };
+        let cmd = IrCommand {
+            protocol: Protocol::Nec,
+            addr,
+            cmd,
+            repeat: false,
+        };
+        self.transmitter.send(&cmd)?;
+        self.leader.last = Some((cmd, self.timebase.now_ms()));
+        Ok(())
+    }
+
+    /// Whether `received` is the turret hearing its own broadcast.
+    pub(super) fn is_echo(&self, received: &Received) -> bool {
+        matches!(
+            self.leader.last,
+            Some((sent, at)) if sent == received.cmd && received.at_ms.wrapping_sub(at) <= ECHO_MS
+        )
+    }
+
+    pub(super) fn relay(&mut self, action: TurretAction) {
+        match self.broadcast(action) {
+            Ok(()) => {}
+            Err(nb::Error::WouldBlock) => crate::warn!("Still sending"),
+            Err(nb::Error::Other(error)) => crate::warn!("Not sent: {:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use infrared::protocol::nec::NecCommand;
+
+    use super::*;
+    use crate::{
+        hal::host::RecordingTransmitter,
+        ir::{self, Key, KeyMap},
+        turret::test_support::{turret, HostTurret, Sink},
+    };
+
+    type Leader = HostTurret<RecordingTransmitter>;
+
+    fn leader() -> Leader {
+        let mut turret = turret().with_transmitter(RecordingTransmitter::new());
+        // The HackPack remote has no spare button for it
+        let key = Key {
+            protocol: Protocol::Rc5,
+            addr: 0,
+            cmd: 1,
+        };
+        turret
+            .keymap_mut()
+            .bind(key, TurretAction::Broadcast)
+            .unwrap();
+        turret
+    }
+
+    fn rc5(cmd: u8) -> IrCommand {
+        IrCommand {
+            protocol: Protocol::Rc5,
+            addr: 0,
+            cmd,
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn leader_passes_on_what_it_does() {
+        let mut turret = leader();
+        turret.commands.press(ir::UP);
+        turret.handle_commands(&mut Sink);
+        assert!(turret.transmitter.sent().is_empty());
+
+        turret.commands.push(rc5(1));
+        turret.handle_commands(&mut Sink);
+        assert!(turret.is_leading());
+
+        turret.commands.press(ir::OK);
+        turret.commands.push(NecCommand {
+            addr: 0,
+            cmd: ir::OK,
+            repeat: true,
+        });
+        turret.handle_commands(&mut Sink);
+        // Held buttons only go out once
+        assert_eq!(
+            turret.transmitter.sent(),
+            &[IrCommand::from(NecCommand {
+                addr: 0,
+                cmd: ir::OK,
+                repeat: false
+            })]
+        );
+
+        // To followers on their own address
+        turret.set_broadcast_to(BroadcastTo::Address(0x42));
+        turret.timebase.advance(1_000);
+        turret.commands.press(ir::CMD3);
+        turret.handle_commands(&mut Sink);
+        let sent = turret.transmitter.sent()[1];
+        assert_eq!((sent.addr, sent.cmd), (0x42, ir::CMD3));
+        let follower = KeyMap::hackpack(0x42);
+        assert_eq!(follower.action(&sent), Some(TurretAction::Preset(3)));
+    }
+
+    #[test]
+    fn ignores_its_own_broadcast() {
+        let mut turret = leader();
+        turret.set_leading(true);
+        turret.commands.press(ir::OK);
+        turret.handle_commands(&mut Sink);
+        turret.poll();
+        let fired = turret.roll.writes().len();
+
+        // The frame it just sent, picked up by its own receiver
+        let echo = turret.transmitter.sent()[0];
+        turret.commands.push_at(echo, 70);
+        turret.handle_commands(&mut Sink);
+        assert_eq!(turret.transmitter.sent().len(), 1);
+        turret.timebase.advance(70);
+        turret.poll();
+        assert_eq!(turret.roll.writes().len(), fired);
+
+        // The same button again, well after
+        turret.commands.push_at(echo, 1_000);
+        turret.handle_commands(&mut Sink);
+        assert_eq!(turret.transmitter.sent().len(), 2);
+    }
+}
@@ -1,5 +1,5 @@
 use arduino_hal::{
-    hal::port::{PB0, PB2, PB3, PB4, PD3},
+    hal::port::{PB0, PB2, PB3, PB4},
     port::{
         mode::{Floating, Input, Output},
         Pin,
@@ -8,11 +8,13 @@ use arduino_hal::{
 use uom::si::{f32::TemperatureInterval, temperature_interval::degree_celsius};
 
 use crate::{
-    hc_sr04::HcSr04,
+    clock::SystemTimebase,
+    hc_sr04::{HcSr04, SetupError},
+    ir::IrRemote,
     servo::{Servo, ServoAttached, ServoDetached, ServoError},
 };
 
-use super::Turret;
+use super::{AvrTransmitter, AvrTurret, EchoPin, Turret};
 
 #[derive(Default)]
 pub struct NoYaw;
@@ -28,7 +30,7 @@ pub struct Roll(Servo<ServoAttached>);
 
 #[derive(Default)]
 pub struct NoRangeFinder;
-pub struct RangeFinder(HcSr04<PD3>);
+pub struct RangeFinder(HcSr04<EchoPin>);
 
 #[derive(Default)]
 pub struct Builder<Yaw, Pitch, Roll, RangeFinder> {
@@ -108,31 +110,32 @@ impl<Yaw, Pitch, Roll> Builder<Yaw, Pitch, Roll, NoRangeFinder> {
     pub fn range_finder(
         self,
         d8: Pin<Output, PB0>,
-        d3: Pin<Input<Floating>, PD3>,
-    ) -> Builder<Yaw, Pitch, Roll, RangeFinder> {
+        echo: Pin<Input<Floating>, EchoPin>,
+    ) -> Result<Builder<Yaw, Pitch, Roll, RangeFinder>, SetupError> {
         let Self {
             yaw, pitch, roll, ..
         } = self;
-        let range_finder = HcSr04::new(TemperatureInterval::new::<degree_celsius>(23.0), d8, d3);
+        let range_finder = HcSr04::new(TemperatureInterval::new::<degree_celsius>(23.0), d8, echo)?;
 
-        Builder {
+        Ok(Builder {
             yaw,
             pitch,
             roll,
             range_finder: RangeFinder(range_finder),
-        }
+        })
     }
 }
 
 impl Builder<Yaw, Pitch, Roll, RangeFinder> {
-    pub fn build(self) -> Turret {
-        Turret {
-            yaw: self.yaw.0,
-            pitch: self.pitch.0,
-            roll: self.roll.0,
-
-            pitch_value: 100,
-            range_finder: self.range_finder.0,
-        }
+    pub fn build(self, transmitter: AvrTransmitter) -> AvrTurret {
+        Turret::from_parts(
+            self.yaw.0,
+            self.pitch.0,
+            self.roll.0,
+            self.range_finder.0,
+            SystemTimebase,
+            IrRemote,
+        )
+        .with_transmitter(transmitter)
     }
 }
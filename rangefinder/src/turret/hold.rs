@@ -0,0 +1,272 @@
+//! Press and hold for the movement buttons.
+//!
+//! A held NEC button sends its frame once and then a short repeat frame every
+//! 108 ms. The first frame moves one step, like a single press. Once repeats
+//! start coming in the turret keeps moving, a step each time the last one is
+//! done, until they stop for [`HoldConfig::timeout_ms`].
+//!
+//! With acceleration on, pitch takes bigger steps the longer the button is
+//! held. Yaw is already turning flat out on a single press, so held turns
+//! start slow instead and build up to full speed.
+
+use super::{
+    Turret, TurretAction, PITCH_MAX, PITCH_MIN, PITCH_MOVE_SPEED, YAW_MOVE_SPEED, YAW_PRECISION,
+    YAW_STOP_SPEED,
+};
+use crate::{
+    hal::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter},
+    motion::Axis,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HoldConfig {
+    /// How long after the last repeat the button counts as let go. A little
+    /// over the 108 ms between NEC repeats, to ride out the odd lost frame.
+    pub timeout_ms: u32,
+    /// Each time the button has been held this much longer, steps get one
+    /// step bigger
+    pub accelerate_ms: u32,
+    /// Biggest step, in single steps, and the number of speeds a held turn
+    /// goes through. `1` turns acceleration off.
+    pub max_steps: u8,
+}
+
+impl Default for HoldConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 250,
+            accelerate_ms: 1_000,
+            max_steps: 4,
+        }
+    }
+}
+
+impl HoldConfig {
+    /// How many steps at a time after the button has been held for `held_ms`
+    fn steps(&self, held_ms: u32) -> u8 {
+        let extra = held_ms
+            .checked_div(self.accelerate_ms)
+            .unwrap_or(0)
+            .min(u8::MAX as u32) as u8;
+        extra.saturating_add(1).min(self.max_steps.max(1))
+    }
+}
+
+/// The movement button being held down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Hold {
+    action: TurretAction,
+    /// When it was first pressed
+    since: u32,
+    /// When the last frame for it came in
+    last: u32,
+    /// Whether any repeat frames have come in yet
+    repeating: bool,
+}
+
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    pub fn hold_config(&self) -> &HoldConfig {
+        &self.hold_config
+    }
+
+    pub fn set_hold_config(&mut self, config: HoldConfig) {
+        self.hold_config = config;
+    }
+
+    /// A frame for one of the movement buttons came in at `at_ms`.
+    pub(super) fn press_movement(&mut self, action: TurretAction, repeat: bool, at_ms: u32) {
+        match &mut self.held {
+            Some(hold) if repeat && hold.action == action => {
+                hold.last = at_ms;
+                hold.repeating = true;
+            }
+            _ => {
+                self.held = Some(Hold {
+                    action,
+                    since: at_ms,
+                    last: at_ms,
+                    repeating: repeat,
+                });
+                match action {
+                    TurretAction::Up => self.move_up(1),
+                    TurretAction::Down => self.move_down(1),
+                    TurretAction::Left => self.move_left(1),
+                    TurretAction::Right => self.move_right(1),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Any button other than a movement one lets go of the held one.
+    pub(super) fn release(&mut self) {
+        self.held = None;
+    }
+
+    /// Keep moving while the button is held. Called from [`Turret::poll`].
+    pub(super) fn poll_hold(&mut self) {
+        let Some(hold) = self.held else {
+            return;
+        };
+        let now = self.timebase.now_ms();
+        if now.wrapping_sub(hold.last) > self.hold_config.timeout_ms {
+            self.held = None;
+            return;
+        }
+        let axis = match hold.action {
+            TurretAction::Up | TurretAction::Down => Axis::Pitch,
+            _ => Axis::Yaw,
+        };
+        if !hold.repeating || !self.motion.is_idle(axis) {
+            return;
+        }
+        let steps = self.hold_config.steps(now.wrapping_sub(hold.since));
+        self.step(hold.action, steps);
+    }
+
+    /// Keep a held button going, `steps` out of [`HoldConfig::max_steps`].
+    fn step(&mut self, action: TurretAction, steps: u8) {
+        let steps = steps as i16;
+        let speed = YAW_MOVE_SPEED * steps / self.hold_config.max_steps.max(1) as i16;
+        match action {
+            TurretAction::Up => {
+                self.pitch_to((self.pitch_value - PITCH_MOVE_SPEED * steps).max(PITCH_MIN))
+            }
+            TurretAction::Down => {
+                self.pitch_to((self.pitch_value + PITCH_MOVE_SPEED * steps).min(PITCH_MAX))
+            }
+            TurretAction::Left => {
+                self.pulse(Axis::Yaw, YAW_STOP_SPEED + speed, YAW_PRECISION);
+            }
+            TurretAction::Right => {
+                self.pulse(Axis::Yaw, YAW_STOP_SPEED - speed, YAW_PRECISION);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use infrared::protocol::nec::NecCommand;
+
+    use super::*;
+    use crate::{
+        ir,
+        turret::{
+            test_support::{turret, HostTurret, Sink},
+            PITCH_START,
+        },
+    };
+
+    /// Hold `cmd` for `held_ms`, with repeats every 108 ms like a NEC remote,
+    /// then run until the turret stops.
+    fn hold(turret: &mut HostTurret, cmd: u8, held_ms: u32) {
+        let start = turret.timebase.now_ms();
+        let mut next_frame = start;
+        let mut repeat = false;
+        loop {
+            let now = turret.timebase.now_ms();
+            if now >= next_frame && now - start <= held_ms {
+                turret.commands.push_at(
+                    NecCommand {
+                        addr: 0,
+                        cmd,
+                        repeat,
+                    },
+                    now,
+                );
+                turret.handle_commands(&mut Sink);
+                repeat = true;
+                next_frame += 108;
+            }
+            turret.poll();
+            if now - start > held_ms && !turret.is_moving() && turret.held.is_none() {
+                break;
+            }
+            turret.timebase.advance(1);
+        }
+    }
+
+    #[test]
+    fn single_press_moves_one_step() {
+        let mut turret = turret();
+        hold(&mut turret, ir::UP, 0);
+        assert_eq!(turret.pitch_value(), PITCH_START - PITCH_MOVE_SPEED);
+    }
+
+    #[test]
+    fn keeps_moving_while_held_and_stops_when_let_go() {
+        let mut turret = turret();
+        turret.set_hold_config(HoldConfig {
+            max_steps: 1,
+            ..HoldConfig::default()
+        });
+
+        hold(&mut turret, ir::UP, 500);
+        let held = turret.pitch_value();
+        // A step every `PITCH_SETTLE`, for about as long as it was held
+        assert!(held < PITCH_START - 5 * PITCH_MOVE_SPEED, "{held}");
+        assert!(held > PITCH_START - 20 * PITCH_MOVE_SPEED, "{held}");
+
+        // Nothing more once it has been let go
+        for _ in 0..1_000 {
+            turret.timebase.advance(1);
+            turret.poll();
+        }
+        assert_eq!(turret.pitch_value(), held);
+    }
+
+    #[test]
+    fn speeds_up_the_longer_it_is_held() {
+        let config = HoldConfig {
+            timeout_ms: 250,
+            accelerate_ms: 500,
+            max_steps: 3,
+        };
+        assert_eq!(config.steps(0), 1);
+        assert_eq!(config.steps(499), 1);
+        assert_eq!(config.steps(500), 2);
+        assert_eq!(config.steps(10_000), 3);
+
+        let mut slow = turret();
+        slow.set_hold_config(HoldConfig {
+            max_steps: 1,
+            ..config
+        });
+        hold(&mut slow, ir::DOWN, 200);
+        let mut fast = turret();
+        fast.set_hold_config(HoldConfig {
+            accelerate_ms: 100,
+            ..config
+        });
+        hold(&mut fast, ir::DOWN, 200);
+        assert!(fast.pitch_value() > slow.pitch_value());
+
+        // Held turns build up to full speed
+        let mut turret = turret();
+        turret.set_hold_config(config);
+        hold(&mut turret, ir::LEFT, 1_500);
+        let speeds: Vec<i16> = turret
+            .yaw
+            .writes()
+            .iter()
+            .map(|value| value - YAW_STOP_SPEED)
+            .filter(|&speed| speed != 0)
+            .collect();
+        // The first press is a single step at full speed
+        assert_eq!(speeds[0], YAW_MOVE_SPEED);
+        assert_eq!(speeds[1], YAW_MOVE_SPEED / 3);
+        assert!(speeds[1..].windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(speeds.last(), Some(&YAW_MOVE_SPEED));
+    }
+}
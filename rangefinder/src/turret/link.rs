@@ -0,0 +1,375 @@
+//! Answering frames from a host, see [`link`](crate::link).
+
+use turret_protocol::{status, ConfigKey, ErrorCode, Frame, Message};
+use ufmt::uWrite;
+use unwrap_infallible::UnwrapInfallible;
+use uom::si::{angle::degree, angular_velocity::degree_per_second, f32::AngularVelocity};
+
+use super::{ranging::RangeRequest, BroadcastTo, HoldConfig, Turret, TurretAction};
+use crate::{
+    hal::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter},
+    link::{Input, Link},
+    shell::ShellCommand,
+};
+
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    /// Carry out whatever `byte`, read off the serial port at `now_ms`,
+    /// finishes. A typed command is answered on `serial`, and the frame to
+    /// send back for a host's is returned. The firmware and the simulator both
+    /// read their serial port through this.
+    pub fn dispatch<W>(
+        &mut self,
+        link: &mut Link,
+        byte: u8,
+        now_ms: u32,
+        serial: &mut W,
+    ) -> Option<Frame>
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        match link.feed(byte, now_ms)? {
+            Input::Line(Ok(command)) => {
+                self.execute(command, serial);
+                None
+            }
+            Input::Line(Err(error)) => {
+                ufmt::uwriteln!(serial, "{:?}, try help", error).unwrap_infallible();
+                None
+            }
+            Input::Frame(Ok(frame)) => self.respond(frame, serial),
+            Input::Frame(Err(error)) => error.reply(),
+        }
+    }
+
+    /// Carry out a command frame, returning what to send back, if anything yet.
+    /// Anything the command prints still goes to `serial` as text, which hosts
+    /// skip over.
+    pub fn respond<W>(&mut self, frame: Frame, serial: &mut W) -> Option<Frame>
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        let seq = frame.seq;
+        let reply = |message| Frame::new(seq, message);
+        let error = |code| reply(Message::Error { seq, code });
+
+        let message = match frame.message {
+            Message::Action { action, times } => match TurretAction::from_byte(action) {
+                // The shell only prints it when the roll servo can't take
+                // another shot, so say so here
+                Some(action @ (TurretAction::Fire | TurretAction::FireAll)) if times > 0 => {
+                    let fired = (0..times).all(|_| match action {
+                        TurretAction::Fire => self.fire(),
+                        _ => self.fire_all(),
+                    });
+                    if !fired {
+                        return Some(error(ErrorCode::Failed));
+                    }
+                    Message::Ack { seq }
+                }
+                Some(action) if times > 0 => {
+                    self.execute(ShellCommand::Action(action, times), serial);
+                    Message::Ack { seq }
+                }
+                _ => return Some(error(ErrorCode::BadPayload)),
+            },
+            Message::TurnTo { degrees } if (-180..=180).contains(&degrees) => {
+                self.execute(ShellCommand::Yaw(degrees), serial);
+                Message::Ack { seq }
+            }
+            Message::TurnTo { .. } => return Some(error(ErrorCode::BadPayload)),
+            // Answered by `poll_range` once the echo is in
+            Message::MeasureRange => match self.request_range(RangeRequest::Frame(seq)) {
+                Ok(()) => return None,
+                Err(_) => return Some(error(ErrorCode::Failed)),
+            },
+            Message::GetStatus => self.pose_message(),
+            Message::GetConfig { key } => Message::Config {
+                key,
+                value: self.config_value(key),
+            },
+            Message::SetConfig { key, value } => match self.set_config_value(key, value) {
+                Ok(()) => Message::Ack { seq },
+                Err(()) => return Some(error(ErrorCode::BadPayload)),
+            },
+            _ => return Some(error(ErrorCode::Unsupported)),
+        };
+        Some(reply(message))
+    }
+
+    /// Where the turret is pointing and what it's up to, for telemetry.
+    pub fn pose_message(&self) -> Message {
+        let flags = [
+            (self.is_moving(), status::MOVING),
+            (self.sentry.is_active(), status::SENTRY),
+            (self.is_leading(), status::LEADING),
+            (self.is_learning(), status::LEARNING),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        Message::Pose {
+            yaw_degrees: self.yaw_estimate().get::<degree>() as i16,
+            pitch: self.pitch_value,
+            flags,
+        }
+    }
+
+    fn config_value(&self, key: ConfigKey) -> i16 {
+        let hold = self.hold_config;
+        let clamp = |ms: u32| ms.min(i16::MAX as u32) as i16;
+        match key {
+            ConfigKey::YawRate => libm::roundf(self.yaw_rate().get::<degree_per_second>()) as i16,
+            ConfigKey::HoldTimeout => clamp(hold.timeout_ms),
+            ConfigKey::HoldAccelerate => clamp(hold.accelerate_ms),
+            ConfigKey::HoldSteps => hold.max_steps as i16,
+            ConfigKey::BroadcastTo => match self.broadcast_to() {
+                BroadcastTo::Everyone => -1,
+                BroadcastTo::Address(address) => address as i16,
+            },
+        }
+    }
+
+    /// Change a setting, unless `value` is out of range for it.
+    fn set_config_value(&mut self, key: ConfigKey, value: i16) -> Result<(), ()> {
+        let hold = self.hold_config;
+        let ms = || u32::try_from(value).map_err(|_| ());
+        match key {
+            ConfigKey::YawRate => self
+                .set_yaw_rate(AngularVelocity::new::<degree_per_second>(value as f32))
+                .map_err(|_| ())?,
+            ConfigKey::HoldTimeout => self.set_hold_config(HoldConfig {
+                timeout_ms: ms()?,
+                ..hold
+            }),
+            ConfigKey::HoldAccelerate => self.set_hold_config(HoldConfig {
+                accelerate_ms: ms()?,
+                ..hold
+            }),
+            ConfigKey::HoldSteps if (1..=u8::MAX as i16).contains(&value) => {
+                self.set_hold_config(HoldConfig {
+                    max_steps: value as u8,
+                    ..hold
+                })
+            }
+            ConfigKey::BroadcastTo => self.set_broadcast_to(match value {
+                -1 => BroadcastTo::Everyone,
+                _ => BroadcastTo::Address(u8::try_from(value).map_err(|_| ())?),
+            }),
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use turret_protocol::Decoder;
+    use uom::si::{f32::Length, length::centimeter};
+
+    use super::*;
+    use crate::{
+        ir,
+        link::{ir_event, wire, Input, Link},
+        turret::{
+            test_support::{turret, HostTurret, Sink},
+            PITCH_MOVE_SPEED, PITCH_START,
+        },
+    };
+
+    /// The host sends `message`, the firmware answers, and the host decodes
+    /// the answer.
+    fn round_trip(turret: &mut HostTurret, seq: u8, message: Message) -> std::vec::Vec<Frame> {
+        let mut link = Link::new();
+        let mut host = Decoder::new();
+        let mut replies = std::vec::Vec::new();
+        for byte in wire(&Frame::new(seq, message)) {
+            if let Some(Input::Frame(frame)) = link.feed(byte, 0) {
+                let answers = turret.respond(frame.unwrap(), &mut Sink);
+                for reply in answers.into_iter().chain(turret.poll_range(&mut Sink)) {
+                    let bytes = wire(&reply);
+                    replies.extend(bytes.iter().filter_map(|&byte| host.feed(byte)));
+                }
+            }
+        }
+        replies.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn host_commands_get_answers() {
+        let mut turret = turret();
+        let up = Message::Action {
+            action: TurretAction::Up.to_byte(),
+            times: 2,
+        };
+        assert_eq!(
+            round_trip(&mut turret, 1, up),
+            [Frame::new(1, Message::Ack { seq: 1 })]
+        );
+        assert_eq!(turret.pitch_value(), PITCH_START - 2 * PITCH_MOVE_SPEED);
+
+        turret
+            .range_finder_mut()
+            .push(Ok(Length::new::<centimeter>(42.0)));
+        assert_eq!(
+            round_trip(&mut turret, 2, Message::MeasureRange),
+            [Frame::new(
+                2,
+                Message::Range {
+                    yaw_degrees: 0,
+                    pitch: PITCH_START - 2 * PITCH_MOVE_SPEED,
+                    distance_mm: Some(420),
+                }
+            )]
+        );
+
+        assert_eq!(
+            round_trip(&mut turret, 3, Message::GetStatus),
+            [Frame::new(
+                3,
+                Message::Pose {
+                    yaw_degrees: 0,
+                    pitch: PITCH_START - 2 * PITCH_MOVE_SPEED,
+                    flags: status::MOVING,
+                }
+            )]
+        );
+
+        let bad = Message::Action {
+            action: 0x7F,
+            times: 1,
+        };
+        assert_eq!(
+            round_trip(&mut turret, 4, bad),
+            [Frame::new(
+                4,
+                Message::Error {
+                    seq: 4,
+                    code: ErrorCode::BadPayload
+                }
+            )]
+        );
+        // Telemetry only goes the other way
+        assert_eq!(
+            round_trip(&mut turret, 5, Message::Ack { seq: 0 }),
+            [Frame::new(
+                5,
+                Message::Error {
+                    seq: 5,
+                    code: ErrorCode::Unsupported
+                }
+            )]
+        );
+
+        let fire = |times| Message::Action {
+            action: TurretAction::Fire.to_byte(),
+            times,
+        };
+        assert_eq!(
+            round_trip(&mut turret, 6, fire(1)),
+            [Frame::new(6, Message::Ack { seq: 6 })]
+        );
+        // More than the roll servo can queue up
+        assert_eq!(
+            round_trip(&mut turret, 7, fire(10)),
+            [Frame::new(
+                7,
+                Message::Error {
+                    seq: 7,
+                    code: ErrorCode::Failed
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn settings_can_be_read_and_changed() {
+        let mut turret = turret();
+        let get = |key| Message::GetConfig { key };
+        let set = |key, value| Message::SetConfig { key, value };
+        let answer = |seq, message| [Frame::new(seq, message)];
+        let bad = |seq| {
+            answer(
+                seq,
+                Message::Error {
+                    seq,
+                    code: ErrorCode::BadPayload,
+                },
+            )
+        };
+
+        assert_eq!(
+            round_trip(&mut turret, 1, get(ConfigKey::BroadcastTo)),
+            answer(
+                1,
+                Message::Config {
+                    key: ConfigKey::BroadcastTo,
+                    value: -1
+                }
+            )
+        );
+        assert_eq!(
+            round_trip(&mut turret, 2, set(ConfigKey::BroadcastTo, 7)),
+            answer(2, Message::Ack { seq: 2 })
+        );
+        assert_eq!(turret.broadcast_to(), BroadcastTo::Address(7));
+        assert_eq!(
+            round_trip(&mut turret, 3, set(ConfigKey::HoldSteps, 2)),
+            answer(3, Message::Ack { seq: 3 })
+        );
+        assert_eq!(turret.hold_config().max_steps, 2);
+        assert_eq!(
+            round_trip(&mut turret, 4, set(ConfigKey::YawRate, 180)),
+            answer(4, Message::Ack { seq: 4 })
+        );
+        assert_eq!(
+            round_trip(&mut turret, 5, get(ConfigKey::YawRate)),
+            answer(
+                5,
+                Message::Config {
+                    key: ConfigKey::YawRate,
+                    value: 180
+                }
+            )
+        );
+
+        assert_eq!(
+            round_trip(&mut turret, 6, set(ConfigKey::HoldTimeout, -5)),
+            bad(6)
+        );
+        assert_eq!(
+            round_trip(&mut turret, 7, set(ConfigKey::BroadcastTo, 256)),
+            bad(7)
+        );
+        assert_eq!(
+            round_trip(&mut turret, 8, set(ConfigKey::YawRate, 0)),
+            bad(8)
+        );
+        assert_eq!(turret.hold_config().timeout_ms, 250);
+    }
+
+    #[test]
+    fn ir_events_are_reported() {
+        let mut turret = turret();
+        turret.commands.press(ir::OK);
+        let received = turret.handle_command(&mut Sink).unwrap();
+        assert_eq!(
+            ir_event(&received),
+            Message::IrEvent {
+                protocol: ir::Protocol::Nec as u8,
+                addr: 0,
+                cmd: ir::OK,
+                repeat: false,
+            }
+        );
+        assert_eq!(turret.handle_command(&mut Sink), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! Measuring the distance when the shell or a host asks, without waiting on
+//! the echo.
+//!
+//! The ask only sends the ping. [`Turret::poll_range`] picks the echo up later
+//! and answers whoever asked, the same way they asked.
+
+use turret_protocol::{ErrorCode, Frame, Message};
+use ufmt::uWrite;
+use unwrap_infallible::UnwrapInfallible;
+use uom::si::{
+    angle::degree,
+    length::{centimeter, millimeter},
+};
+
+use super::Turret;
+use crate::{
+    hal::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter},
+    hc_sr04::HcSr04Error,
+};
+
+/// Who asked for the ping in flight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum RangeRequest {
+    /// `range` typed into the shell
+    Shell,
+    /// A [`Message::MeasureRange`] frame with this sequence number
+    Frame(u8),
+}
+
+/// Why a ping couldn't be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum RangeBusy {
+    /// The sweep has its own pings to send
+    Sentry,
+    /// Another ask is still waiting on its echo
+    Ranging,
+}
+
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    /// Send a ping for `request`, to be answered by [`Turret::poll_range`].
+    pub(super) fn request_range(&mut self, request: RangeRequest) -> Result<(), RangeBusy> {
+        if self.sentry.is_active() {
+            return Err(RangeBusy::Sentry);
+        }
+        if self.range_request.is_some() {
+            return Err(RangeBusy::Ranging);
+        }
+        self.range_finder.start_measurement();
+        self.range_request = Some(request);
+        Ok(())
+    }
+
+    /// Answer an ask for the distance once its echo is in. Text for the shell
+    /// goes to `serial`, and a frame for a host is returned to be sent. Call
+    /// this from the main loop as often as [`Turret::poll`].
+    pub fn poll_range<W>(&mut self, serial: &mut W) -> Option<Frame>
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        let request = self.range_request?;
+        let distance = match self.range_finder.poll_measurement() {
+            Err(nb::Error::WouldBlock) => return None,
+            Err(nb::Error::Other(error)) => Err(error),
+            Ok(distance) => Ok(distance),
+        };
+        self.range_request = None;
+
+        match request {
+            RangeRequest::Shell => {
+                match distance {
+                    Ok(distance) => {
+                        let cm = libm::roundf(distance.get::<centimeter>()) as i32;
+                        ufmt::uwriteln!(serial, "RANGE {} cm", cm).unwrap_infallible();
+                    }
+                    Err(error) => {
+                        ufmt::uwriteln!(serial, "RANGE {:?}", error).unwrap_infallible();
+                    }
+                }
+                None
+            }
+            RangeRequest::Frame(seq) => {
+                let distance_mm = match distance {
+                    Ok(distance) => {
+                        let mm = libm::roundf(distance.get::<millimeter>());
+                        Some(mm.clamp(0.0, (u16::MAX - 1) as f32) as u16)
+                    }
+                    Err(HcSr04Error::NoEcho) => None,
+                    Err(_) => {
+                        let code = ErrorCode::Failed;
+                        return Some(Frame::new(seq, Message::Error { seq, code }));
+                    }
+                };
+                let range = Message::Range {
+                    yaw_degrees: self.yaw_estimate().get::<degree>() as i16,
+                    pitch: self.pitch_value,
+                    distance_mm,
+                };
+                Some(Frame::new(seq, range))
+            }
+        }
+    }
+}
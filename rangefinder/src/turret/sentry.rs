@@ -0,0 +1,409 @@
+//! Autonomous sentry mode: sweep the room, find the closest thing within range
+//! and shoot at it.
+//!
+//! The sweep runs a step at a time from [`Turret::poll`], so the turret keeps
+//! listening to the remote while it looks around.
+
+use heapless::Vec;
+use uom::si::{
+    angle::degree,
+    f32::{Angle, Length},
+    length::{centimeter, meter},
+};
+
+use super::{Turret, PITCH_MAX, PITCH_MIN};
+use crate::{
+    hal::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter},
+    hc_sr04::HcSr04Error,
+    motion::Axis,
+};
+
+/// Most readings a single sweep can hold
+pub const PROFILE_LEN: usize = 32;
+
+/// Readings this much further away than the closest still count as the same
+/// object when working out where its middle is.
+const SAME_OBJECT_CM: f32 = 5.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SentryConfig {
+    /// Yaw the sweep starts at
+    pub sweep_from: Angle,
+    /// Yaw the sweep ends at
+    pub sweep_to: Angle,
+    /// Yaw between readings
+    pub step: Angle,
+    /// Pitches to take a reading at for every yaw step. Empty means stay at
+    /// whatever pitch the turret is at.
+    pub pitches: &'static [i16],
+    /// Only shoot at things closer than this
+    pub engagement: Length,
+    /// Time to let the turret stop wobbling before pinging, and to let the
+    /// previous ping's echoes die down. The HC-SR04 wants at least 60 ms.
+    pub settle_ms: u16,
+    /// Darts to fire at a target
+    pub shots: u8,
+}
+
+impl Default for SentryConfig {
+    fn default() -> Self {
+        Self {
+            sweep_from: Angle::new::<degree>(-60.0),
+            sweep_to: Angle::new::<degree>(60.0),
+            step: Angle::new::<degree>(15.0),
+            pitches: &[],
+            engagement: Length::new::<meter>(2.0),
+            settle_ms: 100,
+            shots: 1,
+        }
+    }
+}
+
+impl SentryConfig {
+    fn yaw_steps(&self) -> usize {
+        let span = self.sweep_to.get::<degree>() - self.sweep_from.get::<degree>();
+        let step = self.step.get::<degree>();
+        if step <= 0.0 || span < 0.0 {
+            return 1;
+        }
+        (span / step) as usize + 1
+    }
+
+    /// Number of readings in a sweep
+    pub fn readings(&self) -> usize {
+        (self.yaw_steps() * self.pitches.len().max(1)).min(PROFILE_LEN)
+    }
+
+    /// Where the `index`th reading of the sweep is taken, as yaw in degrees
+    /// and pitch if the sweep moves the pitch servo.
+    pub fn pose(&self, index: usize) -> Option<(f32, Option<i16>)> {
+        if index >= self.readings() {
+            return None;
+        }
+        let pitches = self.pitches.len().max(1);
+        let yaw =
+            self.sweep_from.get::<degree>() + (index / pitches) as f32 * self.step.get::<degree>();
+        let pitch = self.pitches.get(index % pitches).copied();
+        Some((yaw, pitch))
+    }
+}
+
+/// A single reading of the sweep.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeReading {
+    /// Degrees, positive to the left
+    pub yaw: f32,
+    pub pitch: i16,
+    pub distance: Result<Length, HcSr04Error>,
+}
+
+/// Where the sentry decided to shoot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Target {
+    /// Degrees, positive to the left
+    pub yaw: f32,
+    pub pitch: i16,
+    pub distance: Length,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    /// Queue the moves for the `index`th reading
+    Aim(usize),
+    /// Wait for the moves to finish, then for things to settle
+    Settle {
+        index: usize,
+        since: Option<u32>,
+    },
+    /// Wait for the ping to come back
+    Ping(usize),
+    /// Wait for the turret to finish aiming at the target, then shoot
+    Fire,
+}
+
+#[derive(Debug)]
+pub struct Sentry {
+    config: SentryConfig,
+    phase: Phase,
+    profile: Vec<RangeReading, PROFILE_LEN>,
+    target: Option<Target>,
+}
+
+impl Sentry {
+    pub fn new(config: SentryConfig) -> Self {
+        Self {
+            config,
+            phase: Phase::Idle,
+            profile: Vec::new(),
+            target: None,
+        }
+    }
+
+    pub fn config(&self) -> &SentryConfig {
+        &self.config
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+
+    /// Readings from the current or most recent sweep
+    pub fn profile(&self) -> &[RangeReading] {
+        &self.profile
+    }
+
+    /// What the most recent finished sweep shot at, if anything
+    pub fn target(&self) -> Option<Target> {
+        self.target
+    }
+
+    fn start(&mut self) {
+        self.profile.clear();
+        self.target = None;
+        self.phase = Phase::Aim(0);
+    }
+
+    fn stop(&mut self) {
+        self.phase = Phase::Idle;
+    }
+}
+
+/// Pick the closest reading within `engagement` and aim at the middle of it.
+///
+/// An object usually shows up in a few neighbouring readings, so the yaw is
+/// averaged over the run of readings at the same pitch that are about as close.
+pub fn closest_target(profile: &[RangeReading], engagement: Length) -> Option<Target> {
+    let (index, closest) = profile
+        .iter()
+        .enumerate()
+        .filter_map(|(i, r)| r.distance.ok().map(|d| (i, d)))
+        .filter(|&(_, d)| d <= engagement)
+        .min_by(|a, b| a.1.value.total_cmp(&b.1.value))?;
+    let pitch = profile[index].pitch;
+    let limit = closest + Length::new::<centimeter>(SAME_OBJECT_CM);
+    let same_object = |r: &&RangeReading| r.distance.map(|d| d <= limit).unwrap_or(false);
+
+    let same_row = |r: &&RangeReading| r.pitch == pitch;
+    let before = profile[..index]
+        .iter()
+        .rev()
+        .filter(same_row)
+        .take_while(same_object);
+    let after = profile[index..]
+        .iter()
+        .filter(same_row)
+        .take_while(same_object);
+
+    let (sum, count) = before
+        .chain(after)
+        .fold((0.0, 0), |(sum, count), r| (sum + r.yaw, count + 1));
+    Some(Target {
+        yaw: sum / count as f32,
+        pitch,
+        distance: closest,
+    })
+}
+
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    pub fn sentry(&self) -> &Sentry {
+        &self.sentry
+    }
+
+    pub fn set_sentry_config(&mut self, config: SentryConfig) {
+        self.sentry.config = config;
+    }
+
+    /// Start a sweep, or call off the one that is running.
+    pub fn toggle_sentry(&mut self) {
+        if self.sentry.is_active() {
+            self.sentry.stop();
+        } else {
+            self.sentry.start();
+        }
+    }
+
+    /// Move the sweep along. Called from [`Turret::poll`].
+    pub(super) fn poll_sentry(&mut self) {
+        match self.sentry.phase {
+            Phase::Idle => {}
+            Phase::Aim(index) => {
+                let Some((yaw, pitch)) = self.sentry.config.pose(index) else {
+                    self.engage();
+                    return;
+                };
+                self.turn_to(Angle::new::<degree>(yaw));
+                if let Some(pitch) = pitch {
+                    self.pitch_to(pitch);
+                }
+                self.sentry.phase = Phase::Settle { index, since: None };
+            }
+            Phase::Settle { index, since } => {
+                if !self.motion.is_idle(Axis::Yaw) || !self.motion.is_idle(Axis::Pitch) {
+                    return;
+                }
+                let now = self.timebase.now_ms();
+                let Some(since) = since else {
+                    self.sentry.phase = Phase::Settle {
+                        index,
+                        since: Some(now),
+                    };
+                    return;
+                };
+                // Wait for a ping the shell or a host sent before the sweep
+                // started, too
+                if now.wrapping_sub(since) < self.sentry.config.settle_ms as u32
+                    || self.range_request.is_some()
+                {
+                    return;
+                }
+                self.range_finder.start_measurement();
+                self.sentry.phase = Phase::Ping(index);
+            }
+            Phase::Ping(index) => {
+                let distance = match self.range_finder.poll_measurement() {
+                    Err(nb::Error::WouldBlock) => return,
+                    Err(nb::Error::Other(e)) => Err(e),
+                    Ok(distance) => Ok(distance),
+                };
+                let reading = RangeReading {
+                    yaw: self.yaw_estimate().get::<degree>(),
+                    pitch: self.pitch_value,
+                    distance,
+                };
+                // `readings()` never hands out more poses than fit
+                let _ = self.sentry.profile.push(reading);
+                self.sentry.phase = Phase::Aim(index + 1);
+            }
+            Phase::Fire => {
+                if !self.motion.is_idle(Axis::Yaw) || !self.motion.is_idle(Axis::Pitch) {
+                    return;
+                }
+                // The roll servo stops taking shots once its queue is full
+                let shots = self.sentry.config.shots;
+                if let Some(fired) = (0..shots).find(|_| !self.fire()) {
+                    crate::warn!("Sentry fired {} of {} shots", fired, shots);
+                }
+                self.sentry.stop();
+            }
+        }
+    }
+
+    /// The sweep is done, so turn towards the closest target if there is one.
+    fn engage(&mut self) {
+        let target = closest_target(&self.sentry.profile, self.sentry.config.engagement);
+        self.sentry.target = target;
+        let Some(target) = target else {
+            self.sentry.stop();
+            return;
+        };
+
+        self.turn_to(Angle::new::<degree>(target.yaw));
+        self.pitch_to(target.pitch);
+        self.sentry.phase = Phase::Fire;
+    }
+
+    /// Queue a move of the pitch servo straight to `value`.
+    pub fn pitch_to(&mut self, value: i16) {
+        self.step_pitch(value.clamp(PITCH_MIN, PITCH_MAX));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::turret::test_support::turret;
+
+    fn reading(yaw: f32, distance: Option<f32>) -> RangeReading {
+        RangeReading {
+            yaw,
+            pitch: 100,
+            distance: distance
+                .map(Length::new::<meter>)
+                .ok_or(HcSr04Error::NoEcho),
+        }
+    }
+
+    #[test]
+    fn poses_cover_the_sweep() {
+        let config = SentryConfig {
+            pitches: &[80, 100],
+            ..SentryConfig::default()
+        };
+
+        // Degrees go through radians and back, so only roughly equal
+        let pose = |index| {
+            let (yaw, pitch) = config.pose(index).unwrap();
+            (yaw.round() as i32, pitch)
+        };
+
+        assert_eq!(config.readings(), 18);
+        assert_eq!(pose(0), (-60, Some(80)));
+        assert_eq!(pose(1), (-60, Some(100)));
+        assert_eq!(pose(17), (60, Some(100)));
+        assert_eq!(config.pose(18), None);
+    }
+
+    #[test]
+    fn aims_at_middle_of_closest_object() {
+        let profile = [
+            reading(-30.0, Some(1.5)),
+            reading(-15.0, None),
+            reading(0.0, Some(1.02)),
+            reading(15.0, Some(1.0)),
+            reading(30.0, Some(1.03)),
+            reading(45.0, Some(3.0)),
+        ];
+
+        let target = closest_target(&profile, Length::new::<meter>(2.0)).unwrap();
+
+        assert_eq!(target.yaw, 15.0);
+        assert_eq!(target.distance, Length::new::<meter>(1.0));
+    }
+
+    #[test]
+    fn ignores_things_out_of_range() {
+        let profile = [reading(0.0, Some(2.5)), reading(15.0, None)];
+
+        assert_eq!(closest_target(&profile, Length::new::<meter>(2.0)), None);
+    }
+
+    #[test]
+    fn keeps_polling_while_the_ping_is_in_flight() {
+        let mut turret = turret();
+        turret.set_sentry_config(SentryConfig {
+            sweep_from: Angle::new::<degree>(0.0),
+            sweep_to: Angle::new::<degree>(0.0),
+            ..SentryConfig::default()
+        });
+        turret.range_finder_mut().set_latency(3);
+        turret
+            .range_finder_mut()
+            .push(Ok(Length::new::<meter>(1.0)));
+
+        turret.toggle_sentry();
+        while !turret.range_finder().in_flight() {
+            turret.poll();
+            turret.timebase.advance(1);
+        }
+        for _ in 0..3 {
+            turret.poll();
+            assert!(turret.sentry().profile().is_empty());
+        }
+        turret.poll();
+
+        assert_eq!(
+            turret.sentry().profile()[0].distance,
+            Ok(Length::new::<meter>(1.0))
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Carrying out commands typed into the [`shell`](crate::shell).
+
+use ufmt::uWrite;
+use unwrap_infallible::UnwrapInfallible;
+use uom::si::{angle::degree, f32::Angle};
+
+use super::{
+    ranging::{RangeBusy, RangeRequest},
+    Turret, TurretAction,
+};
+use crate::{
+    hal::{Actuator, CommandSource, RangeSensor, Timebase, Transmitter},
+    shell::{ShellCommand, HELP},
+};
+
+impl<Y, P, R, S, T, C, X> Turret<Y, P, R, S, T, C, X>
+where
+    Y: Actuator,
+    P: Actuator,
+    R: Actuator,
+    S: RangeSensor,
+    T: Timebase,
+    C: CommandSource,
+    X: Transmitter,
+{
+    /// Carry out a command from the serial console, answering on `serial`.
+    pub fn execute<W>(&mut self, command: ShellCommand, serial: &mut W)
+    where
+        W: uWrite<Error = core::convert::Infallible>,
+    {
+        match command {
+            ShellCommand::Action(TurretAction::Fire, times) => {
+                for fired in 0..times {
+                    if !self.fire() {
+                        ufmt::uwriteln!(serial, "Busy: fired {} of {}", fired, times)
+                            .unwrap_infallible();
+                        return;
+                    }
+                    ufmt::uwriteln!(serial, "FIRE").unwrap_infallible();
+                }
+            }
+            ShellCommand::Action(action, times) => {
+                for _ in 0..times {
+                    self.perform(action, serial);
+                }
+            }
+            ShellCommand::Yaw(degrees) => {
+                self.turn_to(Angle::new::<degree>(degrees as f32));
+                ufmt::uwriteln!(serial, "YAW {}", degrees).unwrap_infallible();
+            }
+            // Answered by `poll_range` once the echo is in
+            ShellCommand::Range => match self.request_range(RangeRequest::Shell) {
+                Ok(()) => {}
+                Err(RangeBusy::Sentry) => {
+                    ufmt::uwriteln!(serial, "Busy: sentry").unwrap_infallible()
+                }
+                Err(RangeBusy::Ranging) => {
+                    ufmt::uwriteln!(serial, "Busy: ranging").unwrap_infallible()
+                }
+            },
+            ShellCommand::Status => {
+                let yaw = self.yaw_estimate().get::<degree>() as i32;
+                ufmt::uwriteln!(
+                    serial,
+                    "Yaw: {}, Pitch: {}, Moving: {}, Sentry: {}, Leading: {}, Learning: {}",
+                    yaw,
+                    self.pitch_value,
+                    self.is_moving(),
+                    self.sentry.is_active(),
+                    self.is_leading(),
+                    self.is_learning()
+                )
+                .unwrap_infallible();
+            }
+            ShellCommand::Help => {
+                ufmt::uwriteln!(serial, "{}", HELP).unwrap_infallible();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uom::si::{f32::Length, length::centimeter};
+
+    use super::*;
+    use crate::{
+        shell::Shell,
+        turret::{
+            test_support::{turret, HostTurret},
+            PITCH_MOVE_SPEED, PITCH_START,
+        },
+    };
+
+    /// Serial port that keeps everything written to it
+    #[derive(Default)]
+    struct Console(String);
+
+    impl uWrite for Console {
+        type Error = core::convert::Infallible;
+
+        fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+            self.0.push_str(s);
+            Ok(())
+        }
+    }
+
+    /// Type `line` into a shell and run what comes out, returning the reply.
+    fn run(turret: &mut HostTurret, line: &str) -> String {
+        let mut shell = Shell::new();
+        let mut reply = Console::default();
+        for byte in line.bytes().chain(*b"\n") {
+            if let Some(command) = shell.feed(byte) {
+                turret.execute(command.unwrap(), &mut reply);
+            }
+        }
+        turret.poll_range(&mut reply);
+        reply.0
+    }
+
+    #[test]
+    fn shell_commands_move_the_turret() {
+        let mut shelled = turret();
+        assert_eq!(run(&mut shelled, "up 3"), "Up\nUp\nUp\n");
+        assert_eq!(shelled.pitch_value(), PITCH_START - 3 * PITCH_MOVE_SPEED);
+
+        assert_eq!(run(&mut shelled, "fire"), "FIRE\n");
+        shelled.poll();
+        assert_eq!(shelled.roll.writes().len(), 1);
+
+        shelled
+            .range_finder_mut()
+            .push(Ok(Length::new::<centimeter>(42.0)));
+        assert_eq!(run(&mut shelled, "range"), "RANGE 42 cm\n");
+        assert_eq!(run(&mut shelled, "range"), "RANGE NoEcho\n");
+
+        // The echo takes a while, and the shell doesn't wait for it
+        shelled.range_finder_mut().set_latency(1);
+        shelled
+            .range_finder_mut()
+            .push(Ok(Length::new::<centimeter>(50.0)));
+        assert_eq!(run(&mut shelled, "range"), "");
+        assert_eq!(run(&mut shelled, "range"), "Busy: ranging\nRANGE 50 cm\n");
+
+        run(&mut shelled, "lead");
+        let status = run(&mut shelled, "status");
+        assert!(status.contains("Leading: true"), "{status}");
+        assert!(run(&mut shelled, "help").contains("preset"));
+
+        // Each shot takes two moves on the roll servo's queue
+        let mut busy = turret();
+        let reply = run(&mut busy, "fire 10");
+        assert!(reply.ends_with("FIRE\nBusy: fired 8 of 10\n"), "{reply}");
+        assert_eq!(run(&mut busy, "fire"), "Busy: fired 0 of 1\n");
+
+        // Same as the remote's button
+        let mut remote = turret();
+        remote.perform(TurretAction::Up, &mut Console::default());
+        assert_eq!(remote.pitch_value(), PITCH_START - PITCH_MOVE_SPEED);
+    }
+}
@@ -0,0 +1,204 @@
+use uom::si::{
+    angle::degree,
+    angular_velocity::degree_per_second,
+    f32::{Angle, AngularVelocity},
+};
+
+use super::{YAW_MOVE_SPEED, YAW_STOP_SPEED};
+
+/// A yaw rate that isn't a positive number of degrees per second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BadYawRate;
+
+/// Dead-reckoning estimate of where the continuous-rotation yaw servo is
+/// pointing.
+///
+/// The servo has no feedback, so the best we can do is add up commanded speed
+/// times how long it was commanded for. Positive angles are to the left.
+#[derive(Clone, Copy, Debug)]
+pub struct YawEstimator {
+    /// Degrees per second with the servo driven flat out
+    rate: f32,
+
+    /// Estimated angle, in degrees, as of `since_ms`
+    angle: f32,
+    /// Signed fraction of full speed the servo has been running at since `since_ms`
+    speed: f32,
+    since_ms: u32,
+}
+
+impl YawEstimator {
+    pub fn new(rate: AngularVelocity) -> Self {
+        Self {
+            rate: rate.get::<degree_per_second>(),
+            angle: 0.0,
+            speed: 0.0,
+            since_ms: 0,
+        }
+    }
+
+    /// Record that the servo was just written `value`.
+    pub fn set_speed(&mut self, value: i16, now_ms: u32) {
+        self.catch_up(now_ms);
+        self.speed = speed(value);
+    }
+
+    /// Change the calibration. Motion up to now is accounted for at the old rate.
+    ///
+    /// Moves are timed by dividing by the rate, so anything but a positive
+    /// rate is turned down and the old one kept.
+    pub fn set_rate(&mut self, rate: AngularVelocity, now_ms: u32) -> Result<(), BadYawRate> {
+        let rate = rate.get::<degree_per_second>();
+        // Written this way round so NaN fails too
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(BadYawRate);
+        }
+        self.catch_up(now_ms);
+        self.rate = rate;
+        Ok(())
+    }
+
+    pub fn rate(&self) -> AngularVelocity {
+        AngularVelocity::new::<degree_per_second>(self.rate)
+    }
+
+    /// Declare the turret to be pointing at `angle`, e.g. after lining it up
+    /// by eye.
+    pub fn reset(&mut self, angle: Angle, now_ms: u32) {
+        self.catch_up(now_ms);
+        self.angle = angle.get::<degree>();
+    }
+
+    /// Estimated angle at `now_ms`, normalized to `-180..180` degrees.
+    pub fn angle(&self, now_ms: u32) -> Angle {
+        Angle::new::<degree>(normalize(self.degrees_at(now_ms)))
+    }
+
+    /// How far a servo `value` held for `duration_ms` turns the turret, in degrees.
+    pub fn travel(&self, value: i16, duration_ms: u16) -> f32 {
+        speed(value) * self.rate * duration_ms as f32 / 1_000.0
+    }
+
+    /// Servo value and how long to hold it for to turn by `degrees`.
+    ///
+    /// Returns `None` if the turn is too small to be worth a millisecond.
+    pub fn pulse_for(&self, degrees: f32) -> Option<(i16, u32)> {
+        let magnitude = if degrees < 0.0 { -degrees } else { degrees };
+        let duration_ms = (magnitude / self.rate * 1_000.0 + 0.5) as u32;
+        if duration_ms == 0 {
+            return None;
+        }
+        let value = if degrees > 0.0 {
+            YAW_STOP_SPEED + YAW_MOVE_SPEED
+        } else {
+            YAW_STOP_SPEED - YAW_MOVE_SPEED
+        };
+        Some((value, duration_ms))
+    }
+
+    fn degrees_at(&self, now_ms: u32) -> f32 {
+        let elapsed = now_ms.wrapping_sub(self.since_ms) as f32 / 1_000.0;
+        self.angle + self.speed * self.rate * elapsed
+    }
+
+    fn catch_up(&mut self, now_ms: u32) {
+        self.angle = normalize(self.degrees_at(now_ms));
+        self.since_ms = now_ms;
+    }
+}
+
+/// Signed fraction of full speed for a yaw servo value
+fn speed(value: i16) -> f32 {
+    ((value - YAW_STOP_SPEED) as f32 / YAW_MOVE_SPEED as f32).clamp(-1.0, 1.0)
+}
+
+/// Wrap an angle in degrees into `-180..180`
+pub(crate) fn normalize(degrees: f32) -> f32 {
+    let wrapped = (degrees + 180.0) % 360.0;
+    if wrapped < 0.0 {
+        wrapped + 180.0
+    } else {
+        wrapped - 180.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator() -> YawEstimator {
+        YawEstimator::new(AngularVelocity::new::<degree_per_second>(360.0))
+    }
+
+    fn degrees(estimator: &YawEstimator, now_ms: u32) -> f32 {
+        estimator.angle(now_ms).get::<degree>()
+    }
+
+    #[test]
+    fn integrates_speed_over_time() {
+        let mut yaw = estimator();
+
+        yaw.set_speed(YAW_STOP_SPEED + YAW_MOVE_SPEED, 0);
+        assert!((degrees(&yaw, 50) - 18.0).abs() < 0.01);
+
+        yaw.set_speed(YAW_STOP_SPEED, 100);
+        assert!((degrees(&yaw, 1_000) - 36.0).abs() < 0.01);
+
+        yaw.set_speed(YAW_STOP_SPEED - YAW_MOVE_SPEED / 2, 1_000);
+        assert!((degrees(&yaw, 1_100) - 18.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut yaw = estimator();
+        yaw.reset(Angle::new::<degree>(170.0), 0);
+
+        yaw.set_speed(YAW_STOP_SPEED + YAW_MOVE_SPEED, 0);
+        yaw.set_speed(YAW_STOP_SPEED, 100);
+
+        assert!((degrees(&yaw, 100) + 154.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn rate_change_keeps_past_motion() {
+        let mut yaw = estimator();
+
+        yaw.set_speed(YAW_STOP_SPEED + YAW_MOVE_SPEED, 0);
+        yaw.set_rate(AngularVelocity::new::<degree_per_second>(180.0), 100)
+            .unwrap();
+
+        assert!((degrees(&yaw, 200) - 54.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn rejects_rates_that_are_not_positive() {
+        let mut yaw = estimator();
+
+        for rate in [0.0, -90.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(
+                yaw.set_rate(AngularVelocity::new::<degree_per_second>(rate), 0),
+                Err(BadYawRate)
+            );
+        }
+        assert_eq!(yaw.rate().get::<degree_per_second>(), 360.0);
+        assert_eq!(
+            yaw.pulse_for(90.0),
+            Some((YAW_STOP_SPEED + YAW_MOVE_SPEED, 250))
+        );
+    }
+
+    #[test]
+    fn pulse_for_picks_direction_and_duration() {
+        let yaw = estimator();
+
+        assert_eq!(
+            yaw.pulse_for(90.0),
+            Some((YAW_STOP_SPEED + YAW_MOVE_SPEED, 250))
+        );
+        assert_eq!(
+            yaw.pulse_for(-36.0),
+            Some((YAW_STOP_SPEED - YAW_MOVE_SPEED, 100))
+        );
+        assert_eq!(yaw.pulse_for(0.1), None);
+    }
+}
@@ -0,0 +1,290 @@
+//! The subcommands, and how each one plays out over the protocol.
+
+use std::io::Write;
+
+use rangefinder::turret::TurretAction;
+use turret_protocol::{status, ConfigKey, Message};
+
+use crate::{Client, Error, Port, POLL_MS};
+
+pub const USAGE: &str = "\
+move up|down|left|right [N]   step N times
+move to DEGREES               turn to face DEGREES, positive to the left
+fire [N|all]                  fire N darts, or every dart
+scan [FROM TO STEP]           range every STEP degrees, -90 to 90 by 15
+tail-telemetry [SECONDS]      print what the turret sends unasked
+config get [KEY]              print one setting, or all of them
+config set KEY VALUE          change a setting until the turret resets
+
+Settings: yaw-rate, hold-timeout, hold-accelerate, hold-steps,
+broadcast-to (an address, or everyone)";
+
+/// Longest a move can take before the turret stops answering `moving`
+const MOVE_MS: u32 = 30_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Do what a remote button does, this many times
+    Action(TurretAction, u8),
+    TurnTo(i16),
+    Scan {
+        from: i16,
+        to: i16,
+        step: i16,
+    },
+    /// Print telemetry for this many seconds, or for good
+    Tail(Option<u32>),
+    /// Print this setting, or every one
+    GetConfig(Option<ConfigKey>),
+    SetConfig(ConfigKey, i16),
+}
+
+impl Command {
+    /// Read a command from the words after the program name and options.
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Self, String> {
+        let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+        let command = match args[..] {
+            ["move", "to", degrees] => Command::TurnTo(number(degrees)?),
+            ["move", direction, ref times @ ..] => {
+                let action = match direction {
+                    "up" => TurretAction::Up,
+                    "down" => TurretAction::Down,
+                    "left" => TurretAction::Left,
+                    "right" => TurretAction::Right,
+                    _ => return Err(format!("Can't move {direction}")),
+                };
+                Command::Action(action, self::times(times)?)
+            }
+            ["fire", "all"] => Command::Action(TurretAction::FireAll, 1),
+            ["fire", ref times @ ..] => Command::Action(TurretAction::Fire, self::times(times)?),
+            ["scan"] => Command::Scan {
+                from: -90,
+                to: 90,
+                step: 15,
+            },
+            ["scan", from, to, step] => {
+                let (from, to, step) = (number(from)?, number(to)?, number(step)?);
+                if from > to || step <= 0 {
+                    return Err("Scan from low to high, with a step above zero".into());
+                }
+                Command::Scan { from, to, step }
+            }
+            ["tail-telemetry"] => Command::Tail(None),
+            ["tail-telemetry", seconds] => Command::Tail(Some(number(seconds)?)),
+            ["config", "get"] => Command::GetConfig(None),
+            ["config", "get", key] => Command::GetConfig(Some(config_key(key)?)),
+            ["config", "set", key, value] => {
+                let key = config_key(key)?;
+                let value = match (key, value) {
+                    (ConfigKey::BroadcastTo, "everyone") => -1,
+                    _ => number(value)?,
+                };
+                Command::SetConfig(key, value)
+            }
+            _ => return Err(format!("Unknown command: {}", args.join(" "))),
+        };
+        Ok(command)
+    }
+
+    /// Carry the command out, printing the results to `out`.
+    pub fn run<P: Port>(&self, client: &mut Client<P>, out: &mut impl Write) -> Result<(), Error> {
+        match *self {
+            Command::Action(action, times) => {
+                client.command(Message::Action {
+                    action: action.to_byte(),
+                    times,
+                })?;
+                writeln!(out, "{}", describe(&wait_still(client)?))?;
+            }
+            Command::TurnTo(degrees) => {
+                client.command(Message::TurnTo { degrees })?;
+                writeln!(out, "{}", describe(&wait_still(client)?))?;
+            }
+            Command::Scan { from, to, step } => {
+                for degrees in (from..=to).step_by(step as usize) {
+                    client.command(Message::TurnTo { degrees })?;
+                    wait_still(client)?;
+                    match client.request(Message::MeasureRange)? {
+                        range @ Message::Range { .. } => writeln!(out, "{}", describe(&range))?,
+                        other => return Err(Error::Unexpected(other)),
+                    }
+                }
+            }
+            Command::Tail(seconds) => {
+                // The turret only sends telemetry once it has heard a frame
+                writeln!(out, "{}", describe(&client.request(Message::GetStatus)?))?;
+                let mut left_ms = seconds.map(|seconds| seconds.saturating_mul(1_000));
+                while left_ms != Some(0) {
+                    let wait_ms = left_ms.map_or(POLL_MS, |ms| ms.min(POLL_MS));
+                    if let Some(frame) = client.telemetry(wait_ms)? {
+                        writeln!(out, "{}", describe(&frame.message))?;
+                    }
+                    left_ms = left_ms.map(|ms| ms - wait_ms);
+                }
+            }
+            Command::GetConfig(key) => {
+                let keys = key.map_or(ConfigKey::ALL.to_vec(), |key| vec![key]);
+                for key in keys {
+                    writeln!(
+                        out,
+                        "{}",
+                        describe(&client.request(Message::GetConfig { key })?)
+                    )?;
+                }
+            }
+            Command::SetConfig(key, value) => {
+                client.command(Message::SetConfig { key, value })?;
+                writeln!(out, "{}", describe(&Message::Config { key, value }))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn number<N: std::str::FromStr>(arg: &str) -> Result<N, String> {
+    arg.parse().map_err(|_| format!("Not a number: {arg}"))
+}
+
+fn times(args: &[&str]) -> Result<u8, String> {
+    match *args {
+        [] => Ok(1),
+        [times] => match number(times)? {
+            0 => Err("Do it at least once".into()),
+            times => Ok(times),
+        },
+        _ => Err(format!("Too many arguments: {}", args.join(" "))),
+    }
+}
+
+const CONFIG_NAMES: [(&str, ConfigKey); 5] = [
+    ("yaw-rate", ConfigKey::YawRate),
+    ("hold-timeout", ConfigKey::HoldTimeout),
+    ("hold-accelerate", ConfigKey::HoldAccelerate),
+    ("hold-steps", ConfigKey::HoldSteps),
+    ("broadcast-to", ConfigKey::BroadcastTo),
+];
+
+fn config_key(name: &str) -> Result<ConfigKey, String> {
+    CONFIG_NAMES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|&(_, key)| key)
+        .ok_or_else(|| format!("No such setting: {name}"))
+}
+
+fn config_name(key: ConfigKey) -> &'static str {
+    CONFIG_NAMES
+        .iter()
+        .find(|(_, known)| *known == key)
+        .map_or("?", |(name, _)| name)
+}
+
+/// Ask for the pose until the turret has stopped moving.
+fn wait_still<P: Port>(client: &mut Client<P>) -> Result<Message, Error> {
+    let mut waited = 0;
+    loop {
+        let pose = client.request(Message::GetStatus)?;
+        match pose {
+            Message::Pose { flags, .. } if flags & status::MOVING == 0 => return Ok(pose),
+            Message::Pose { .. } if waited < MOVE_MS => {}
+            Message::Pose { .. } => return Err(Error::Timeout),
+            other => return Err(Error::Unexpected(other)),
+        }
+        client.port_mut().pause(POLL_MS);
+        waited += POLL_MS;
+    }
+}
+
+/// One line for a message from the turret.
+pub fn describe(message: &Message) -> String {
+    match *message {
+        Message::Pose {
+            yaw_degrees,
+            pitch,
+            flags,
+        } => {
+            let mut line = format!("yaw {yaw_degrees} pitch {pitch}");
+            for (flag, name) in [
+                (status::MOVING, "moving"),
+                (status::SENTRY, "sentry"),
+                (status::LEADING, "leading"),
+                (status::LEARNING, "learning"),
+            ] {
+                if flags & flag != 0 {
+                    line.push(' ');
+                    line.push_str(name);
+                }
+            }
+            line
+        }
+        Message::Range {
+            yaw_degrees,
+            pitch,
+            distance_mm,
+        } => match distance_mm {
+            Some(mm) => format!("yaw {yaw_degrees} pitch {pitch} range {mm} mm"),
+            None => format!("yaw {yaw_degrees} pitch {pitch} no echo"),
+        },
+        Message::IrEvent {
+            protocol,
+            addr,
+            cmd,
+            repeat,
+        } => {
+            let repeat = if repeat { " repeat" } else { "" };
+            format!("ir protocol {protocol} addr {addr:#06x} cmd {cmd:#04x}{repeat}")
+        }
+        Message::Config {
+            key: ConfigKey::BroadcastTo,
+            value: -1,
+        } => format!("{} everyone", config_name(ConfigKey::BroadcastTo)),
+        Message::Config { key, value } => format!("{} {value}", config_name(key)),
+        other => format!("{other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subcommands() {
+        let parse = |args: &str| Command::parse(&args.split_whitespace().collect::<Vec<_>>());
+        assert_eq!(
+            parse("move left"),
+            Ok(Command::Action(TurretAction::Left, 1))
+        );
+        assert_eq!(
+            parse("move down 3"),
+            Ok(Command::Action(TurretAction::Down, 3))
+        );
+        assert_eq!(parse("move to -45"), Ok(Command::TurnTo(-45)));
+        assert_eq!(
+            parse("fire all"),
+            Ok(Command::Action(TurretAction::FireAll, 1))
+        );
+        assert_eq!(
+            parse("scan"),
+            Ok(Command::Scan {
+                from: -90,
+                to: 90,
+                step: 15
+            })
+        );
+        assert_eq!(parse("tail-telemetry 5"), Ok(Command::Tail(Some(5))));
+        assert_eq!(
+            parse("config set broadcast-to everyone"),
+            Ok(Command::SetConfig(ConfigKey::BroadcastTo, -1))
+        );
+        assert_eq!(
+            parse("config get hold-steps"),
+            Ok(Command::GetConfig(Some(ConfigKey::HoldSteps)))
+        );
+
+        assert!(parse("move sideways").is_err());
+        assert!(parse("fire 0").is_err());
+        assert!(parse("scan 90 -90 15").is_err());
+        assert!(parse("config set colour 3").is_err());
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Talking to the rangefinder turret from a computer, over the binary
+//! [`turret_protocol`] on its serial port.
+//!
+//! [`Client`] works over any [`Port`]: the real [`Serial`] port, or a
+//! [`SimPort`] with the simulated turret on the other end.
+
+use std::{collections::VecDeque, fmt, io};
+
+use turret_protocol::{Decoder, ErrorCode, Frame, Message, MAX_FRAME};
+
+pub mod command;
+pub mod port;
+
+pub use port::{Port, Serial, SimPort};
+
+/// How long to wait for the answer to a command
+pub const TIMEOUT_MS: u32 = 2_000;
+/// How long to wait between looks at a port with nothing to read
+pub const POLL_MS: u32 = 10;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The turret didn't answer in time
+    Timeout,
+    /// The turret answered with [`Message::Error`]
+    Turret(ErrorCode),
+    /// The turret answered with something that doesn't go with the command
+    Unexpected(Message),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::Timeout => write!(f, "no answer from the turret"),
+            Error::Turret(code) => write!(f, "the turret said {code:?}"),
+            Error::Unexpected(message) => write!(f, "unexpected answer {message:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// The host end of the protocol.
+pub struct Client<P> {
+    port: P,
+    decoder: Decoder,
+    seq: u8,
+    /// Frames that came in unasked, e.g. while waiting for an answer
+    telemetry: VecDeque<Frame>,
+}
+
+impl<P: Port> Client<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            decoder: Decoder::new(),
+            seq: 0,
+            telemetry: VecDeque::new(),
+        }
+    }
+
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    /// Send a command and wait for the answer to it. Error answers come back
+    /// as [`Error::Turret`].
+    pub fn request(&mut self, message: Message) -> Result<Message, Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let frame = Frame::new(self.seq, message);
+        let mut out = [0; MAX_FRAME];
+        let len = frame.encode(&mut out);
+        self.port.write_all(&out[..len])?;
+        self.port.flush()?;
+
+        let mut waited = 0;
+        loop {
+            while let Some(frame) = self.read_frame()? {
+                // Telemetry has sequence numbers of its own, so a pose could
+                // pass for the answer to a `GetStatus`. It's a pose all the same.
+                if frame.seq != self.seq || !is_answer(&frame.message) {
+                    self.telemetry.push_back(frame);
+                    continue;
+                }
+                return match frame.message {
+                    Message::Error { code, .. } => Err(Error::Turret(code)),
+                    message => Ok(message),
+                };
+            }
+            if waited >= TIMEOUT_MS {
+                return Err(Error::Timeout);
+            }
+            self.port.pause(POLL_MS);
+            waited += POLL_MS;
+        }
+    }
+
+    /// Send a command that is answered with [`Message::Ack`].
+    pub fn command(&mut self, message: Message) -> Result<(), Error> {
+        match self.request(message)? {
+            Message::Ack { .. } => Ok(()),
+            other => Err(Error::Unexpected(other)),
+        }
+    }
+
+    /// The next frame the turret sent unasked, waiting up to `wait_ms` for
+    /// one.
+    pub fn telemetry(&mut self, wait_ms: u32) -> Result<Option<Frame>, Error> {
+        let mut waited = 0;
+        loop {
+            if let Some(frame) = self.telemetry.pop_front() {
+                return Ok(Some(frame));
+            }
+            if let Some(frame) = self.read_frame()? {
+                self.telemetry.push_back(frame);
+                continue;
+            }
+            if waited >= wait_ms {
+                return Ok(None);
+            }
+            self.port.pause(POLL_MS);
+            waited += POLL_MS;
+        }
+    }
+
+    /// Read what there is to read, up to the end of the next good frame.
+    /// Console text and garbled frames are skipped.
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let mut byte = [0];
+        while self.port.read(&mut byte)? == 1 {
+            if let Some(Ok(frame)) = self.decoder.feed(byte[0]) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Whether the turret sends `message` in answer to a command
+fn is_answer(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Ack { .. }
+            | Message::Error { .. }
+            | Message::Pose { .. }
+            | Message::Range { .. }
+            | Message::Config { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use turret_protocol::ConfigKey;
+    use turret_sim::{Object, SimConfig};
+
+    use super::*;
+    use crate::command::Command;
+
+    fn sim(scene: Vec<Object>) -> Client<SimPort> {
+        Client::new(SimPort::new(SimConfig::default(), scene))
+    }
+
+    fn run(client: &mut Client<SimPort>, args: &str) -> Result<String, Error> {
+        let args: Vec<&str> = args.split(' ').collect();
+        let mut out = Vec::new();
+        Command::parse(&args).unwrap().run(client, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn commands_play_out_on_the_simulator() {
+        let wall = Object {
+            bearing: 30.0,
+            distance: 1.5,
+        };
+        let mut client = sim(vec![wall]);
+
+        assert_eq!(run(&mut client, "move up 2").unwrap(), "yaw 0 pitch 84\n");
+        assert_eq!(run(&mut client, "fire").unwrap(), "yaw 0 pitch 84\n");
+        assert_eq!(client.port().world().borrow().shots().len(), 1);
+
+        let scan = run(&mut client, "scan 0 30 30").unwrap();
+        let lines: Vec<&str> = scan.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("no echo"), "{scan}");
+        assert!(lines[1].ends_with("range 1500 mm"), "{scan}");
+
+        assert_eq!(
+            run(&mut client, "config set broadcast-to 9").unwrap(),
+            "broadcast-to 9\n"
+        );
+        assert_eq!(
+            run(&mut client, "config get").unwrap(),
+            "yaw-rate 360\nhold-timeout 250\nhold-accelerate 1000\nhold-steps 4\nbroadcast-to 9\n"
+        );
+        assert!(matches!(
+            run(&mut client, "config set hold-steps 0"),
+            Err(Error::Turret(ErrorCode::BadPayload))
+        ));
+        assert_eq!(client.port().turret().hold_config().max_steps, 4);
+    }
+
+    #[test]
+    fn telemetry_keeps_coming() {
+        let mut client = sim(Vec::new());
+        let tail = run(&mut client, "tail-telemetry 2").unwrap();
+        // The answer to the status request, then a pose every 500 ms
+        assert!(tail.lines().count() >= 4, "{tail}");
+        assert!(tail.lines().all(|line| line == "yaw 0 pitch 100"), "{tail}");
+    }
+
+    /// A turret that prints text but never answers
+    #[derive(Default)]
+    struct Mute {
+        text: VecDeque<u8>,
+    }
+
+    impl io::Read for Mute {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.text.len());
+            for (slot, byte) in buf.iter_mut().zip(self.text.drain(..len)) {
+                *slot = byte;
+            }
+            Ok(len)
+        }
+    }
+
+    impl io::Write for Mute {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for Mute {
+        fn pause(&mut self, _: u32) {
+            self.text.extend(b"Clock: 1\r\n");
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_turret_that_doesnt_answer() {
+        let mut client = Client::new(Mute::default());
+        let get = Message::GetConfig {
+            key: ConfigKey::YawRate,
+        };
+        assert!(matches!(client.request(get), Err(Error::Timeout)));
+    }
+}
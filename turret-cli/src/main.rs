@@ -0,0 +1,74 @@
+//! Drive the turret from the command line.
+//!
+//! ```text
+//! turret-cli [--port PATH | --sim] COMMAND...
+//! ```
+//!
+//! The port defaults to `/dev/ttyUSB0`. `--sim` talks to the simulated turret
+//! instead, with nothing in the room but a wall 2 m straight ahead.
+
+use std::{io, process::ExitCode};
+
+use turret_cli::{
+    command::{Command, USAGE},
+    Client, Port, Serial, SimPort,
+};
+use turret_sim::{Object, SimConfig};
+
+const DEFAULT_PORT: &str = "/dev/ttyUSB0";
+
+fn run(port: impl Port, command: Command) -> ExitCode {
+    let mut client = Client::new(port);
+    match command.run(&mut client, &mut io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut port = None;
+    let mut sim = false;
+    while let Some(option) = args.first().filter(|arg| arg.starts_with("--")).cloned() {
+        match option.as_str() {
+            "--port" if args.len() > 1 => port = Some(args.remove(1)),
+            "--sim" => sim = true,
+            "--help" => {
+                println!("turret-cli [--port PATH | --sim] COMMAND...\n\n{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                eprintln!("Unknown option: {option}");
+                return ExitCode::FAILURE;
+            }
+        }
+        args.remove(0);
+    }
+
+    let command = match Command::parse(&args) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("{error}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if sim {
+        let wall = Object {
+            bearing: 0.0,
+            distance: 2.0,
+        };
+        return run(SimPort::new(SimConfig::default(), vec![wall]), command);
+    }
+    let path = port.unwrap_or_else(|| DEFAULT_PORT.into());
+    match Serial::open(&path) {
+        Ok(serial) => run(serial, command),
+        Err(error) => {
+            eprintln!("Can't open {path}: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
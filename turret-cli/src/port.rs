@@ -0,0 +1,199 @@
+//! What the [`Client`](crate::Client) talks through.
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+#[cfg(target_os = "linux")]
+use std::{fs::OpenOptions, process::Command};
+
+use rangefinder::link::{wire, Frame, Link, TELEMETRY_MS};
+use turret_sim::{run_for, simulate, Object, SharedWorld, SimConfig, SimTurret};
+
+/// Same as `default_serial!` in the firmware and `ravedude nano-new -cb 57600`
+pub const BAUD: u32 = 57_600;
+
+/// Opening the port resets the Nano, and the bootloader waits this long
+/// before starting the firmware
+#[cfg(target_os = "linux")]
+const BOOT_MS: u64 = 2_000;
+
+/// A byte stream to the turret. Reads don't wait: with nothing to read they
+/// return `Ok(0)`, and the client calls [`pause`](Port::pause) before trying
+/// again.
+pub trait Port: Read + Write {
+    /// Let `ms` go by.
+    fn pause(&mut self, ms: u32);
+}
+
+/// The turret's USB serial port.
+#[derive(Debug)]
+pub struct Serial {
+    file: File,
+}
+
+impl Serial {
+    /// Open and set up a serial device such as `/dev/ttyUSB0`, then wait for
+    /// the turret to boot.
+    ///
+    /// Linux only: the line settings are made by running GNU `stty -F`, which
+    /// other systems don't have.
+    #[cfg(target_os = "linux")]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        // Raw bytes both ways, and reads that return straight away
+        let status = Command::new("stty")
+            .arg("-F")
+            .arg(path)
+            .arg(BAUD.to_string())
+            .args(["raw", "-echo", "min", "0", "time", "0"])
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "stty couldn't set up {}",
+                path.display()
+            )));
+        }
+        thread::sleep(Duration::from_millis(BOOT_MS));
+        Ok(Self { file })
+    }
+
+    /// Setting up the line needs GNU `stty -F`, so this always fails here.
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "setting up {} needs Linux, try --sim instead",
+                path.as_ref().display()
+            ),
+        ))
+    }
+}
+
+impl Read for Serial {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Serial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Port for Serial {
+    fn pause(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
+
+/// The simulated turret, answering the way the firmware's main loop does.
+/// Its time only moves on [`pause`](Port::pause).
+pub struct SimPort {
+    turret: SimTurret,
+    world: SharedWorld,
+    link: Link,
+    /// Written by the turret, waiting to be read
+    out: VecDeque<u8>,
+    /// How long the turret has been running
+    now_ms: u32,
+    since_telemetry: u32,
+}
+
+/// Console text from the simulated turret
+struct Text<'a>(&'a mut VecDeque<u8>);
+
+impl ufmt::uWrite for Text<'_> {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.0.extend(s.bytes());
+        Ok(())
+    }
+}
+
+impl SimPort {
+    pub fn new(config: SimConfig, scene: Vec<Object>) -> Self {
+        let (turret, world) = simulate(config, scene);
+        Self {
+            turret,
+            world,
+            link: Link::new(),
+            out: VecDeque::new(),
+            now_ms: 0,
+            since_telemetry: 0,
+        }
+    }
+
+    pub fn turret(&self) -> &SimTurret {
+        &self.turret
+    }
+
+    pub fn world(&self) -> &SharedWorld {
+        &self.world
+    }
+
+    fn send(&mut self, frame: &Frame) {
+        self.out.extend(wire(frame));
+    }
+}
+
+impl Read for SimPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.out.len());
+        for (slot, byte) in buf.iter_mut().zip(self.out.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for SimPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            let reply =
+                self.turret
+                    .dispatch(&mut self.link, byte, self.now_ms, &mut Text(&mut self.out));
+            if let Some(reply) = reply {
+                self.send(&reply);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for SimPort {
+    fn pause(&mut self, ms: u32) {
+        for _ in 0..ms {
+            run_for(&mut self.turret, &self.world, 1);
+            if let Some(reply) = self.turret.poll_range(&mut Text(&mut self.out)) {
+                self.send(&reply);
+            }
+            self.now_ms = self.now_ms.wrapping_add(1);
+            self.since_telemetry += 1;
+            if self.since_telemetry >= TELEMETRY_MS {
+                self.since_telemetry = 0;
+                if self.link.is_binary() {
+                    let pose = self.link.telemetry(self.turret.pose_message());
+                    self.send(&pose);
+                }
+            }
+        }
+    }
+}
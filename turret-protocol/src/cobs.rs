@@ -0,0 +1,117 @@
+//! Consistent Overhead Byte Stuffing.
+//!
+//! Rewrites a frame so it has no zero bytes in it, at the cost of one byte
+//! every 254, which leaves zero free to mark where frames start and end.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CobsError {
+    /// The output buffer is too small
+    Overflow,
+    /// Not something [`encode`] could have produced
+    Invalid,
+}
+
+/// Most bytes [`encode`] can turn `len` bytes into
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Stuff `data` into `out`, returning how many bytes were written. There is
+/// no trailing zero.
+pub fn encode(data: &[u8], out: &mut [u8]) -> Result<usize, CobsError> {
+    let mut code_at = 0;
+    let mut written = 1;
+    let mut code = 1u8;
+    for (i, &byte) in data.iter().enumerate() {
+        if byte != 0 {
+            *out.get_mut(written).ok_or(CobsError::Overflow)? = byte;
+            written += 1;
+            code += 1;
+        }
+        // A full block at the very end needs no empty one after it
+        let full = code == 0xFF && i + 1 < data.len();
+        if byte == 0 || full {
+            *out.get_mut(code_at).ok_or(CobsError::Overflow)? = code;
+            code_at = written;
+            written += 1;
+            code = 1;
+        }
+    }
+    *out.get_mut(code_at).ok_or(CobsError::Overflow)? = code;
+    Ok(written)
+}
+
+/// Undo [`encode`], returning how many bytes were written to `out`.
+pub fn decode(data: &[u8], out: &mut [u8]) -> Result<usize, CobsError> {
+    let mut read = 0;
+    let mut written = 0;
+    while read < data.len() {
+        let code = data[read];
+        if code == 0 {
+            return Err(CobsError::Invalid);
+        }
+        read += 1;
+        let end = read + code as usize - 1;
+        let block = data.get(read..end).ok_or(CobsError::Invalid)?;
+        if block.contains(&0) {
+            return Err(CobsError::Invalid);
+        }
+        out.get_mut(written..written + block.len())
+            .ok_or(CobsError::Overflow)?
+            .copy_from_slice(block);
+        written += block.len();
+        read = end;
+        // Every block but a full one, or the last, stands for a zero
+        if code != 0xFF && read < data.len() {
+            *out.get_mut(written).ok_or(CobsError::Overflow)? = 0;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0; max_encoded_len(data.len())];
+        let len = encode(data, &mut encoded).unwrap();
+        encoded.truncate(len);
+        assert!(!encoded.contains(&0), "{encoded:?}");
+
+        let mut decoded = vec![0; data.len()];
+        let len = decode(&encoded, &mut decoded).unwrap();
+        assert_eq!(&decoded[..len], data);
+        encoded
+    }
+
+    #[test]
+    fn stuffs_out_every_zero() {
+        assert_eq!(round_trip(&[]), [1]);
+        assert_eq!(round_trip(&[0]), [1, 1]);
+        assert_eq!(
+            round_trip(&[0x11, 0x22, 0x00, 0x33]),
+            [3, 0x11, 0x22, 2, 0x33]
+        );
+        assert_eq!(round_trip(&[0x11, 0x00, 0x00]), [2, 0x11, 1, 1]);
+
+        // Runs long enough to need more than one block
+        let long: Vec<u8> = (1..=254).collect();
+        assert_eq!(round_trip(&long).len(), 255);
+        let longer: Vec<u8> = (0..600).map(|i| (i % 7) as u8).collect();
+        round_trip(&longer);
+    }
+
+    #[test]
+    fn rejects_what_encode_wouldnt_make() {
+        let mut out = [0; 8];
+        assert_eq!(decode(&[3, 1], &mut out), Err(CobsError::Invalid));
+        assert_eq!(decode(&[2, 0], &mut out), Err(CobsError::Invalid));
+        assert_eq!(
+            decode(&[5, 1, 2, 3, 4], &mut [0; 2]),
+            Err(CobsError::Overflow)
+        );
+        assert_eq!(encode(&[1, 2, 3], &mut [0; 3]), Err(CobsError::Overflow));
+    }
+}
@@ -0,0 +1,29 @@
+//! CRC-16/CCITT-FALSE: polynomial `0x1021`, starting from `0xFFFF`, no
+//! reflection. Worked out a bit at a time, which is slow but keeps a 512 byte
+//! table out of the Nano's flash.
+
+const POLY: u16 = 0x1021;
+
+/// CRC of `bytes`
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ POLY
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_standard_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+}
@@ -0,0 +1,327 @@
+//! Frames on the wire.
+//!
+//! A frame is a version byte, the message ID, a sequence number, the payload
+//! and a CRC-16 of all of that, COBS-encoded and with a zero either side.
+//! The leading zero keeps any text that was written to the port before the
+//! frame out of it.
+
+use crate::{
+    cobs::{self, max_encoded_len},
+    crc::crc16,
+    message::{ErrorCode, Message, PayloadError, MAX_PAYLOAD},
+    VERSION,
+};
+
+/// Version, message ID and sequence number
+const HEADER: usize = 3;
+/// CRC, little endian
+const CRC: usize = 2;
+const MAX_RAW: usize = HEADER + MAX_PAYLOAD + CRC;
+
+/// Longest frame on the wire, both zeros included
+pub const MAX_FRAME: usize = max_encoded_len(MAX_RAW) + 2;
+
+/// A message and its sequence number. Replies carry the sequence number of
+/// the command they answer, so the host can tell which is which.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u8,
+    pub message: Message,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// More bytes between zeros than any frame has
+    TooLong,
+    /// Not valid COBS, or too short to hold a header and CRC
+    Malformed,
+    /// The CRC didn't match, so the frame got garbled or wasn't one at all
+    Crc,
+    /// Frame for another version of the protocol
+    Version {
+        seq: u8,
+        version: u8,
+    },
+    UnknownMessage {
+        seq: u8,
+        id: u8,
+    },
+    /// The payload didn't fit the message ID
+    BadPayload {
+        seq: u8,
+    },
+}
+
+impl DecodeError {
+    /// The error to send back, for frames that made it far enough to tell
+    /// what they were trying to say.
+    pub fn reply(&self) -> Option<Frame> {
+        let (seq, code) = match *self {
+            DecodeError::Version { seq, .. } => (seq, ErrorCode::Version),
+            DecodeError::UnknownMessage { seq, .. } => (seq, ErrorCode::UnknownMessage),
+            DecodeError::BadPayload { seq } => (seq, ErrorCode::BadPayload),
+            _ => return None,
+        };
+        Some(Frame {
+            seq,
+            message: Message::Error { seq, code },
+        })
+    }
+}
+
+impl Frame {
+    pub const fn new(seq: u8, message: Message) -> Self {
+        Self { seq, message }
+    }
+
+    /// Put the frame into `out` ready to be written out as is, returning its
+    /// length.
+    pub fn encode(&self, out: &mut [u8; MAX_FRAME]) -> usize {
+        let mut raw = [0; MAX_RAW];
+        raw[..HEADER].copy_from_slice(&[VERSION, self.message.id(), self.seq]);
+        let mut payload = [0; MAX_PAYLOAD];
+        let len = self.message.write_payload(&mut payload);
+        raw[HEADER..HEADER + len].copy_from_slice(&payload[..len]);
+        let len = HEADER + len;
+        let crc = crc16(&raw[..len]);
+        raw[len..len + CRC].copy_from_slice(&crc.to_le_bytes());
+        let len = len + CRC;
+
+        out[0] = 0;
+        // `MAX_FRAME` leaves room for the biggest message
+        let encoded = cobs::encode(&raw[..len], &mut out[1..]).unwrap_or(0);
+        out[1 + encoded] = 0;
+        encoded + 2
+    }
+
+    /// Read back the bytes between two zeros.
+    pub fn decode(encoded: &[u8]) -> Result<Self, DecodeError> {
+        let mut raw = [0; MAX_RAW];
+        let len = cobs::decode(encoded, &mut raw).map_err(|_| DecodeError::Malformed)?;
+        if len < HEADER + CRC {
+            return Err(DecodeError::Malformed);
+        }
+        let (body, crc) = raw[..len].split_at(len - CRC);
+        if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return Err(DecodeError::Crc);
+        }
+
+        let [version, id, seq] = [body[0], body[1], body[2]];
+        if version != VERSION {
+            return Err(DecodeError::Version { seq, version });
+        }
+        let message = Message::read_payload(id, &body[HEADER..]).map_err(|error| match error {
+            PayloadError::UnknownMessage => DecodeError::UnknownMessage { seq, id },
+            PayloadError::BadPayload => DecodeError::BadPayload { seq },
+        })?;
+        Ok(Self { seq, message })
+    }
+}
+
+/// Picks frames out of a stream of bytes, one byte at a time.
+#[derive(Clone, Debug)]
+pub struct Decoder {
+    buffer: [u8; MAX_FRAME],
+    len: usize,
+    /// The frame coming in has already run past the buffer
+    overflowed: bool,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_FRAME],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Take the next byte off the wire. Returns the frame, or why it wasn't
+    /// one, at the zero that ends it. Nothing between two zeros, like the
+    /// leading zero of a frame right after the trailing one of the last, is
+    /// skipped.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<Frame, DecodeError>> {
+        if byte != 0 {
+            match self.buffer.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflowed = true,
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(DecodeError::TooLong));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(Frame::decode(&self.buffer[..len]))
+    }
+
+    /// Whether part of a frame has come in
+    pub fn is_receiving(&self) -> bool {
+        self.len > 0 || self.overflowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConfigKey;
+
+    fn decode_all(bytes: &[u8]) -> Vec<Result<Frame, DecodeError>> {
+        let mut decoder = Decoder::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.feed(byte))
+            .collect()
+    }
+
+    fn wire(frame: Frame) -> Vec<u8> {
+        let mut out = [0; MAX_FRAME];
+        let len = frame.encode(&mut out);
+        out[..len].to_vec()
+    }
+
+    #[test]
+    fn every_message_survives_the_trip() {
+        let messages = [
+            Message::Action {
+                action: 0x83,
+                times: 2,
+            },
+            Message::TurnTo { degrees: -45 },
+            Message::MeasureRange,
+            Message::GetStatus,
+            Message::GetConfig {
+                key: ConfigKey::BroadcastTo,
+            },
+            Message::SetConfig {
+                key: ConfigKey::HoldTimeout,
+                value: 300,
+            },
+            Message::Ack { seq: 7 },
+            Message::Error {
+                seq: 7,
+                code: ErrorCode::Failed,
+            },
+            Message::Pose {
+                yaw_degrees: 170,
+                pitch: 100,
+                flags: 0b101,
+            },
+            Message::Range {
+                yaw_degrees: 0,
+                pitch: 0,
+                distance_mm: Some(1234),
+            },
+            Message::Range {
+                yaw_degrees: -1,
+                pitch: 10,
+                distance_mm: None,
+            },
+            Message::IrEvent {
+                protocol: 0,
+                addr: 0x100,
+                cmd: 0x1C,
+                repeat: true,
+            },
+            Message::Config {
+                key: ConfigKey::BroadcastTo,
+                value: -1,
+            },
+        ];
+
+        let mut stream = Vec::new();
+        for (seq, message) in messages.iter().enumerate() {
+            let bytes = wire(Frame::new(seq as u8, *message));
+            assert!(bytes.len() <= MAX_FRAME);
+            assert!(!bytes[1..bytes.len() - 1].contains(&0));
+            stream.extend(bytes);
+        }
+        let decoded = decode_all(&stream);
+        assert_eq!(decoded.len(), messages.len());
+        for (seq, (decoded, message)) in decoded.iter().zip(messages).enumerate() {
+            assert_eq!(decoded, &Ok(Frame::new(seq as u8, message)));
+        }
+    }
+
+    #[test]
+    fn skips_text_and_garbage_between_frames() {
+        let ack = Frame::new(1, Message::Ack { seq: 1 });
+        let mut stream = b"FIRE\r\n".to_vec();
+        stream.extend(wire(ack));
+        stream.extend(b"Up\n");
+        let mut garbled = wire(ack);
+        garbled[3] ^= 0x40;
+        stream.extend(garbled);
+        stream.extend(vec![0x55; MAX_FRAME * 2]);
+        stream.push(0);
+        stream.extend(wire(ack));
+
+        assert_eq!(
+            decode_all(&stream),
+            [
+                Err(DecodeError::Malformed),
+                Ok(ack),
+                Err(DecodeError::Malformed),
+                Err(DecodeError::Crc),
+                Err(DecodeError::TooLong),
+                Ok(ack),
+            ]
+        );
+    }
+
+    #[test]
+    fn says_what_was_wrong_with_frames_it_could_read() {
+        let raw_frame = |body: &[u8]| {
+            let mut raw = body.to_vec();
+            raw.extend(crc16(body).to_le_bytes());
+            let mut encoded = [0; MAX_FRAME];
+            let len = cobs::encode(&raw, &mut encoded).unwrap();
+            Frame::decode(&encoded[..len])
+        };
+
+        let error = raw_frame(&[VERSION + 1, 0x04, 9]).unwrap_err();
+        assert_eq!(error, DecodeError::Version { seq: 9, version: 2 });
+        assert_eq!(
+            error.reply(),
+            Some(Frame::new(
+                9,
+                Message::Error {
+                    seq: 9,
+                    code: ErrorCode::Version
+                }
+            ))
+        );
+        assert_eq!(
+            raw_frame(&[VERSION, 0x7F, 3]),
+            Err(DecodeError::UnknownMessage { seq: 3, id: 0x7F })
+        );
+        // Turn to where?
+        assert_eq!(
+            raw_frame(&[VERSION, 0x02, 4, 45]),
+            Err(DecodeError::BadPayload { seq: 4 })
+        );
+        assert_eq!(
+            raw_frame(&[VERSION, 0x04, 5, 0]),
+            Err(DecodeError::BadPayload { seq: 5 })
+        );
+        // No such setting
+        assert_eq!(
+            raw_frame(&[VERSION, 0x05, 6, 0x7F]),
+            Err(DecodeError::BadPayload { seq: 6 })
+        );
+        assert_eq!(DecodeError::Crc.reply(), None);
+    }
+}
@@ -0,0 +1,19 @@
+//! Binary protocol between the rangefinder turret and a host application,
+//! shared by the firmware and the host so both agree on every byte.
+//!
+//! Messages go over the same USART as the serial console, framed with COBS
+//! so a frame can always be told apart from console text. See [`frame`] for
+//! the layout and [`Message`] for what can be said.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod cobs;
+pub mod crc;
+pub mod frame;
+mod message;
+
+pub use frame::{DecodeError, Decoder, Frame, MAX_FRAME};
+pub use message::{status, ConfigKey, ErrorCode, Message, MAX_PAYLOAD};
+
+/// Goes first in every frame. Bumped whenever a message changes shape.
+pub const VERSION: u8 = 1;
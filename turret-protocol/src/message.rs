@@ -0,0 +1,284 @@
+//! What goes in a frame.
+//!
+//! Every message has a one byte ID and a fixed size payload, all multi-byte
+//! fields little endian.
+
+/// Why a command wasn't carried out, sent back in [`Message::Error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The frame was for another version of the protocol
+    Version,
+    /// No message has that ID
+    UnknownMessage,
+    /// The payload was the wrong size or held a value out of range
+    BadPayload,
+    /// The turret doesn't take that message, e.g. telemetry sent to it
+    Unsupported,
+    /// The command was understood but couldn't be carried out
+    Failed,
+}
+
+impl ErrorCode {
+    fn from_byte(byte: u8) -> Option<Self> {
+        [
+            ErrorCode::Version,
+            ErrorCode::UnknownMessage,
+            ErrorCode::BadPayload,
+            ErrorCode::Unsupported,
+            ErrorCode::Failed,
+        ]
+        .get(byte as usize)
+        .copied()
+    }
+}
+
+/// Settings that can be read with [`Message::GetConfig`] and changed with
+/// [`Message::SetConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigKey {
+    /// How fast the yaw servo turns flat out, in degrees per second
+    YawRate,
+    /// How long after the last repeat a held button counts as let go, in ms
+    HoldTimeout,
+    /// How long a button has to be held for each bigger step, in ms
+    HoldAccelerate,
+    /// Biggest step a held button takes, `1` for no acceleration
+    HoldSteps,
+    /// Address a leader sends to, `-1` for everyone
+    BroadcastTo,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; 5] = [
+        ConfigKey::YawRate,
+        ConfigKey::HoldTimeout,
+        ConfigKey::HoldAccelerate,
+        ConfigKey::HoldSteps,
+        ConfigKey::BroadcastTo,
+    ];
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
+
+/// Flags in [`Message::Pose`]
+pub mod status {
+    pub const MOVING: u8 = 1 << 0;
+    pub const SENTRY: u8 = 1 << 1;
+    pub const LEADING: u8 = 1 << 2;
+    pub const LEARNING: u8 = 1 << 3;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    // Host to turret
+    /// Do what a remote button does, `times` times over. `action` is the
+    /// firmware's `TurretAction::to_byte`.
+    Action { action: u8, times: u8 },
+    /// Turn to face `degrees` from straight ahead, positive to the left
+    TurnTo { degrees: i16 },
+    /// Take a range reading, answered with [`Message::Range`]
+    MeasureRange,
+    /// Answered with [`Message::Pose`]
+    GetStatus,
+    /// Answered with [`Message::Config`]
+    GetConfig { key: ConfigKey },
+    /// Change a setting until the turret is reset
+    SetConfig { key: ConfigKey, value: i16 },
+
+    // Turret to host
+    /// The command with this sequence number was carried out
+    Ack { seq: u8 },
+    /// The command with this sequence number wasn't
+    Error { seq: u8, code: ErrorCode },
+    /// Where the turret is pointing and what it is up to
+    Pose {
+        yaw_degrees: i16,
+        pitch: i16,
+        /// Bits from [`status`]
+        flags: u8,
+    },
+    /// A range reading, `None` if nothing echoed back
+    Range {
+        yaw_degrees: i16,
+        pitch: i16,
+        distance_mm: Option<u16>,
+    },
+    /// A remote button press the turret picked up
+    IrEvent {
+        /// The firmware's `ir::Protocol`, as a byte
+        protocol: u8,
+        addr: u16,
+        cmd: u8,
+        repeat: bool,
+    },
+    /// The value of a setting
+    Config { key: ConfigKey, value: i16 },
+}
+
+/// Longest payload of any message
+pub const MAX_PAYLOAD: usize = 6;
+
+/// Stands for `None` in [`Message::Range`]
+const NO_ECHO: u16 = u16::MAX;
+
+/// Why a payload couldn't be read back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PayloadError {
+    UnknownMessage,
+    BadPayload,
+}
+
+impl Message {
+    pub fn id(&self) -> u8 {
+        match self {
+            Message::Action { .. } => 0x01,
+            Message::TurnTo { .. } => 0x02,
+            Message::MeasureRange => 0x03,
+            Message::GetStatus => 0x04,
+            Message::GetConfig { .. } => 0x05,
+            Message::SetConfig { .. } => 0x06,
+            Message::Ack { .. } => 0x10,
+            Message::Error { .. } => 0x11,
+            Message::Pose { .. } => 0x20,
+            Message::Range { .. } => 0x21,
+            Message::IrEvent { .. } => 0x22,
+            Message::Config { .. } => 0x23,
+        }
+    }
+
+    /// Write the payload to the start of `out`, returning its length.
+    pub(crate) fn write_payload(&self, out: &mut [u8; MAX_PAYLOAD]) -> usize {
+        let mut payload = Writer { out, len: 0 };
+        match *self {
+            Message::Action { action, times } => payload.u8(action).u8(times),
+            Message::TurnTo { degrees } => payload.i16(degrees),
+            Message::MeasureRange | Message::GetStatus => &mut payload,
+            Message::GetConfig { key } => payload.u8(key as u8),
+            Message::SetConfig { key, value } | Message::Config { key, value } => {
+                payload.u8(key as u8).i16(value)
+            }
+            Message::Ack { seq } => payload.u8(seq),
+            Message::Error { seq, code } => payload.u8(seq).u8(code as u8),
+            Message::Pose {
+                yaw_degrees,
+                pitch,
+                flags,
+            } => payload.i16(yaw_degrees).i16(pitch).u8(flags),
+            Message::Range {
+                yaw_degrees,
+                pitch,
+                distance_mm,
+            } => payload
+                .i16(yaw_degrees)
+                .i16(pitch)
+                .u16(distance_mm.unwrap_or(NO_ECHO)),
+            Message::IrEvent {
+                protocol,
+                addr,
+                cmd,
+                repeat,
+            } => payload.u8(protocol).u16(addr).u8(cmd).u8(repeat as u8),
+        };
+        payload.len
+    }
+
+    pub(crate) fn read_payload(id: u8, payload: &[u8]) -> Result<Self, PayloadError> {
+        let mut r = Reader { payload };
+        let message = match id {
+            0x01 => Message::Action {
+                action: r.u8()?,
+                times: r.u8()?,
+            },
+            0x02 => Message::TurnTo { degrees: r.i16()? },
+            0x03 => Message::MeasureRange,
+            0x04 => Message::GetStatus,
+            0x05 => Message::GetConfig { key: r.key()? },
+            0x06 => Message::SetConfig {
+                key: r.key()?,
+                value: r.i16()?,
+            },
+            0x10 => Message::Ack { seq: r.u8()? },
+            0x11 => Message::Error {
+                seq: r.u8()?,
+                code: ErrorCode::from_byte(r.u8()?).ok_or(PayloadError::BadPayload)?,
+            },
+            0x20 => Message::Pose {
+                yaw_degrees: r.i16()?,
+                pitch: r.i16()?,
+                flags: r.u8()?,
+            },
+            0x21 => Message::Range {
+                yaw_degrees: r.i16()?,
+                pitch: r.i16()?,
+                distance_mm: Some(r.u16()?).filter(|&mm| mm != NO_ECHO),
+            },
+            0x22 => Message::IrEvent {
+                protocol: r.u8()?,
+                addr: r.u16()?,
+                cmd: r.u8()?,
+                repeat: match r.u8()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(PayloadError::BadPayload),
+                },
+            },
+            0x23 => Message::Config {
+                key: r.key()?,
+                value: r.i16()?,
+            },
+            _ => return Err(PayloadError::UnknownMessage),
+        };
+        if !r.payload.is_empty() {
+            return Err(PayloadError::BadPayload);
+        }
+        Ok(message)
+    }
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8; MAX_PAYLOAD],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.out[self.len] = value;
+        self.len += 1;
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        let [lo, hi] = value.to_le_bytes();
+        self.u8(lo).u8(hi)
+    }
+
+    fn i16(&mut self, value: i16) -> &mut Self {
+        self.u16(value as u16)
+    }
+}
+
+struct Reader<'a> {
+    payload: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, PayloadError> {
+        let (&first, rest) = self.payload.split_first().ok_or(PayloadError::BadPayload)?;
+        self.payload = rest;
+        Ok(first)
+    }
+
+    fn u16(&mut self) -> Result<u16, PayloadError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn i16(&mut self) -> Result<i16, PayloadError> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn key(&mut self) -> Result<ConfigKey, PayloadError> {
+        ConfigKey::from_byte(self.u8()?).ok_or(PayloadError::BadPayload)
+    }
+}
@@ -0,0 +1,482 @@
+//! A physics model of the HackPack turret, so the firmware's `Turret` logic can
+//! be exercised on a dev box.
+//!
+//! All of the simulated hardware shares one [`World`]. Time only moves when it
+//! is told to, which keeps every run deterministic.
+
+use std::{cell::RefCell, rc::Rc};
+
+use rangefinder::{
+    hal::{host::QueuedCommands, Actuator, RangeSensor, Timebase},
+    hc_sr04::HcSr04Error,
+    turret::{
+        Turret, PITCH_MAX, PITCH_MIN, PITCH_START, ROLL_PRECISION, ROLL_STOP_SPEED,
+        YAW_DEG_PER_SEC, YAW_STOP_SPEED,
+    },
+};
+use uom::si::{f32::Length, length::meter};
+
+/// Servo value that means "stop" for a continuous-rotation servo.
+const SERVO_CENTER: f32 = 90.0;
+
+/// How the simulated hardware behaves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimConfig {
+    /// Yaw rotation speed with the servo driven flat out, in degrees per second.
+    pub yaw_deg_per_sec: f32,
+    /// How fast the pitch servo slews towards its target, in degrees per second.
+    pub pitch_deg_per_sec: f32,
+    /// Darts loaded in the barrel at the start.
+    pub darts: u8,
+    /// Half-angle of the HC-SR04's detection cone, in degrees.
+    pub beam_half_angle: f32,
+    /// Furthest echo the HC-SR04 can pick up, in meters.
+    pub max_range: f32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            yaw_deg_per_sec: YAW_DEG_PER_SEC,
+            pitch_deg_per_sec: 300.0,
+            darts: 6,
+            beam_half_angle: 15.0,
+            max_range: 4.0,
+        }
+    }
+}
+
+/// Something in the room the range finder can see.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Object {
+    /// Direction of the object, in degrees. Positive is to the turret's left.
+    pub bearing: f32,
+    /// Distance from the turret, in meters.
+    pub distance: f32,
+}
+
+/// A dart that left the barrel, and where the turret was pointing at the time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shot {
+    pub time_ms: u32,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+/// State of the simulated turret and its surroundings.
+#[derive(Debug)]
+pub struct World {
+    config: SimConfig,
+    scene: Vec<Object>,
+
+    time_ms: u32,
+
+    /// Last value written to each servo
+    yaw_command: i16,
+    pitch_command: i16,
+    roll_command: i16,
+
+    yaw: f32,
+    pitch: f32,
+
+    /// Milliseconds of roll spin not yet turned into a dart
+    roll_progress: f32,
+    darts_left: u8,
+    shots: Vec<Shot>,
+}
+
+pub type SharedWorld = Rc<RefCell<World>>;
+
+impl World {
+    pub fn new(config: SimConfig, scene: Vec<Object>) -> Self {
+        Self {
+            config,
+            scene,
+
+            time_ms: 0,
+
+            yaw_command: YAW_STOP_SPEED,
+            pitch_command: PITCH_START,
+            roll_command: ROLL_STOP_SPEED,
+
+            yaw: 0.0,
+            pitch: PITCH_START as f32,
+
+            roll_progress: 0.0,
+            darts_left: config.darts,
+            shots: Vec::new(),
+        }
+    }
+
+    pub fn time_ms(&self) -> u32 {
+        self.time_ms
+    }
+
+    /// Direction the turret is facing, in degrees, normalized to `-180..180`.
+    pub fn yaw(&self) -> f32 {
+        normalize(self.yaw)
+    }
+
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    pub fn darts_left(&self) -> u8 {
+        self.darts_left
+    }
+
+    pub fn shots(&self) -> &[Shot] {
+        &self.shots
+    }
+
+    /// Put something new in the room.
+    pub fn add_object(&mut self, object: Object) {
+        self.scene.push(object);
+    }
+
+    /// Let `ms` milliseconds pass, one millisecond at a time.
+    pub fn advance(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.time_ms = self.time_ms.wrapping_add(1);
+
+        // Continuous rotation: the distance from center is the speed
+        self.yaw += speed(self.yaw_command) * self.config.yaw_deg_per_sec / 1_000.0;
+
+        // Positional: slew towards the target, which can't leave the limits
+        let target = (self.pitch_command.clamp(PITCH_MIN, PITCH_MAX)) as f32;
+        let max_step = self.config.pitch_deg_per_sec / 1_000.0;
+        self.pitch += (target - self.pitch).clamp(-max_step, max_step);
+
+        // Continuous rotation, one dart every `ROLL_PRECISION` ms at full speed
+        self.roll_progress += speed(self.roll_command).abs();
+        if self.roll_progress >= ROLL_PRECISION as f32 {
+            self.roll_progress -= ROLL_PRECISION as f32;
+            if self.darts_left > 0 {
+                self.darts_left -= 1;
+                self.shots.push(Shot {
+                    time_ms: self.time_ms,
+                    yaw: self.yaw(),
+                    pitch: self.pitch,
+                });
+            }
+        }
+    }
+
+    /// What the HC-SR04 would report right now.
+    pub fn range(&self) -> Result<Length, HcSr04Error> {
+        let yaw = self.yaw();
+        self.scene
+            .iter()
+            .filter(|o| normalize(o.bearing - yaw).abs() <= self.config.beam_half_angle)
+            .filter(|o| o.distance <= self.config.max_range)
+            .map(|o| o.distance)
+            .min_by(f32::total_cmp)
+            .map(Length::new::<meter>)
+            .ok_or(HcSr04Error::NoEcho)
+    }
+}
+
+/// Signed servo speed in `-1.0..=1.0` for a continuous-rotation servo value
+fn speed(value: i16) -> f32 {
+    ((value as f32 - SERVO_CENTER) / SERVO_CENTER).clamp(-1.0, 1.0)
+}
+
+fn normalize(degrees: f32) -> f32 {
+    (degrees + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// Continuous-rotation yaw servo.
+#[derive(Debug)]
+pub struct SimYaw(SharedWorld);
+
+impl Actuator for SimYaw {
+    fn write(&mut self, value: i16) {
+        self.0.borrow_mut().yaw_command = value;
+    }
+}
+
+/// Positional pitch servo.
+#[derive(Debug)]
+pub struct SimPitch(SharedWorld);
+
+impl Actuator for SimPitch {
+    fn write(&mut self, value: i16) {
+        self.0.borrow_mut().pitch_command = value;
+    }
+}
+
+/// Continuous-rotation roll servo that pushes the darts out.
+#[derive(Debug)]
+pub struct SimRoll(SharedWorld);
+
+impl Actuator for SimRoll {
+    fn write(&mut self, value: i16) {
+        self.0.borrow_mut().roll_command = value;
+    }
+}
+
+/// HC-SR04 looking at the [`World`]'s scene.
+#[derive(Debug)]
+pub struct SimRangeFinder(SharedWorld);
+
+impl RangeSensor for SimRangeFinder {
+    fn measure_distance(&mut self) -> Result<Length, HcSr04Error> {
+        self.0.borrow().range()
+    }
+}
+
+/// Clock that drives the [`World`] forward whenever the turret waits.
+#[derive(Debug)]
+pub struct SimTimebase(SharedWorld);
+
+impl Timebase for SimTimebase {
+    fn now_ms(&self) -> u32 {
+        self.0.borrow().time_ms()
+    }
+
+    fn delay_ms(&mut self, ms: u16) {
+        self.0.borrow_mut().advance(ms as u32);
+    }
+}
+
+pub type SimTurret = Turret<SimYaw, SimPitch, SimRoll, SimRangeFinder, SimTimebase, QueuedCommands>;
+
+/// Build a turret wired up to a freshly created world.
+pub fn simulate(config: SimConfig, scene: Vec<Object>) -> (SimTurret, SharedWorld) {
+    let world = Rc::new(RefCell::new(World::new(config, scene)));
+    let turret = Turret::from_parts(
+        SimYaw(world.clone()),
+        SimPitch(world.clone()),
+        SimRoll(world.clone()),
+        SimRangeFinder(world.clone()),
+        SimTimebase(world.clone()),
+        QueuedCommands::new(),
+    );
+    (turret, world)
+}
+
+/// Let `ms` milliseconds pass, polling the turret every millisecond like the
+/// firmware's main loop would.
+pub fn run_for(turret: &mut SimTurret, world: &SharedWorld, ms: u32) {
+    turret.poll();
+    for _ in 0..ms {
+        world.borrow_mut().advance(1);
+        turret.poll();
+    }
+}
+
+/// Keep time running until the turret has finished every queued move, and
+/// any sentry sweep is over.
+pub fn settle(turret: &mut SimTurret, world: &SharedWorld) {
+    turret.poll();
+    while turret.is_moving() || turret.sentry().is_active() {
+        world.borrow_mut().advance(1);
+        turret.poll();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rangefinder::{
+        ir,
+        range_map::{RangeMap, Reading},
+        turret::{PITCH_MOVE_SPEED, STOP_SETTLE, YAW_PRECISION},
+    };
+    use uom::si::{angle::degree, f32::Angle};
+
+    use super::*;
+
+    /// Serial port that throws everything away
+    struct Sink;
+
+    impl ufmt::uWrite for Sink {
+        type Error = core::convert::Infallible;
+
+        fn write_str(&mut self, _: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn quiet() -> (SimTurret, SharedWorld) {
+        simulate(SimConfig::default(), Vec::new())
+    }
+
+    #[test]
+    fn yaw_turns_while_pulsed() {
+        let (mut turret, world) = quiet();
+
+        turret.move_left(1);
+        settle(&mut turret, &world);
+        let expected = 360.0 * YAW_PRECISION as f32 / 1_000.0;
+        assert!((world.borrow().yaw() - expected).abs() < 0.01);
+
+        turret.move_right(2);
+        settle(&mut turret, &world);
+        assert!((world.borrow().yaw() + expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn pitch_is_clamped() {
+        let (mut turret, world) = quiet();
+
+        turret.move_down(1);
+        settle(&mut turret, &world);
+        assert_eq!(
+            world.borrow().pitch(),
+            (PITCH_START + PITCH_MOVE_SPEED) as f32
+        );
+
+        turret.move_up(100);
+        settle(&mut turret, &world);
+        assert_eq!(world.borrow().pitch(), PITCH_MIN as f32);
+    }
+
+    #[test]
+    fn fire_shoots_one_dart() {
+        let (mut turret, world) = quiet();
+
+        turret.fire();
+        settle(&mut turret, &world);
+        assert_eq!(world.borrow().shots().len(), 1);
+        assert_eq!(world.borrow().darts_left(), 5);
+    }
+
+    #[test]
+    fn fire_all_empties_the_barrel() {
+        let (mut turret, world) = quiet();
+
+        turret.fire_all();
+        turret.fire();
+        settle(&mut turret, &world);
+        assert_eq!(world.borrow().shots().len(), 6);
+        assert_eq!(world.borrow().darts_left(), 0);
+    }
+
+    #[test]
+    fn yaw_and_pitch_move_together() {
+        let (mut turret, world) = quiet();
+
+        turret.move_left(1);
+        turret.move_up(1);
+        settle(&mut turret, &world);
+
+        let world = world.borrow();
+        assert_eq!(world.time_ms(), (YAW_PRECISION + STOP_SETTLE) as u32);
+        assert_eq!(world.pitch(), (PITCH_START - PITCH_MOVE_SPEED) as f32);
+    }
+
+    #[test]
+    fn yaw_estimate_matches_the_world() {
+        let (mut turret, world) = quiet();
+
+        turret.turn_to(Angle::new::<degree>(90.0));
+        settle(&mut turret, &world);
+        turret.turn_to(Angle::new::<degree>(-45.0));
+        settle(&mut turret, &world);
+
+        let estimate = turret.yaw_estimate().get::<degree>();
+        assert!((estimate - world.borrow().yaw()).abs() < 0.5);
+        assert!((estimate + 45.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn sentry_shoots_the_closest_object() {
+        let scene = vec![
+            Object {
+                bearing: 22.5,
+                distance: 1.0,
+            },
+            Object {
+                bearing: -45.0,
+                distance: 1.8,
+            },
+        ];
+        let (mut turret, world) = simulate(SimConfig::default(), scene);
+
+        turret.commands_mut().press(ir::HASHTAG);
+        turret.handle_command(&mut Sink);
+        settle(&mut turret, &world);
+
+        let world = world.borrow();
+        assert_eq!(world.shots().len(), 1);
+        assert!((world.shots()[0].yaw - 22.5).abs() < 2.0);
+        let target = turret.sentry().target().unwrap();
+        assert_eq!(target.distance.get::<meter>(), 1.0);
+    }
+
+    #[test]
+    fn sentry_holds_fire_when_nothing_is_close() {
+        let scene = vec![Object {
+            bearing: 0.0,
+            distance: 3.0,
+        }];
+        let (mut turret, world) = simulate(SimConfig::default(), scene);
+
+        turret.toggle_sentry();
+        settle(&mut turret, &world);
+
+        assert!(world.borrow().shots().is_empty());
+        assert_eq!(turret.sentry().profile().len(), 9);
+        assert_eq!(turret.sentry().target(), None);
+    }
+
+    #[test]
+    fn range_finder_sees_objects_in_the_beam() {
+        let scene = vec![
+            Object {
+                bearing: 10.0,
+                distance: 1.5,
+            },
+            Object {
+                bearing: 90.0,
+                distance: 0.5,
+            },
+        ];
+        let (mut turret, world) = simulate(SimConfig::default(), scene);
+
+        let range = turret.range_finder_mut().measure_distance().unwrap();
+        assert_eq!(range.get::<meter>(), 1.5);
+
+        turret.move_right(4);
+        settle(&mut turret, &world);
+        assert_eq!(
+            turret.range_finder_mut().measure_distance(),
+            Err(HcSr04Error::NoEcho)
+        );
+    }
+
+    #[test]
+    fn range_map_spots_an_intruder() {
+        let scene = vec![Object {
+            bearing: 0.0,
+            distance: 3.0,
+        }];
+        let (mut turret, world) = simulate(SimConfig::default(), scene);
+        let mut map: RangeMap<16> = RangeMap::new(Angle::new::<degree>(30.0), 20).unwrap();
+        let sweep = |turret: &mut SimTurret, map: &mut RangeMap<16>| {
+            for step in -3..=3 {
+                turret.turn_to(Angle::new::<degree>(step as f32 * 30.0));
+                settle(turret, &world);
+                turret.scan_into(map).unwrap();
+            }
+        };
+
+        sweep(&mut turret, &mut map);
+        map.capture_baseline();
+        world.borrow_mut().add_object(Object {
+            bearing: -60.0,
+            distance: 1.2,
+        });
+        sweep(&mut turret, &mut map);
+
+        let changes: Vec<_> = map.changes(Length::new::<meter>(0.1)).collect();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].bin.yaw, -2);
+        assert_eq!(changes[0].latest, Reading::Echo(Length::new::<meter>(1.2)));
+    }
+}
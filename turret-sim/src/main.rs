@@ -0,0 +1,107 @@
+//! Drive the simulated turret through a list of remote buttons and print what
+//! happened after each one.
+//!
+//! ```text
+//! turret-sim [object=BEARING:METERS]... [BUTTON]...
+//! ```
+//!
+//! Buttons are `up`, `down`, `left`, `right`, `ok`, `star`, `hash`, plus
+//! `range` to take a measurement and `wait:MS` to let time pass.
+
+use std::{convert::Infallible, process::ExitCode};
+
+use rangefinder::{hal::RangeSensor, ir};
+use turret_sim::{run_for, settle, simulate, Object, SimConfig};
+use uom::si::length::meter;
+
+/// Serial console that ends up on stdout
+struct Console;
+
+impl ufmt::uWrite for Console {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        print!("{s}");
+        Ok(())
+    }
+}
+
+enum Step {
+    Button(u8),
+    Range,
+    Wait(u32),
+}
+
+fn parse_step(arg: &str) -> Option<Step> {
+    let step = match arg {
+        "up" => Step::Button(ir::UP),
+        "down" => Step::Button(ir::DOWN),
+        "left" => Step::Button(ir::LEFT),
+        "right" => Step::Button(ir::RIGHT),
+        "ok" => Step::Button(ir::OK),
+        "star" => Step::Button(ir::STAR),
+        "hash" => Step::Button(ir::HASHTAG),
+        "range" => Step::Range,
+        _ => Step::Wait(arg.strip_prefix("wait:")?.parse().ok()?),
+    };
+    Some(step)
+}
+
+fn parse_object(arg: &str) -> Option<Object> {
+    let (bearing, distance) = arg.strip_prefix("object=")?.split_once(':')?;
+    Some(Object {
+        bearing: bearing.parse().ok()?,
+        distance: distance.parse().ok()?,
+    })
+}
+
+fn main() -> ExitCode {
+    let mut scene = Vec::new();
+    let mut steps = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if let Some(object) = parse_object(&arg) {
+            scene.push(object);
+        } else if let Some(step) = parse_step(&arg) {
+            steps.push(step);
+        } else {
+            eprintln!("Unknown argument: {arg}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let (mut turret, world) = simulate(SimConfig::default(), scene);
+
+    for step in steps {
+        match step {
+            Step::Button(cmd) => {
+                turret.commands_mut().press(cmd);
+                turret.handle_command(&mut Console);
+                settle(&mut turret, &world);
+            }
+            Step::Range => match turret.range_finder_mut().measure_distance() {
+                Ok(distance) => println!("Range: {:.2} m", distance.get::<meter>()),
+                Err(e) => println!("Range: {e:?}"),
+            },
+            Step::Wait(ms) => run_for(&mut turret, &world, ms),
+        }
+
+        let world = world.borrow();
+        println!(
+            "t={} ms yaw={:.1} pitch={:.1} darts_left={} shots={}",
+            world.time_ms(),
+            world.yaw(),
+            world.pitch(),
+            world.darts_left(),
+            world.shots().len(),
+        );
+    }
+
+    for shot in world.borrow().shots() {
+        println!(
+            "Shot at t={} ms: yaw={:.1} pitch={:.1}",
+            shot.time_ms, shot.yaw, shot.pitch
+        );
+    }
+
+    ExitCode::SUCCESS
+}